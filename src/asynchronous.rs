@@ -0,0 +1,99 @@
+//! Helpers for the `embed_async`/`rerank_async` and `embed_stream` methods, available with
+//! the `async` feature.
+//!
+//! Inference is run on the tokio blocking thread pool, one chunk of `batch_size` inputs at a
+//! time. Dropping the returned future stops any further chunk from being scheduled, while
+//! the chunk already running completes in the background and its output is discarded.
+
+use std::{
+    sync::{Arc, OnceLock},
+    thread::available_parallelism,
+};
+
+use anyhow::Result;
+use tokio::sync::{mpsc::Sender, Semaphore};
+
+/// Permits for the inference calls allowed to run at once, shared by all models.
+static INFERENCE_PERMITS: OnceLock<Arc<Semaphore>> = OnceLock::new();
+
+/// Set how many inference calls of the async methods can run at once, across all models.
+///
+/// Defaults to the number of CPUs available. This must be called before the first async
+/// method call, and returns an error otherwise.
+pub fn set_max_concurrent_inferences(limit: usize) -> Result<()> {
+    INFERENCE_PERMITS
+        .set(Arc::new(Semaphore::new(limit.max(1))))
+        .map_err(|_| {
+            anyhow::Error::msg(
+                "The maximum number of concurrent inferences can only be set before the \
+                first async call.",
+            )
+        })
+}
+
+fn inference_permits() -> Arc<Semaphore> {
+    INFERENCE_PERMITS
+        .get_or_init(|| {
+            let limit = available_parallelism().map_or(1, |threads| threads.get());
+            Arc::new(Semaphore::new(limit))
+        })
+        .clone()
+}
+
+/// Run `run` on consecutive chunks of at most `chunk_size` inputs on the blocking thread
+/// pool, passing the index of the first input of each chunk, and concatenate the outputs.
+pub(crate) async fn run_in_chunks<S, T, F>(
+    inputs: Vec<S>,
+    chunk_size: usize,
+    run: F,
+) -> Result<Vec<T>>
+where
+    S: Send + 'static,
+    T: Send + 'static,
+    F: Fn(usize, Vec<S>) -> Result<Vec<T>> + Send + Sync + 'static,
+{
+    let run = Arc::new(run);
+    let mut output = Vec::with_capacity(inputs.len());
+    let mut inputs = inputs.into_iter().peekable();
+    let mut offset = 0;
+
+    while inputs.peek().is_some() {
+        let chunk: Vec<S> = inputs.by_ref().take(chunk_size.max(1)).collect();
+        let chunk_offset = offset;
+        offset += chunk.len();
+        let run = run.clone();
+
+        // The permit is moved into the blocking task, so that it is held until the inference
+        // completes even if this future is dropped.
+        let permit = inference_permits().acquire_owned().await?;
+        output.extend(
+            tokio::task::spawn_blocking(move || {
+                let _permit = permit;
+                run(chunk_offset, chunk)
+            })
+            .await??,
+        );
+    }
+
+    Ok(output)
+}
+
+/// Send `items` through `sender` until they are exhausted or the receiver is dropped.
+///
+/// This must run on the blocking thread pool. An inference permit is held whenever the next
+/// item is requested, as this is when a new batch is run.
+pub(crate) fn forward<T>(mut items: impl Iterator<Item = T>, sender: Sender<T>) {
+    let handle = tokio::runtime::Handle::current();
+    let permits = inference_permits();
+
+    loop {
+        let item = {
+            let _permit = handle.block_on(permits.acquire());
+            items.next()
+        };
+        let Some(item) = item else { break };
+        if sender.blocking_send(item).is_err() {
+            break;
+        }
+    }
+}
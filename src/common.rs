@@ -1,12 +1,20 @@
 use anyhow::Result;
 #[cfg(feature = "hf-hub")]
 use hf_hub::api::sync::ApiRepo;
+use ndarray::Array2;
+use ort::{session::SessionInputValue, value::Value};
+use rayon::iter::{IntoParallelIterator, IntoParallelRefMutIterator, ParallelIterator};
+use std::borrow::Cow;
 use std::io::Read;
 use std::{fs::File, path::PathBuf};
-use tokenizers::{AddedToken, PaddingParams, PaddingStrategy, Tokenizer, TruncationParams};
+use tokenizers::{
+    pad_encodings, AddedToken, EncodeInput, Encoding, PaddingDirection, PaddingParams,
+    PaddingStrategy, Tokenizer, TruncationParams,
+};
 
 pub const DEFAULT_CACHE_DIR: &str = ".fastembed_cache";
 
+#[derive(Debug, Clone, PartialEq)]
 pub struct SparseEmbedding {
     pub indices: Vec<usize>,
     pub values: Vec<f32>,
@@ -15,6 +23,65 @@ pub struct SparseEmbedding {
 /// Type alias for the embedding vector
 pub type Embedding = Vec<f32>;
 
+/// Token-level embeddings of a single input, with padding removed
+#[derive(Debug, Clone, PartialEq)]
+pub struct TokenEmbeddings {
+    /// One embedding per token, as output by the model
+    pub embeddings: Vec<Embedding>,
+    /// The id of each token
+    pub token_ids: Vec<u32>,
+    /// The `(start, end)` byte offsets of each token in the input text
+    pub offsets: Vec<(usize, usize)>,
+}
+
+/// Whether a single input was truncated to the `max_length` of the model
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TruncationReport {
+    /// The number of tokens of the input before truncation, including special tokens
+    pub token_count: usize,
+    /// Whether the input exceeded `max_length` and was cut
+    pub truncated: bool,
+}
+
+/// The tokens of a single input, as they are fed to the model but without padding
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TokenizedInput {
+    /// The token ids, truncated to `max_length`
+    pub ids: Vec<u32>,
+    /// `1` for the special tokens added by the tokenizer, e.g. `[CLS]` and `[SEP]`, `0` otherwise
+    pub special_tokens_mask: Vec<u32>,
+    /// The `(start, end)` byte offsets of each token in the text it comes from, see
+    /// `sequence_ids`
+    pub offsets: Vec<(usize, usize)>,
+    /// The text each token comes from: `Some(0)` for the input, or the query of a pair,
+    /// `Some(1)` for the document of a pair, and `None` for special tokens
+    pub sequence_ids: Vec<Option<usize>>,
+    /// The number of tokens before truncation, and whether the input was truncated
+    pub truncation: TruncationReport,
+}
+
+/// How the window embeddings of a document are combined by
+/// [`TextEmbedding::embed_windowed`](crate::TextEmbedding::embed_windowed)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WindowAggregation {
+    /// Element-wise mean of the window embeddings
+    #[default]
+    Mean,
+    /// Element-wise maximum of the window embeddings
+    Max,
+    /// Keep one embedding per window
+    All,
+}
+
+/// Embeddings of a single input that was split into overlapping token windows
+#[derive(Debug, Clone, PartialEq)]
+pub struct WindowedEmbedding {
+    /// A single aggregated embedding, or one embedding per window for [`WindowAggregation::All`]
+    pub embeddings: Vec<Embedding>,
+    /// The `(start, end)` byte offsets of each window in the input text
+    pub window_offsets: Vec<(usize, usize)>,
+}
+
 /// Type alias for the error type
 pub type Error = anyhow::Error;
 
@@ -27,6 +94,23 @@ pub struct TokenizerFiles {
     pub tokenizer_config_file: Vec<u8>,
 }
 
+/// Side on which the tokenizer pads the inputs of a batch
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PaddingSide {
+    Left,
+    #[default]
+    Right,
+}
+
+impl From<PaddingSide> for PaddingDirection {
+    fn from(side: PaddingSide) -> Self {
+        match side {
+            PaddingSide::Left => PaddingDirection::Left,
+            PaddingSide::Right => PaddingDirection::Right,
+        }
+    }
+}
+
 /// The procedure for loading tokenizer files from the hugging face hub is separated
 /// from the main load_tokenizer function (which is expecting bytes, from any source).
 #[cfg(feature = "hf-hub")]
@@ -46,6 +130,18 @@ pub fn load_tokenizer_hf_hub(model_repo: ApiRepo, max_length: usize) -> Result<T
 ///
 /// Or indirectly from the try_new function via load_tokenizer_hf_hub (converting HF files to bytes)
 pub fn load_tokenizer(tokenizer_files: TokenizerFiles, max_length: usize) -> Result<Tokenizer> {
+    load_tokenizer_with_padding(tokenizer_files, max_length, None)
+}
+
+/// Same as [`load_tokenizer`], but allows overriding the padding side.
+///
+/// If `padding_side` is `None`, the `padding_side` entry of `tokenizer_config.json` is used,
+/// defaulting to right padding.
+pub fn load_tokenizer_with_padding(
+    tokenizer_files: TokenizerFiles,
+    max_length: usize,
+    padding_side: Option<PaddingSide>,
+) -> Result<Tokenizer> {
     let base_error_message =
         "Error building TokenizerFiles for UserDefinedEmbeddingModel. Could not read {} file.";
 
@@ -85,16 +181,33 @@ pub fn load_tokenizer(tokenizer_files: TokenizerFiles, max_length: usize) -> Res
         .expect("Error reading model_max_length from tokenizer_config.json")
         as f32;
     let max_length = max_length.min(model_max_length as usize);
-    let pad_id = config["pad_token_id"].as_u64().unwrap_or(0) as u32;
-    let pad_token = tokenizer_config["pad_token"]
-        .as_str()
-        .expect("Error reading pad_token from tokenizer_config.json")
+    // Decoder-based models frequently ship without a pad token, in which case the EOS token
+    // is used for padding.
+    let pad_token: String = [
+        &tokenizer_config["pad_token"],
+        &tokenizer_config["eos_token"],
+    ]
+    .into_iter()
+    .find_map(|value| value.as_str().or_else(|| value["content"].as_str()))
+    .expect("Error reading pad_token from tokenizer_config.json")
+    .into();
+    let pad_id = config["pad_token_id"]
+        .as_u64()
+        .map(|id| id as u32)
+        .or_else(|| tokenizer.token_to_id(&pad_token))
+        .unwrap_or(0);
+    let direction = padding_side
+        .unwrap_or_else(|| match tokenizer_config["padding_side"].as_str() {
+            Some("left") => PaddingSide::Left,
+            _ => PaddingSide::Right,
+        })
         .into();
 
     let mut tokenizer = tokenizer
         .with_padding(Some(PaddingParams {
             // TODO: the user should able to choose the padding strategy
             strategy: PaddingStrategy::BatchLongest,
+            direction,
             pad_token,
             pad_id,
             ..Default::default()
@@ -128,6 +241,243 @@ pub fn load_tokenizer(tokenizer_files: TokenizerFiles, max_length: usize) -> Res
     Ok(tokenizer.into())
 }
 
+/// Tokenize the inputs without truncation to report which of them exceed the `max_length`
+/// of the tokenizer.
+pub(crate) fn truncation_report<'s, E>(
+    tokenizer: &Tokenizer,
+    inputs: Vec<E>,
+) -> Result<Vec<TruncationReport>>
+where
+    E: Into<EncodeInput<'s>> + Send,
+{
+    let max_length = tokenizer
+        .get_truncation()
+        .map_or(usize::MAX, |truncation| truncation.max_length);
+
+    let mut tokenizer = tokenizer.clone();
+    tokenizer.with_padding(None);
+    tokenizer
+        .with_truncation(None)
+        .map_err(anyhow::Error::msg)?;
+
+    let encodings = tokenizer
+        .encode_batch(inputs, true)
+        .map_err(|e| anyhow::Error::msg(e.to_string()).context("Failed to encode the inputs."))?;
+
+    Ok(encodings
+        .iter()
+        .map(|encoding| TruncationReport {
+            token_count: encoding.len(),
+            truncated: encoding.len() > max_length,
+        })
+        .collect())
+}
+
+/// Tokenize the inputs as they would be fed to the model, without padding them.
+pub(crate) fn tokenize<'s, E>(tokenizer: &Tokenizer, inputs: Vec<E>) -> Result<Vec<TokenizedInput>>
+where
+    E: Into<EncodeInput<'s>> + Send + Clone,
+{
+    let reports = truncation_report(tokenizer, inputs.clone())?;
+
+    let mut tokenizer = tokenizer.clone();
+    tokenizer.with_padding(None);
+    let encodings = tokenizer
+        .encode_batch(inputs, true)
+        .map_err(|e| anyhow::Error::msg(e.to_string()).context("Failed to encode the inputs."))?;
+
+    Ok(encodings
+        .into_iter()
+        .zip(reports)
+        .map(|(encoding, truncation)| TokenizedInput {
+            ids: encoding.get_ids().to_vec(),
+            special_tokens_mask: encoding.get_special_tokens_mask().to_vec(),
+            offsets: encoding.get_offsets().to_vec(),
+            sequence_ids: encoding.get_sequence_ids(),
+            truncation,
+        })
+        .collect())
+}
+
+/// Encode all the inputs, then group them into batches with [`batch_encodings`].
+///
+/// All the inputs are tokenized before the first batch is run, so the encodings of the whole
+/// input are held in memory at once, rather than those of a single batch.
+pub(crate) fn encode_batches<'s, E>(
+    tokenizer: &Tokenizer,
+    inputs: Vec<E>,
+    batch_size: usize,
+    max_batch_tokens: Option<usize>,
+    sort_by_length: bool,
+) -> Result<(Vec<Vec<Encoding>>, Vec<usize>)>
+where
+    E: Into<EncodeInput<'s>> + Send,
+{
+    // Unlike `encode_batch`, `encode` does not pad to the longest input
+    let encodings = inputs
+        .into_par_iter()
+        .map(|input| tokenizer.encode(input, true))
+        .collect::<tokenizers::Result<Vec<_>>>()
+        .map_err(|e| anyhow::Error::msg(e.to_string()).context("Failed to encode the inputs."))?;
+
+    batch_encodings(
+        encodings,
+        tokenizer.get_padding(),
+        batch_size,
+        max_batch_tokens,
+        sort_by_length,
+    )
+}
+
+/// Group encodings into batches of at most `batch_size` encodings and, if given,
+/// `max_batch_tokens` padded tokens, each padded to its longest member.
+///
+/// If `sort_by_length` is set, the encodings are sorted by length first so that each batch
+/// holds encodings of similar length.
+///
+/// Returns the batches, along with the index in `encodings` of each encoding in batch order.
+pub(crate) fn batch_encodings(
+    encodings: Vec<Encoding>,
+    padding: Option<&PaddingParams>,
+    batch_size: usize,
+    max_batch_tokens: Option<usize>,
+    sort_by_length: bool,
+) -> Result<(Vec<Vec<Encoding>>, Vec<usize>)> {
+    let mut encodings = encodings.into_iter().enumerate().collect::<Vec<_>>();
+    if sort_by_length {
+        encodings.sort_by_key(|(_, encoding)| encoding.len());
+    }
+
+    let mut order = Vec::with_capacity(encodings.len());
+    let mut batches: Vec<(Vec<Encoding>, usize)> = Vec::new();
+    for (index, encoding) in encodings {
+        order.push(index);
+        match batches.last_mut() {
+            Some((batch, longest))
+                if batch.len() < batch_size
+                    && max_batch_tokens.map_or(true, |max_batch_tokens| {
+                        (batch.len() + 1) * encoding.len().max(*longest) <= max_batch_tokens
+                    }) =>
+            {
+                *longest = encoding.len().max(*longest);
+                batch.push(encoding);
+            }
+            _ => {
+                let length = encoding.len();
+                batches.push((vec![encoding], length));
+            }
+        }
+    }
+    let mut batches: Vec<Vec<Encoding>> = batches.into_iter().map(|(batch, _)| batch).collect();
+
+    if let Some(padding) = padding {
+        batches
+            .par_iter_mut()
+            .try_for_each(|batch| pad_encodings(batch, padding))
+            .map_err(anyhow::Error::msg)?;
+    }
+
+    Ok((batches, order))
+}
+
+/// The `input_ids`, `attention_mask` and `token_type_ids` model inputs of a batch, of shape
+/// `(batch_size, length)`.
+pub(crate) struct BatchArrays {
+    pub(crate) input_ids: Array2<i64>,
+    pub(crate) attention_mask: Array2<i64>,
+    pub(crate) token_type_ids: Array2<i64>,
+}
+
+impl BatchArrays {
+    /// The arrays of a batch of equally long encodings.
+    pub(crate) fn from_encodings(encodings: &[Encoding]) -> Result<Self> {
+        let shape = (encodings.len(), encodings.first().map_or(0, Encoding::len));
+        let array = |values: fn(&Encoding) -> &[u32]| {
+            Array2::from_shape_vec(
+                shape,
+                encodings
+                    .iter()
+                    .flat_map(|encoding| values(encoding).iter().map(|&value| value as i64))
+                    .collect(),
+            )
+        };
+
+        Ok(Self {
+            input_ids: array(Encoding::get_ids)?,
+            attention_mask: array(Encoding::get_attention_mask)?,
+            token_type_ids: array(Encoding::get_type_ids)?,
+        })
+    }
+
+    /// The session inputs of the batch, with the `token_type_ids` only if the model needs them.
+    pub(crate) fn session_inputs(
+        &self,
+        need_token_type_ids: bool,
+    ) -> Result<Vec<(Cow<'static, str>, SessionInputValue<'static>)>> {
+        let mut session_inputs = ort::inputs![
+            "input_ids" => Value::from_array(self.input_ids.view())?,
+            "attention_mask" => Value::from_array(self.attention_mask.view())?,
+        ]?;
+
+        if need_token_type_ids {
+            session_inputs.push((
+                "token_type_ids".into(),
+                Value::from_array(self.token_type_ids.view())?.into(),
+            ));
+        }
+        Ok(session_inputs)
+    }
+}
+
+/// Put outputs computed in the order returned by [`batch_encodings`] back in input order.
+pub(crate) fn restore_order<T>(values: Vec<T>, order: &[usize]) -> Vec<T> {
+    let mut values = values.into_iter().zip(order).collect::<Vec<_>>();
+    values.sort_by_key(|(_, &index)| index);
+    values.into_iter().map(|(value, _)| value).collect()
+}
+
+/// Lazily run `run` on consecutive batches of at most `batch_size` inputs, yielding each
+/// output along with the index of its input.
+///
+/// Only one batch of inputs and outputs is held at a time. Iteration stops after the first
+/// error.
+pub(crate) fn lazy_batches<S, T>(
+    inputs: impl Iterator<Item = S>,
+    batch_size: usize,
+    mut run: impl FnMut(Vec<S>) -> Result<Vec<T>>,
+) -> impl Iterator<Item = Result<(usize, T)>> {
+    let mut inputs = inputs.fuse();
+    let mut offset = 0;
+    let mut failed = false;
+
+    std::iter::from_fn(move || {
+        if failed {
+            return None;
+        }
+        let batch: Vec<S> = inputs.by_ref().take(batch_size.max(1)).collect();
+        if batch.is_empty() {
+            return None;
+        }
+
+        let start = offset;
+        offset += batch.len();
+        match run(batch) {
+            Ok(outputs) => Some(
+                outputs
+                    .into_iter()
+                    .enumerate()
+                    .map(|(index, output)| Ok((start + index, output)))
+                    .collect::<Vec<_>>(),
+            ),
+            Err(err) => {
+                failed = true;
+                Some(vec![Err(err)])
+            }
+        }
+    })
+    .flatten()
+}
+
 pub fn normalize(v: &[f32]) -> Vec<f32> {
     let norm = (v.iter().map(|val| val * val).sum::<f32>()).sqrt();
     let epsilon = 1e-12;
@@ -0,0 +1,382 @@
+//! Optional cache of computed embeddings, see [`EmbeddingCacheOptions`].
+//!
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, OnceLock,
+    },
+};
+
+use anyhow::Result;
+use sha2::{Digest, Sha256};
+use tokenizers::Encoding;
+
+use crate::{Embedding, SparseEmbedding};
+
+/// Name of the directory under the `cache_dir` that persistent caches are stored in
+const DISK_CACHE_DIR: &str = "embeddings";
+
+/// Version of the on-disk format, part of every cache key
+const CACHE_FORMAT_VERSION: u32 = 1;
+
+/// Options for caching the embeddings computed by a model
+///
+/// Embeddings are cached by the model and the options that affect its output, such as
+/// pooling, normalization and `max_length`, together with a hash of the input text. Cache
+/// hits skip tokenization and inference entirely, and only the misses are run through the
+/// model.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct EmbeddingCacheOptions {
+    /// Maximum number of embeddings kept in memory, evicting the least recently used first
+    pub capacity: usize,
+    /// Whether to also store the embeddings on disk, under `<cache_dir>/embeddings`,
+    /// so that they are kept across runs
+    pub persistent: bool,
+}
+
+impl EmbeddingCacheOptions {
+    /// Cache up to `capacity` embeddings in memory
+    pub fn in_memory(capacity: usize) -> Self {
+        Self {
+            capacity,
+            persistent: false,
+        }
+    }
+
+    /// Cache embeddings on disk, keeping up to `capacity` of them in memory as well
+    pub fn persistent(capacity: usize) -> Self {
+        Self {
+            capacity,
+            persistent: true,
+        }
+    }
+}
+
+/// Hash of the model configuration and an input text
+type CacheKey = [u8; 32];
+
+/// Hex digest of the files of a user-defined model, to identify it in a cache namespace
+pub(crate) fn files_digest(files: &[&[u8]]) -> String {
+    let mut hasher = Sha256::new();
+    for file in files {
+        hasher.update((file.len() as u64).to_le_bytes());
+        hasher.update(file);
+    }
+    hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// Values that can be stored in an [`EmbeddingCache`]
+pub(crate) trait CacheValue: Clone + Sized {
+    fn to_bytes(&self) -> Vec<u8>;
+
+    /// Returns `None` if the bytes are not a valid value, e.g. a partially written file
+    fn from_bytes(bytes: &[u8]) -> Option<Self>;
+
+    /// Number of dimensions of the value, or `None` if values of a model vary in size
+    fn dim(&self) -> Option<usize>;
+}
+
+impl CacheValue for Embedding {
+    fn to_bytes(&self) -> Vec<u8> {
+        self.iter().flat_map(|value| value.to_le_bytes()).collect()
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() % 4 != 0 {
+            return None;
+        }
+
+        Some(
+            bytes
+                .chunks_exact(4)
+                .map(|chunk| f32::from_le_bytes(chunk.try_into().unwrap()))
+                .collect(),
+        )
+    }
+
+    fn dim(&self) -> Option<usize> {
+        Some(self.len())
+    }
+}
+
+impl CacheValue for SparseEmbedding {
+    /// The number of entries, followed by the indices and the values
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(8 + self.indices.len() * 12);
+        bytes.extend((self.indices.len() as u64).to_le_bytes());
+        bytes.extend(
+            self.indices
+                .iter()
+                .flat_map(|&index| (index as u64).to_le_bytes()),
+        );
+        bytes.extend(self.values.iter().flat_map(|value| value.to_le_bytes()));
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let (len, rest) = bytes.split_first_chunk::<8>()?;
+        let len = usize::try_from(u64::from_le_bytes(*len)).ok()?;
+        if rest.len() != len.checked_mul(12)? {
+            return None;
+        }
+
+        let (indices, values) = rest.split_at(len * 8);
+        Some(SparseEmbedding {
+            indices: indices
+                .chunks_exact(8)
+                .map(|chunk| u64::from_le_bytes(chunk.try_into().unwrap()) as usize)
+                .collect(),
+            values: values
+                .chunks_exact(4)
+                .map(|chunk| f32::from_le_bytes(chunk.try_into().unwrap()))
+                .collect(),
+        })
+    }
+
+    fn dim(&self) -> Option<usize> {
+        None
+    }
+}
+
+/// In-memory store evicting the least recently used entry once over capacity
+struct Lru<V> {
+    capacity: usize,
+    entries: HashMap<CacheKey, (V, u64)>,
+    /// Keys by the tick of their last use
+    recency: BTreeMap<u64, CacheKey>,
+    tick: u64,
+}
+
+impl<V: Clone> Lru<V> {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            tick: 0,
+        }
+    }
+
+    fn get(&mut self, key: &CacheKey) -> Option<V> {
+        let (value, last_used) = self.entries.get_mut(key)?;
+        self.recency.remove(last_used);
+        self.tick += 1;
+        *last_used = self.tick;
+        self.recency.insert(self.tick, *key);
+        Some(value.clone())
+    }
+
+    fn insert(&mut self, key: CacheKey, value: V) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        self.tick += 1;
+        if let Some((_, last_used)) = self.entries.insert(key, (value, self.tick)) {
+            self.recency.remove(&last_used);
+        }
+        self.recency.insert(self.tick, key);
+
+        while self.entries.len() > self.capacity {
+            let Some((_, oldest)) = self.recency.pop_first() else {
+                break;
+            };
+            self.entries.remove(&oldest);
+        }
+    }
+}
+
+/// Cache of the embeddings computed by a single model configuration
+pub(crate) struct EmbeddingCache<V> {
+    /// Identifies the model configuration, hashed into every key
+    namespace: String,
+    memory: Mutex<Lru<V>>,
+    dir: Option<PathBuf>,
+    /// Number of dimensions of the values, values read from disk with another one are misses
+    dim: OnceLock<usize>,
+}
+
+impl<V: CacheValue> EmbeddingCache<V> {
+    /// Create a cache for the model configuration identified by `namespace`
+    ///
+    /// The namespace must include everything that affects the output of the model, besides
+    /// the input text. If `dim` is not known in advance, it is taken from the first computed
+    /// value.
+    pub(crate) fn new(
+        options: &EmbeddingCacheOptions,
+        namespace: String,
+        cache_dir: &Path,
+        dim: Option<usize>,
+    ) -> Result<Self> {
+        let dir = if options.persistent {
+            let dir = cache_dir.join(DISK_CACHE_DIR);
+            fs::create_dir_all(&dir)?;
+            Some(dir)
+        } else {
+            None
+        };
+
+        Ok(Self {
+            namespace,
+            memory: Mutex::new(Lru::new(options.capacity)),
+            dir,
+            dim: dim.map(OnceLock::from).unwrap_or_default(),
+        })
+    }
+
+    fn key(&self, input: &[u8]) -> CacheKey {
+        let mut hasher = Sha256::new();
+        hasher.update(CACHE_FORMAT_VERSION.to_le_bytes());
+        hasher.update((self.namespace.len() as u64).to_le_bytes());
+        hasher.update(self.namespace.as_bytes());
+        hasher.update(input);
+        hasher.finalize().into()
+    }
+
+    /// Path of the file storing the value of `key`, sharded by the first byte of the key
+    fn path(dir: &Path, key: &CacheKey) -> PathBuf {
+        let hex = key
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect::<String>();
+        dir.join(&hex[..2]).join(hex)
+    }
+
+    fn get(&self, key: &CacheKey) -> Option<V> {
+        if let Some(value) = self.memory.lock().unwrap().get(key) {
+            return Some(value);
+        }
+
+        // Unreadable or corrupt files are treated as misses and overwritten
+        let dir = self.dir.as_ref()?;
+        let value = V::from_bytes(&fs::read(Self::path(dir, key)).ok()?)?;
+        if let (Some(dim), Some(&expected)) = (value.dim(), self.dim.get()) {
+            if dim != expected {
+                return None;
+            }
+        }
+        self.memory.lock().unwrap().insert(*key, value.clone());
+        Some(value)
+    }
+
+    /// Store a value in memory and, if persistent, on disk
+    ///
+    /// Writing to disk is best-effort: the value is already computed, so a failed write only
+    /// leaves it cached in memory rather than failing the embedding.
+    fn insert(&self, key: CacheKey, value: V) {
+        if let Some(dim) = value.dim() {
+            let _ = self.dim.set(dim);
+        }
+        if let Some(dir) = &self.dir {
+            let _ = Self::write(dir, &key, &value);
+        }
+
+        self.memory.lock().unwrap().insert(key, value);
+    }
+
+    fn write(dir: &Path, key: &CacheKey, value: &V) -> Result<()> {
+        // Write to a unique temporary file first, so that concurrent readers never see a
+        // partially written value
+        static TMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+        let path = Self::path(dir, key);
+        let tmp = path.with_extension(format!(
+            "{}.{}.tmp",
+            std::process::id(),
+            TMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::create_dir_all(path.parent().unwrap())?;
+        let written = fs::write(&tmp, value.to_bytes()).and_then(|()| fs::rename(&tmp, &path));
+        if written.is_err() {
+            let _ = fs::remove_file(&tmp);
+        }
+        Ok(written?)
+    }
+
+    /// Look up the embeddings of `texts`, computing the missing ones with `compute`
+    ///
+    /// `compute` is called at most once, with the distinct texts that are not cached, and must
+    /// return one value per text in the same order.
+    pub(crate) fn get_or_compute<S: AsRef<str>>(
+        &self,
+        texts: &[S],
+        compute: impl FnOnce(Vec<&str>) -> Result<Vec<V>>,
+    ) -> Result<Vec<V>> {
+        let keys = texts
+            .iter()
+            .map(|text| self.key(text.as_ref().as_bytes()))
+            .collect();
+        self.get_or_compute_keyed(keys, texts, |misses| {
+            compute(misses.into_iter().map(AsRef::as_ref).collect())
+        })
+    }
+
+    /// Same as [`EmbeddingCache::get_or_compute`], for the windows of longer texts, keyed by
+    /// their token ids
+    pub(crate) fn get_or_compute_windows(
+        &self,
+        windows: &[Encoding],
+        compute: impl FnOnce(Vec<&Encoding>) -> Result<Vec<V>>,
+    ) -> Result<Vec<V>> {
+        // Texts are valid UTF-8, which never contains 0xff, so windows never share a key with
+        // a text
+        let keys = windows
+            .iter()
+            .map(|window| {
+                let mut input = vec![0xff];
+                input.extend(window.get_ids().iter().flat_map(|id| id.to_le_bytes()));
+                self.key(&input)
+            })
+            .collect();
+        self.get_or_compute_keyed(keys, windows, compute)
+    }
+
+    fn get_or_compute_keyed<'i, T>(
+        &self,
+        keys: Vec<CacheKey>,
+        inputs: &'i [T],
+        compute: impl FnOnce(Vec<&'i T>) -> Result<Vec<V>>,
+    ) -> Result<Vec<V>> {
+        let mut values = keys.iter().map(|key| self.get(key)).collect::<Vec<_>>();
+
+        // Distinct missing inputs, by the position of their first occurrence
+        let mut misses = Vec::new();
+        let mut seen = HashMap::new();
+        for (i, key) in keys.iter().enumerate() {
+            if values[i].is_none() && !seen.contains_key(key) {
+                seen.insert(*key, misses.len());
+                misses.push(i);
+            }
+        }
+
+        if !misses.is_empty() {
+            let computed = compute(misses.iter().map(|&i| &inputs[i]).collect())?;
+            if computed.len() != misses.len() {
+                return Err(anyhow::Error::msg(format!(
+                    "Expected {} embeddings to cache, got {}.",
+                    misses.len(),
+                    computed.len()
+                )));
+            }
+
+            for (&i, value) in misses.iter().zip(&computed) {
+                self.insert(keys[i], value.clone());
+            }
+            for (key, value) in keys.iter().zip(&mut values) {
+                if value.is_none() {
+                    *value = Some(computed[seen[key]].clone());
+                }
+            }
+        }
+
+        Ok(values.into_iter().map(Option::unwrap).collect())
+    }
+}
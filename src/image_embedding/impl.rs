@@ -5,17 +5,22 @@ use hf_hub::{
 };
 use image::DynamicImage;
 use ndarray::{Array3, ArrayView3};
-use ort::{
-    session::{builder::GraphOptimizationLevel, Session},
-    value::Value,
-};
-#[cfg(feature = "hf-hub")]
+use ort::value::Value;
 use std::path::PathBuf;
-use std::{io::Cursor, path::Path, thread::available_parallelism};
-
+#[cfg(feature = "async")]
+use std::sync::Arc;
+use std::{io::Cursor, path::Path};
+#[cfg(feature = "async")]
+use tokio::sync::mpsc::{channel, Receiver};
+
+#[cfg(feature = "async")]
+use crate::asynchronous::{forward, run_in_chunks};
 use crate::{
-    common::normalize, models::image_embedding::models_list, Embedding, ImageEmbeddingModel,
-    ModelInfo,
+    common::{lazy_batches, normalize},
+    models::image_embedding::models_list,
+    progress::{BatchTracker, EmbedControl},
+    session_pool::SessionPool,
+    Embedding, ImageEmbeddingModel, ModelInfo,
 };
 use anyhow::anyhow;
 #[cfg(feature = "hf-hub")]
@@ -33,9 +38,8 @@ use rayon::prelude::*;
 impl ImageEmbedding {
     /// Try to generate a new ImageEmbedding Instance
     ///
-    /// Uses the highest level of Graph optimization
-    ///
-    /// Uses the total number of CPUs available as the number of intra-threads
+    /// Uses the highest level of Graph optimization and the total number of CPUs available as
+    /// the number of intra-threads, unless configured otherwise with `session_options`
     #[cfg(feature = "hf-hub")]
     pub fn try_new(options: ImageInitOptions) -> anyhow::Result<Self> {
         let ImageInitOptions {
@@ -43,9 +47,12 @@ impl ImageEmbedding {
             execution_providers,
             cache_dir,
             show_download_progress,
+            normalize,
+            session_pool_size,
+            session_options,
         } = options;
 
-        let threads = available_parallelism()?.get();
+        let threads = session_options.threads()?;
 
         let model_repo = ImageEmbedding::retrieve_model(
             model_name.clone(),
@@ -63,13 +70,16 @@ impl ImageEmbedding {
             .get(&model_file_name)
             .context(format!("Failed to retrieve {}", model_file_name))?;
 
-        let session = Session::builder()?
-            .with_execution_providers(execution_providers)?
-            .with_optimization_level(GraphOptimizationLevel::Level3)?
-            .with_intra_threads(threads)?
-            .commit_from_file(model_file_reference)?;
+        let sessions = SessionPool::new(session_pool_size, threads, |intra_threads| {
+            session_options.commit_from_file(
+                &execution_providers,
+                intra_threads,
+                &model_file_reference,
+                &cache_dir,
+            )
+        })?;
 
-        Ok(Self::new(preprocessor, session))
+        Ok(Self::new(preprocessor, sessions, normalize))
     }
 
     /// Create a ImageEmbedding instance from model files provided by the user.
@@ -81,26 +91,37 @@ impl ImageEmbedding {
     ) -> anyhow::Result<Self> {
         let ImageInitOptionsUserDefined {
             execution_providers,
+            normalize,
+            session_pool_size,
+            session_options,
         } = options;
 
-        let threads = available_parallelism()?.get();
+        let threads = session_options.threads()?;
 
         let preprocessor = Compose::from_bytes(model.preprocessor_file)?;
 
-        let session = Session::builder()?
-            .with_execution_providers(execution_providers)?
-            .with_optimization_level(GraphOptimizationLevel::Level3)?
-            .with_intra_threads(threads)?
-            .commit_from_memory(&model.onnx_file)?;
+        let sessions = SessionPool::new(session_pool_size, threads, |intra_threads| {
+            Ok(session_options
+                .builder(&execution_providers, intra_threads)?
+                .commit_from_memory(&model.onnx_file)?)
+        })?;
+
+        Ok(Self::new(preprocessor, sessions, normalize))
+    }
 
-        Ok(Self::new(preprocessor, session))
+    /// Stop profiling the inference of the model, enabled with
+    /// [`SessionOptions::with_profiling`](crate::SessionOptions::with_profiling), and return
+    /// the paths of the profile files, one per session
+    pub fn end_profiling(&self) -> anyhow::Result<Vec<PathBuf>> {
+        self.sessions.end_profiling()
     }
 
     /// Private method to return an instance
-    fn new(preprocessor: Compose, session: Session) -> Self {
+    fn new(preprocessor: Compose, sessions: SessionPool, normalize: bool) -> Self {
         Self {
             preprocessor,
-            session,
+            sessions,
+            normalize,
         }
     }
 
@@ -165,30 +186,110 @@ impl ImageEmbedding {
         Ok(output)
     }
 
+    /// Method to lazily generate image embeddings for an iterator of image paths.
+    ///
+    /// The images are pulled from the iterator one batch at a time, and the embeddings are
+    /// yielded along with the index of their image, so that arbitrarily large collections can
+    /// be embedded with bounded memory. Iteration stops after the first error.
+    pub fn embed_iter<'a, S, I>(
+        &'a self,
+        images: I,
+        batch_size: Option<usize>,
+    ) -> impl Iterator<Item = anyhow::Result<(usize, Embedding)>> + 'a
+    where
+        I: IntoIterator<Item = S>,
+        I::IntoIter: 'a,
+        S: AsRef<Path> + Send + Sync + 'a,
+    {
+        let chunk_size = batch_size.unwrap_or(DEFAULT_BATCH_SIZE);
+
+        lazy_batches(images.into_iter(), chunk_size, move |batch| {
+            self.embed(batch, batch_size)
+        })
+    }
+
+    /// Streaming version of [`ImageEmbedding::embed_iter`], available with the `async` feature.
+    ///
+    /// The embeddings are computed on the blocking thread pool and sent through the returned
+    /// channel, which holds at most one batch of them. Dropping the receiver stops any further
+    /// batch from being run. This must be called from within a tokio runtime.
+    #[cfg(feature = "async")]
+    pub fn embed_stream<S, I>(
+        self: Arc<Self>,
+        images: I,
+        batch_size: Option<usize>,
+    ) -> Receiver<anyhow::Result<(usize, Embedding)>>
+    where
+        I: IntoIterator<Item = S>,
+        I::IntoIter: Send + 'static,
+        S: AsRef<Path> + Send + Sync + 'static,
+    {
+        let (sender, receiver) = channel(batch_size.unwrap_or(DEFAULT_BATCH_SIZE));
+
+        let images = images.into_iter();
+        tokio::task::spawn_blocking(move || forward(self.embed_iter(images, batch_size), sender));
+
+        receiver
+    }
+
+    /// Async version of [`ImageEmbedding::embed`], available with the `async` feature.
+    ///
+    /// The images are processed on the blocking thread pool, `batch_size` at a time, so that
+    /// the async executor is not blocked. Dropping the future stops any further batch from
+    /// being scheduled. See [`set_max_concurrent_inferences`](crate::set_max_concurrent_inferences)
+    /// to limit how many inference calls run at once.
+    #[cfg(feature = "async")]
+    pub async fn embed_async<S: AsRef<Path> + Send + Sync + 'static>(
+        self: Arc<Self>,
+        images: Vec<S>,
+        batch_size: Option<usize>,
+    ) -> anyhow::Result<Vec<Embedding>> {
+        let chunk_size = batch_size.unwrap_or(DEFAULT_BATCH_SIZE);
+
+        run_in_chunks(images, chunk_size, move |_, chunk| {
+            self.embed(chunk, batch_size)
+        })
+        .await
+    }
+
     /// Method to generate image embeddings for a Vec of image path
     // Generic type to accept String, &str, OsString, &OsStr
     pub fn embed<S: AsRef<Path> + Send + Sync>(
         &self,
         images: Vec<S>,
         batch_size: Option<usize>,
+    ) -> anyhow::Result<Vec<Embedding>> {
+        self.embed_with_control(images, batch_size, &EmbedControl::default())
+    }
+
+    /// Same as [`ImageEmbedding::embed`], but reports the progress after each batch and stops
+    /// before the next batch once cancelled, returning a [`Cancelled`](crate::Cancelled) error.
+    pub fn embed_with_control<S: AsRef<Path> + Send + Sync>(
+        &self,
+        images: Vec<S>,
+        batch_size: Option<usize>,
+        control: &EmbedControl,
     ) -> anyhow::Result<Vec<Embedding>> {
         // Determine the batch size, default if not specified
         let batch_size = batch_size.unwrap_or(DEFAULT_BATCH_SIZE);
+        let tracker = BatchTracker::new(control, images.len().div_ceil(batch_size), images.len());
 
         let output = images
             .par_chunks(batch_size)
             .map(|batch| {
-                // Encode the texts in the batch
-                let inputs = batch
-                    .iter()
-                    .map(|img| {
-                        image::ImageReader::open(img)?
-                            .decode()
-                            .map_err(|err| anyhow!("image decode: {}", err))
-                    })
-                    .collect::<Result<_, _>>()?;
-
-                self.embed_images(inputs)
+                tracker.run(batch.len(), || {
+                    // Encode the texts in the batch
+                    let inputs = batch
+                        .iter()
+                        .map(|img| {
+                            image::ImageReader::open(img)?
+                                .decode()
+                                .map_err(|err| anyhow!("image decode: {}", err))
+                        })
+                        .collect::<Result<_, _>>()?;
+
+                    self.embed_images(inputs)
+                })
             })
             .collect::<anyhow::Result<Vec<_>>>()?
             .into_iter()
@@ -215,12 +316,12 @@ impl ImageEmbedding {
         let inputs_view: Vec<ArrayView3<f32>> = inputs.iter().map(|img| img.view()).collect();
         let pixel_values_array = ndarray::stack(ndarray::Axis(0), &inputs_view)?;
 
-        let input_name = self.session.inputs[0].name.clone();
+        let input_name = self.sessions.session().inputs[0].name.clone();
         let session_inputs = ort::inputs![
             input_name => Value::from_array(pixel_values_array)?,
         ]?;
 
-        let outputs = self.session.run(session_inputs)?;
+        let outputs = self.sessions.run(|session| session.run(session_inputs))?;
 
         // Try to get the only output key
         // If multiple, then default to few known keys `image_embeds` and `last_hidden_state`
@@ -249,7 +350,7 @@ impl ImageEmbedding {
                     .map(|batch_idx| {
                         let cls_embedding =
                             output_data.slice(ndarray::s![batch_idx, 0, ..]).to_vec();
-                        normalize(&cls_embedding)
+                        self.post_process(cls_embedding)
                     })
                     .collect()
             }
@@ -258,7 +359,7 @@ impl ImageEmbedding {
                 output_data
                     .rows()
                     .into_iter()
-                    .map(|row| normalize(row.as_slice().unwrap()))
+                    .map(|row| self.post_process(row.to_vec()))
                     .collect()
             }
             _ => return Err(anyhow!("Unexpected output tensor shape: {:?}", shape)),
@@ -266,4 +367,13 @@ impl ImageEmbedding {
 
         Ok(embeddings)
     }
+
+    /// Normalize the embedding, unless disabled in the init options
+    fn post_process(&self, embedding: Embedding) -> Embedding {
+        if self.normalize {
+            normalize(&embedding)
+        } else {
+            embedding
+        }
+    }
 }
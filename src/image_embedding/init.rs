@@ -1,8 +1,11 @@
 use std::path::{Path, PathBuf};
 
-use ort::{execution_providers::ExecutionProviderDispatch, session::Session};
+use ort::execution_providers::ExecutionProviderDispatch;
 
-use crate::{ImageEmbeddingModel, DEFAULT_CACHE_DIR};
+use crate::{
+    session_options::SessionOptions, session_pool::SessionPool, ImageEmbeddingModel,
+    DEFAULT_CACHE_DIR,
+};
 
 use super::{utils::Compose, DEFAULT_EMBEDDING_MODEL};
 
@@ -14,6 +17,9 @@ pub struct ImageInitOptions {
     pub execution_providers: Vec<ExecutionProviderDispatch>,
     pub cache_dir: PathBuf,
     pub show_download_progress: bool,
+    pub normalize: bool,
+    pub session_pool_size: usize,
+    pub session_options: SessionOptions,
 }
 
 impl ImageInitOptions {
@@ -41,6 +47,28 @@ impl ImageInitOptions {
         self.show_download_progress = show_download_progress;
         self
     }
+
+    /// Set whether to L2 normalize the embeddings, defaults to `true`
+    pub fn with_normalize(mut self, normalize: bool) -> Self {
+        self.normalize = normalize;
+        self
+    }
+
+    /// Create the given number of ONNX sessions, each with an equal share of the threads,
+    /// and run each batch on whichever is free. Defaults to `1`
+    ///
+    /// This increases the throughput when several threads share the model, at the cost of
+    /// loading the model once per session.
+    pub fn with_session_pool_size(mut self, session_pool_size: usize) -> Self {
+        self.session_pool_size = session_pool_size;
+        self
+    }
+
+    /// Set the configuration of the ONNX Runtime sessions, such as the number of threads
+    pub fn with_session_options(mut self, session_options: SessionOptions) -> Self {
+        self.session_options = session_options;
+        self
+    }
 }
 
 impl Default for ImageInitOptions {
@@ -50,6 +78,9 @@ impl Default for ImageInitOptions {
             execution_providers: Default::default(),
             cache_dir: Path::new(DEFAULT_CACHE_DIR).to_path_buf(),
             show_download_progress: true,
+            normalize: true,
+            session_pool_size: 1,
+            session_options: Default::default(),
         }
     }
 }
@@ -57,10 +88,13 @@ impl Default for ImageInitOptions {
 /// Options for initializing UserDefinedImageEmbeddingModel
 ///
 /// Model files are held by the UserDefinedImageEmbeddingModel struct
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone)]
 #[non_exhaustive]
 pub struct ImageInitOptionsUserDefined {
     pub execution_providers: Vec<ExecutionProviderDispatch>,
+    pub normalize: bool,
+    pub session_pool_size: usize,
+    pub session_options: SessionOptions,
 }
 
 impl ImageInitOptionsUserDefined {
@@ -75,6 +109,39 @@ impl ImageInitOptionsUserDefined {
         self.execution_providers = execution_providers;
         self
     }
+
+    /// Set whether to L2 normalize the embeddings, defaults to `true`
+    pub fn with_normalize(mut self, normalize: bool) -> Self {
+        self.normalize = normalize;
+        self
+    }
+
+    /// Create the given number of ONNX sessions, each with an equal share of the threads,
+    /// and run each batch on whichever is free. Defaults to `1`
+    ///
+    /// This increases the throughput when several threads share the model, at the cost of
+    /// loading the model once per session.
+    pub fn with_session_pool_size(mut self, session_pool_size: usize) -> Self {
+        self.session_pool_size = session_pool_size;
+        self
+    }
+
+    /// Set the configuration of the ONNX Runtime sessions, such as the number of threads
+    pub fn with_session_options(mut self, session_options: SessionOptions) -> Self {
+        self.session_options = session_options;
+        self
+    }
+}
+
+impl Default for ImageInitOptionsUserDefined {
+    fn default() -> Self {
+        Self {
+            execution_providers: Default::default(),
+            normalize: true,
+            session_pool_size: 1,
+            session_options: Default::default(),
+        }
+    }
 }
 
 /// Convert ImageInitOptions to ImageInitOptionsUserDefined
@@ -84,6 +151,9 @@ impl From<ImageInitOptions> for ImageInitOptionsUserDefined {
     fn from(options: ImageInitOptions) -> Self {
         ImageInitOptionsUserDefined {
             execution_providers: options.execution_providers,
+            normalize: options.normalize,
+            session_pool_size: options.session_pool_size,
+            session_options: options.session_options,
         }
     }
 }
@@ -110,5 +180,6 @@ impl UserDefinedImageEmbeddingModel {
 /// Rust representation of the ImageEmbedding model
 pub struct ImageEmbedding {
     pub(crate) preprocessor: Compose,
-    pub(crate) session: Session,
+    pub(crate) sessions: SessionPool,
+    pub(crate) normalize: bool,
 }
@@ -0,0 +1,103 @@
+use serde_json::Value;
+
+/// JSON object stored with each vector of the index, to filter the results on
+pub type Payload = serde_json::Map<String, Value>;
+
+/// Condition on the payload of the vectors returned by a search
+///
+/// Numbers compare equal regardless of their representation, e.g. `1` and `1.0`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Filter {
+    /// The value at the key equals the given value
+    Equals(String, Value),
+    /// The value at the key equals one of the given values
+    AnyOf(String, Vec<Value>),
+    /// The value at the key is a number within the inclusive bounds
+    Range {
+        key: String,
+        min: Option<f64>,
+        max: Option<f64>,
+    },
+    /// The key is present, with any value
+    Exists(String),
+    And(Vec<Filter>),
+    Or(Vec<Filter>),
+    Not(Box<Filter>),
+}
+
+impl Filter {
+    pub fn equals(key: impl Into<String>, value: impl Into<Value>) -> Self {
+        Filter::Equals(key.into(), value.into())
+    }
+
+    pub fn any_of<V: Into<Value>>(
+        key: impl Into<String>,
+        values: impl IntoIterator<Item = V>,
+    ) -> Self {
+        Filter::AnyOf(key.into(), values.into_iter().map(Into::into).collect())
+    }
+
+    pub fn range(key: impl Into<String>, min: Option<f64>, max: Option<f64>) -> Self {
+        Filter::Range {
+            key: key.into(),
+            min,
+            max,
+        }
+    }
+
+    pub fn exists(key: impl Into<String>) -> Self {
+        Filter::Exists(key.into())
+    }
+
+    /// Both this condition and `other`
+    pub fn and(self, other: Filter) -> Self {
+        match self {
+            Filter::And(mut filters) => {
+                filters.push(other);
+                Filter::And(filters)
+            }
+            filter => Filter::And(vec![filter, other]),
+        }
+    }
+
+    /// Either this condition or `other`
+    pub fn or(self, other: Filter) -> Self {
+        match self {
+            Filter::Or(mut filters) => {
+                filters.push(other);
+                Filter::Or(filters)
+            }
+            filter => Filter::Or(vec![filter, other]),
+        }
+    }
+
+    /// The opposite of this condition
+    pub fn negate(self) -> Self {
+        Filter::Not(Box::new(self))
+    }
+
+    pub fn matches(&self, payload: &Payload) -> bool {
+        match self {
+            Filter::Equals(key, value) => payload.get(key).is_some_and(|v| values_eq(v, value)),
+            Filter::AnyOf(key, values) => payload
+                .get(key)
+                .is_some_and(|v| values.iter().any(|value| values_eq(v, value))),
+            Filter::Range { key, min, max } => {
+                payload.get(key).and_then(Value::as_f64).is_some_and(|v| {
+                    min.map_or(true, |min| v >= min) && max.map_or(true, |max| v <= max)
+                })
+            }
+            Filter::Exists(key) => payload.contains_key(key),
+            Filter::And(filters) => filters.iter().all(|filter| filter.matches(payload)),
+            Filter::Or(filters) => filters.iter().any(|filter| filter.matches(payload)),
+            Filter::Not(filter) => !filter.matches(payload),
+        }
+    }
+}
+
+fn values_eq(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::Number(a), Value::Number(b)) => a.as_f64() == b.as_f64(),
+        _ => a == b,
+    }
+}
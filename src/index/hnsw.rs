@@ -0,0 +1,232 @@
+//! Hierarchical navigable small world graph, see <https://arxiv.org/abs/1603.09320>.
+//!
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::{BinaryHeap, HashSet},
+};
+
+use super::HnswOptions;
+
+/// A slot of the index and its distance to the target, ordered by distance
+#[derive(Debug, Clone, Copy)]
+struct Near(f32, u32);
+
+impl PartialEq for Near {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Near {}
+
+impl PartialOrd for Near {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Near {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0).then(self.1.cmp(&other.1))
+    }
+}
+
+/// Graph over the slots of an index, where lower distances are closer
+///
+/// The distances are given by the index, so that the graph only stores the links.
+#[derive(Debug, Clone)]
+pub(crate) struct Hnsw {
+    pub(crate) options: HnswOptions,
+    /// Neighbours of each slot, on each layer the slot belongs to
+    pub(crate) links: Vec<Vec<Vec<u32>>>,
+    pub(crate) entry_point: Option<u32>,
+    /// State of the random layer assignment
+    pub(crate) rng: u64,
+}
+
+impl Hnsw {
+    pub(crate) fn new(options: HnswOptions) -> Self {
+        Self {
+            options,
+            links: Vec::new(),
+            entry_point: None,
+            rng: options.seed,
+        }
+    }
+
+    /// Random top layer of a new slot, exponentially less likely to be high
+    fn random_level(&mut self) -> usize {
+        // SplitMix64
+        self.rng = self.rng.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.rng;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+
+        // Uniform in (0, 1]
+        let uniform = ((z >> 11) + 1) as f64 / (1u64 << 53) as f64;
+        let level_mult = 1.0 / (self.options.m.max(2) as f64).ln();
+        (-uniform.ln() * level_mult) as usize
+    }
+
+    fn max_links(&self, layer: usize) -> usize {
+        if layer == 0 {
+            self.options.m * 2
+        } else {
+            self.options.m
+        }
+    }
+
+    /// Add the next slot of the index, given the distance between two slots
+    pub(crate) fn insert(&mut self, distance: impl Fn(u32, u32) -> f32) {
+        let slot = self.links.len() as u32;
+        let level = self.random_level();
+        self.links.push(vec![Vec::new(); level + 1]);
+
+        let Some(entry_point) = self.entry_point else {
+            self.entry_point = Some(slot);
+            return;
+        };
+        let top = self.links[entry_point as usize].len() - 1;
+        let to_slot = |other: u32| distance(slot, other);
+
+        let mut entries = vec![Near(to_slot(entry_point), entry_point)];
+        for layer in (level + 1..=top).rev() {
+            entries = self.search_layer(&to_slot, entries, 1, layer, &|_| true);
+        }
+
+        for layer in (0..=level.min(top)).rev() {
+            let candidates = self.search_layer(
+                &to_slot,
+                entries,
+                self.options.ef_construction,
+                layer,
+                &|_| true,
+            );
+            let neighbours = self.select_neighbours(&candidates, self.options.m, &distance);
+
+            for &neighbour in &neighbours {
+                let links = &self.links[neighbour as usize][layer];
+                if links.len() < self.max_links(layer) {
+                    self.links[neighbour as usize][layer].push(slot);
+                    continue;
+                }
+
+                // Keep the best of the current links and the new slot
+                let mut candidates: Vec<Near> = links
+                    .iter()
+                    .chain([&slot])
+                    .map(|&link| Near(distance(neighbour, link), link))
+                    .collect();
+                candidates.sort_unstable();
+                self.links[neighbour as usize][layer] =
+                    self.select_neighbours(&candidates, self.max_links(layer), &distance);
+            }
+
+            self.links[slot as usize][layer] = neighbours;
+            entries = candidates;
+        }
+
+        if level > top {
+            self.entry_point = Some(slot);
+        }
+    }
+
+    /// Up to `m` of the candidates sorted by distance, preferring the ones closer to the target
+    /// than to the already selected ones, which keeps the graph navigable across clusters
+    fn select_neighbours(
+        &self,
+        candidates: &[Near],
+        m: usize,
+        distance: &impl Fn(u32, u32) -> f32,
+    ) -> Vec<u32> {
+        let mut selected: Vec<u32> = Vec::with_capacity(m);
+        let mut pruned = Vec::new();
+        for &Near(dist, candidate) in candidates {
+            if selected.len() == m {
+                break;
+            }
+            if selected
+                .iter()
+                .all(|&other| distance(candidate, other) > dist)
+            {
+                selected.push(candidate);
+            } else {
+                pruned.push(candidate);
+            }
+        }
+
+        // Fill the remaining links with the closest pruned candidates
+        let missing = m - selected.len();
+        selected.extend(pruned.into_iter().take(missing));
+        selected
+    }
+
+    /// The `ef` slots closest to the target that are accepted, sorted by distance, searching
+    /// the layer from the entries
+    ///
+    /// Slots that are not accepted are still traversed, so that they do not disconnect the
+    /// graph.
+    fn search_layer(
+        &self,
+        distance: &impl Fn(u32) -> f32,
+        entries: Vec<Near>,
+        ef: usize,
+        layer: usize,
+        accept: &impl Fn(u32) -> bool,
+    ) -> Vec<Near> {
+        let mut visited: HashSet<u32> = entries.iter().map(|near| near.1).collect();
+        let mut candidates: BinaryHeap<Reverse<Near>> =
+            entries.iter().copied().map(Reverse).collect();
+        let mut results: BinaryHeap<Near> =
+            entries.into_iter().filter(|near| accept(near.1)).collect();
+        while results.len() > ef {
+            results.pop();
+        }
+
+        while let Some(Reverse(candidate)) = candidates.pop() {
+            if results.len() >= ef && results.peek().is_some_and(|worst| candidate.0 > worst.0) {
+                break;
+            }
+
+            for &neighbour in &self.links[candidate.1 as usize][layer] {
+                if !visited.insert(neighbour) {
+                    continue;
+                }
+                let near = Near(distance(neighbour), neighbour);
+                if results.len() < ef || results.peek().is_some_and(|worst| near.0 < worst.0) {
+                    candidates.push(Reverse(near));
+                    if accept(neighbour) {
+                        results.push(near);
+                        if results.len() > ef {
+                            results.pop();
+                        }
+                    }
+                }
+            }
+        }
+
+        results.into_sorted_vec()
+    }
+
+    /// The `ef` accepted slots closest to the target, as `(distance, slot)` sorted by distance
+    pub(crate) fn search(
+        &self,
+        distance: impl Fn(u32) -> f32,
+        ef: usize,
+        accept: impl Fn(u32) -> bool,
+    ) -> Vec<(f32, u32)> {
+        let Some(entry_point) = self.entry_point else {
+            return Vec::new();
+        };
+
+        let mut entries = vec![Near(distance(entry_point), entry_point)];
+        for layer in (1..self.links[entry_point as usize].len()).rev() {
+            entries = self.search_layer(&distance, entries, 1, layer, &|_| true);
+        }
+        self.search_layer(&distance, entries, ef, 0, &accept)
+            .into_iter()
+            .map(|Near(dist, slot)| (dist, slot))
+            .collect()
+    }
+}
@@ -0,0 +1,490 @@
+use std::{
+    cmp::Ordering,
+    collections::HashMap,
+    fs,
+    path::Path,
+    sync::atomic::{self, AtomicU64},
+};
+
+use anyhow::Result;
+use rayon::prelude::*;
+
+use crate::{
+    similarity::{self, Metric},
+    Embedding,
+};
+
+use super::{
+    file::{Reader, Writer},
+    hnsw::Hnsw,
+    Filter, HnswOptions, IndexOptions, Payload, SearchHit, VectorIndex, Vectors,
+};
+
+const FILE_MAGIC: &[u8; 8] = b"FEINDEX\0";
+const FILE_VERSION: u32 = 1;
+
+impl Vectors {
+    fn len(&self) -> usize {
+        self.norms.len()
+    }
+
+    fn row(&self, slot: usize) -> &[f32] {
+        &self.data[slot * self.dim..(slot + 1) * self.dim]
+    }
+
+    fn push(&mut self, vector: &[f32]) {
+        self.data.extend_from_slice(vector);
+        self.norms.push(similarity::norm(vector));
+    }
+
+    /// Remove the vector in `slot`, moving the last one in its place
+    fn swap_remove(&mut self, slot: usize) {
+        let last = self.len() - 1;
+        if slot != last {
+            let (head, tail) = self.data.split_at_mut(last * self.dim);
+            head[slot * self.dim..(slot + 1) * self.dim].copy_from_slice(tail);
+        }
+        self.data.truncate(last * self.dim);
+        self.norms.swap_remove(slot);
+    }
+
+    /// Distance of `query` to the vector in `slot`, lower being closer
+    fn distance(&self, query: &[f32], query_norm: f32, slot: usize) -> f32 {
+        let vector = self.row(slot);
+        match self.metric {
+            Metric::Cosine => {
+                -similarity::cosine_with_norms(query, query_norm, vector, self.norms[slot])
+            }
+            Metric::Dot => -similarity::dot_unchecked(query, vector),
+            Metric::L2 => similarity::l2_squared_unchecked(query, vector),
+        }
+    }
+
+    fn distance_between(&self, a: u32, b: u32) -> f32 {
+        self.distance(self.row(a as usize), self.norms[a as usize], b as usize)
+    }
+
+    /// Score of the metric at the given distance
+    fn score(&self, distance: f32) -> f32 {
+        match self.metric {
+            Metric::L2 => distance.sqrt(),
+            Metric::Cosine | Metric::Dot => -distance,
+        }
+    }
+}
+
+fn by_distance(a: &(f32, usize), b: &(f32, usize)) -> Ordering {
+    a.0.total_cmp(&b.0).then(a.1.cmp(&b.1))
+}
+
+fn check_hnsw_options(options: &HnswOptions) -> Result<()> {
+    if options.m < 2 {
+        return Err(anyhow::Error::msg(
+            "The HNSW graph needs at least 2 neighbours per vector.",
+        ));
+    }
+    // Without candidates, new vectors would not be linked to the graph
+    if options.ef_construction == 0 {
+        return Err(anyhow::Error::msg(
+            "The HNSW graph needs at least 1 candidate when inserting vectors.",
+        ));
+    }
+    Ok(())
+}
+
+impl VectorIndex {
+    pub fn new(options: IndexOptions) -> Result<Self> {
+        let IndexOptions {
+            model,
+            dim,
+            metric,
+            hnsw,
+        } = options;
+
+        if dim == 0 {
+            return Err(anyhow::Error::msg(
+                "The dimension of the index must be at least 1.",
+            ));
+        }
+        if let Some(hnsw) = &hnsw {
+            check_hnsw_options(hnsw)?;
+        }
+
+        Ok(Self {
+            model,
+            vectors: Vectors {
+                dim,
+                metric,
+                ..Default::default()
+            },
+            ids: Vec::new(),
+            payloads: Vec::new(),
+            deleted: Vec::new(),
+            slots: HashMap::new(),
+            hnsw: hnsw.map(Hnsw::new),
+        })
+    }
+
+    /// Name of the model producing the embeddings of the index
+    pub fn model(&self) -> &str {
+        &self.model
+    }
+
+    pub fn dim(&self) -> usize {
+        self.vectors.dim
+    }
+
+    pub fn metric(&self) -> Metric {
+        self.vectors.metric
+    }
+
+    /// Number of vectors in the index
+    pub fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.slots.is_empty()
+    }
+
+    pub fn contains(&self, id: u64) -> bool {
+        self.slots.contains_key(&id)
+    }
+
+    /// The vector and payload with the given id
+    pub fn get(&self, id: u64) -> Option<(&[f32], &Payload)> {
+        let &slot = self.slots.get(&id)?;
+        Some((self.vectors.row(slot), &self.payloads[slot]))
+    }
+
+    fn check_dim(&self, embedding: &[f32]) -> Result<()> {
+        if embedding.len() != self.dim() {
+            return Err(anyhow::Error::msg(format!(
+                "The index holds embeddings of dimension {} from {}, got an embedding of dimension {}.",
+                self.dim(),
+                self.model,
+                embedding.len()
+            )));
+        }
+        Ok(())
+    }
+
+    /// Add an embedding with the given id and payload, replacing any vector with the same id
+    pub fn insert(&mut self, id: u64, embedding: &[f32], payload: Payload) -> Result<()> {
+        self.check_dim(embedding)?;
+        self.delete(id);
+        self.push(id, embedding, payload);
+        Ok(())
+    }
+
+    /// Add an embedding of the right dimension, whose id is not in the index
+    fn push(&mut self, id: u64, embedding: &[f32], payload: Payload) {
+        let slot = self.ids.len();
+        self.vectors.push(embedding);
+        self.ids.push(id);
+        self.payloads.push(payload);
+        self.deleted.push(false);
+        self.slots.insert(id, slot);
+
+        if let Some(hnsw) = &mut self.hnsw {
+            let vectors = &self.vectors;
+            hnsw.insert(|a, b| vectors.distance_between(a, b));
+        }
+    }
+
+    /// Remove the vector with the given id, returning whether it was present
+    ///
+    /// Deleted vectors stay in the HNSW graph to keep it connected, and are skipped by searches.
+    /// They still take memory and space in the saved file until [`VectorIndex::compact`].
+    pub fn delete(&mut self, id: u64) -> bool {
+        let Some(slot) = self.slots.remove(&id) else {
+            return false;
+        };
+
+        if self.hnsw.is_some() {
+            self.deleted[slot] = true;
+            self.payloads[slot] = Payload::new();
+        } else {
+            self.vectors.swap_remove(slot);
+            self.ids.swap_remove(slot);
+            self.payloads.swap_remove(slot);
+            self.deleted.swap_remove(slot);
+            if let Some(&moved) = self.ids.get(slot) {
+                self.slots.insert(moved, slot);
+            }
+        }
+        true
+    }
+
+    /// Rebuild the HNSW graph without the vectors deleted or replaced since it was built
+    ///
+    /// An HNSW index that is updated often keeps growing until compacted, while flat indexes
+    /// free deleted vectors right away.
+    pub fn compact(&mut self) {
+        let Some(hnsw) = &self.hnsw else {
+            return;
+        };
+        if !self.deleted.contains(&true) {
+            return;
+        }
+
+        let mut compacted = Self {
+            model: self.model.clone(),
+            vectors: Vectors {
+                dim: self.vectors.dim,
+                metric: self.vectors.metric,
+                ..Default::default()
+            },
+            ids: Vec::new(),
+            payloads: Vec::new(),
+            deleted: Vec::new(),
+            slots: HashMap::new(),
+            hnsw: Some(Hnsw::new(hnsw.options)),
+        };
+        for slot in 0..self.ids.len() {
+            if !self.deleted[slot] {
+                compacted.push(
+                    self.ids[slot],
+                    self.vectors.row(slot),
+                    std::mem::take(&mut self.payloads[slot]),
+                );
+            }
+        }
+        *self = compacted;
+    }
+
+    /// The `k` vectors closest to the query whose payload matches the filter, closest first
+    pub fn search(
+        &self,
+        query: &[f32],
+        k: usize,
+        filter: Option<&Filter>,
+    ) -> Result<Vec<SearchHit>> {
+        self.check_dim(query)?;
+        Ok(self.search_checked(query, k, filter))
+    }
+
+    /// The `k` vectors closest to each query whose payload matches the filter, closest first
+    pub fn search_batch(
+        &self,
+        queries: &[Embedding],
+        k: usize,
+        filter: Option<&Filter>,
+    ) -> Result<Vec<Vec<SearchHit>>> {
+        for query in queries {
+            self.check_dim(query)?;
+        }
+        Ok(queries
+            .par_iter()
+            .map(|query| self.search_checked(query, k, filter))
+            .collect())
+    }
+
+    fn search_checked(&self, query: &[f32], k: usize, filter: Option<&Filter>) -> Vec<SearchHit> {
+        if k == 0 {
+            return Vec::new();
+        }
+
+        let query_norm = similarity::norm(query);
+        let accept = |slot: usize| {
+            !self.deleted[slot]
+                && filter.map_or(true, |filter| filter.matches(&self.payloads[slot]))
+        };
+
+        let mut nearest: Vec<(f32, usize)> = match &self.hnsw {
+            Some(hnsw) => hnsw
+                .search(
+                    |slot| self.vectors.distance(query, query_norm, slot as usize),
+                    hnsw.options.ef_search.max(k),
+                    |slot| accept(slot as usize),
+                )
+                .into_iter()
+                .map(|(distance, slot)| (distance, slot as usize))
+                .collect(),
+            None => {
+                let mut nearest: Vec<(f32, usize)> = (0..self.ids.len())
+                    .into_par_iter()
+                    .filter(|&slot| accept(slot))
+                    .map(|slot| (self.vectors.distance(query, query_norm, slot), slot))
+                    .collect();
+                if k < nearest.len() {
+                    nearest.select_nth_unstable_by(k - 1, by_distance);
+                    nearest.truncate(k);
+                }
+                nearest.sort_unstable_by(by_distance);
+                nearest
+            }
+        };
+        nearest.truncate(k);
+
+        nearest
+            .into_iter()
+            .map(|(distance, slot)| SearchHit {
+                id: self.ids[slot],
+                score: self.vectors.score(distance),
+            })
+            .collect()
+    }
+
+    /// Write the index to a file, replacing it atomically
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let mut file = Writer::default();
+        file.bytes.extend_from_slice(FILE_MAGIC);
+        file.u32(FILE_VERSION);
+        file.bytes(self.model.as_bytes());
+        file.usize(self.dim());
+        file.u8(match self.metric() {
+            Metric::Cosine => 0,
+            Metric::Dot => 1,
+            Metric::L2 => 2,
+        });
+
+        file.usize(self.ids.len());
+        for slot in 0..self.ids.len() {
+            file.u64(self.ids[slot]);
+            file.u8(self.deleted[slot] as u8);
+            file.f32s(self.vectors.row(slot));
+            file.bytes(&serde_json::to_vec(&self.payloads[slot])?);
+        }
+
+        match &self.hnsw {
+            None => file.u8(0),
+            Some(hnsw) => {
+                file.u8(1);
+                file.usize(hnsw.options.m);
+                file.usize(hnsw.options.ef_construction);
+                file.usize(hnsw.options.ef_search);
+                file.u64(hnsw.options.seed);
+                file.u64(hnsw.rng);
+                file.u64(hnsw.entry_point.map_or(u64::MAX, u64::from));
+                for layers in &hnsw.links {
+                    file.usize(layers.len());
+                    for links in layers {
+                        file.u32s(links);
+                    }
+                }
+            }
+        }
+
+        // Write to a unique temporary file first, so that the index file is never partially
+        // written, even when saved concurrently
+        static TMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+        let path = path.as_ref();
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(format!(
+            ".{}.{}.tmp",
+            std::process::id(),
+            TMP_COUNTER.fetch_add(1, atomic::Ordering::Relaxed)
+        ));
+        let written = fs::write(&tmp_path, file.bytes).and_then(|()| fs::rename(&tmp_path, path));
+        if written.is_err() {
+            let _ = fs::remove_file(&tmp_path);
+        }
+        Ok(written?)
+    }
+
+    /// Read an index written by [`VectorIndex::save`], refusing indexes of another model than
+    /// `model`
+    pub fn load(path: impl AsRef<Path>, model: &str) -> Result<Self> {
+        let bytes = fs::read(path)?;
+        let mut file = Reader { bytes: &bytes };
+
+        if file.bytes.get(..FILE_MAGIC.len()) != Some(FILE_MAGIC) {
+            return Err(anyhow::Error::msg("The file is not an index file."));
+        }
+        file.bytes = &file.bytes[FILE_MAGIC.len()..];
+        let version = file.u32()?;
+        if version != FILE_VERSION {
+            return Err(anyhow::Error::msg(format!(
+                "Unsupported index file version {version}."
+            )));
+        }
+
+        let stored_model = String::from_utf8(file.bytes()?.to_vec())?;
+        if stored_model != model {
+            return Err(anyhow::Error::msg(format!(
+                "The index holds embeddings of {stored_model}, not {model}."
+            )));
+        }
+        let dim = file.usize()?;
+        let metric = match file.u8()? {
+            0 => Metric::Cosine,
+            1 => Metric::Dot,
+            2 => Metric::L2,
+            code => {
+                return Err(anyhow::Error::msg(format!(
+                    "Unknown metric {code} in the index file."
+                )))
+            }
+        };
+
+        let mut index = Self::new(IndexOptions::new(stored_model, dim).with_metric(metric))?;
+        let len = file.usize()?;
+        for slot in 0..len {
+            let id = file.u64()?;
+            let deleted = file.u8()? != 0;
+            index.vectors.push(&file.f32s(dim)?);
+            index.ids.push(id);
+            index.payloads.push(serde_json::from_slice(file.bytes()?)?);
+            index.deleted.push(deleted);
+            if !deleted && index.slots.insert(id, slot).is_some() {
+                return Err(anyhow::Error::msg(format!(
+                    "Duplicate id {id} in the index file."
+                )));
+            }
+        }
+
+        if file.u8()? == 1 {
+            let options = HnswOptions::new()
+                .with_m(file.usize()?)
+                .with_ef_construction(file.usize()?)
+                .with_ef_search(file.usize()?)
+                .with_seed(file.u64()?);
+            check_hnsw_options(&options)?;
+            let mut hnsw = Hnsw::new(options);
+            hnsw.rng = file.u64()?;
+            let entry_point = file.u64()?;
+            hnsw.entry_point = (entry_point != u64::MAX).then_some(entry_point as u32);
+
+            for _ in 0..len {
+                let layers = file.usize()?;
+                hnsw.links.push(
+                    (0..layers)
+                        .map(|_| file.u32s())
+                        .collect::<Result<Vec<_>>>()?,
+                );
+            }
+
+            // Slots without layers, or links to missing slots or layers would make searches panic
+            let valid = hnsw
+                .entry_point
+                .map_or(true, |entry| (entry as usize) < len)
+                && hnsw.links.iter().all(|layers| !layers.is_empty())
+                && hnsw.links.iter().all(|layers| {
+                    layers.iter().enumerate().all(|(layer, links)| {
+                        links.iter().all(|&link| {
+                            hnsw.links
+                                .get(link as usize)
+                                .is_some_and(|other| other.len() > layer)
+                        })
+                    })
+                });
+            if !valid || len > 0 && hnsw.entry_point.is_none() {
+                return Err(anyhow::Error::msg(
+                    "The HNSW graph of the index file is invalid.",
+                ));
+            }
+            index.hnsw = Some(hnsw);
+        } else if index.deleted.iter().any(|&deleted| deleted) {
+            return Err(anyhow::Error::msg(
+                "The flat index file contains deleted vectors.",
+            ));
+        }
+
+        if !file.is_empty() {
+            return Err(anyhow::Error::msg("The index file has trailing bytes."));
+        }
+        Ok(index)
+    }
+}
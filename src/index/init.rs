@@ -0,0 +1,144 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+
+use crate::{similarity::Metric, EmbeddingModel, TextEmbedding};
+
+use super::{
+    hnsw::Hnsw, Payload, DEFAULT_HNSW_EF_CONSTRUCTION, DEFAULT_HNSW_EF_SEARCH, DEFAULT_HNSW_M,
+};
+
+/// Options for creating a VectorIndex
+///
+/// The index refuses embeddings whose dimension differs from `dim`, and index files saved for
+/// another `model`. Embeddings of another model with the same dimension cannot be told apart,
+/// so only insert and search embeddings of `model`.
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub struct IndexOptions {
+    /// Name of the model producing the embeddings, recorded in the index files
+    pub model: String,
+    /// Dimension of the embeddings, vectors of other dimensions are refused
+    pub dim: usize,
+    pub metric: Metric,
+    /// Build an HNSW graph with these options, instead of a flat index
+    pub hnsw: Option<HnswOptions>,
+}
+
+impl IndexOptions {
+    /// Flat index of cosine similarities of `dim` dimensional embeddings of `model`
+    pub fn new(model: impl Into<String>, dim: usize) -> Self {
+        Self {
+            model: model.into(),
+            dim,
+            metric: Metric::Cosine,
+            hnsw: None,
+        }
+    }
+
+    /// Flat index of cosine similarities of the embeddings of a supported text model
+    ///
+    /// Use [`IndexOptions::new`] instead for embeddings truncated to fewer dimensions.
+    pub fn for_model(model: &EmbeddingModel) -> Result<Self> {
+        let info = TextEmbedding::get_model_info(model)?;
+        Ok(Self::new(info.model_code.clone(), info.dim))
+    }
+
+    pub fn with_metric(mut self, metric: Metric) -> Self {
+        self.metric = metric;
+        self
+    }
+
+    pub fn with_hnsw(mut self, hnsw: HnswOptions) -> Self {
+        self.hnsw = Some(hnsw);
+        self
+    }
+}
+
+/// Parameters of the HNSW graph, trading memory and build time for recall
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct HnswOptions {
+    /// Number of neighbours of each vector, twice as many on the bottom layer
+    pub m: usize,
+    /// Number of candidates considered when inserting a vector
+    pub ef_construction: usize,
+    /// Number of candidates considered when searching, at least the number of results
+    pub ef_search: usize,
+    /// Seed of the random layer assignment, for reproducible graphs
+    pub seed: u64,
+}
+
+impl HnswOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_m(mut self, m: usize) -> Self {
+        self.m = m;
+        self
+    }
+
+    pub fn with_ef_construction(mut self, ef_construction: usize) -> Self {
+        self.ef_construction = ef_construction;
+        self
+    }
+
+    pub fn with_ef_search(mut self, ef_search: usize) -> Self {
+        self.ef_search = ef_search;
+        self
+    }
+
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+}
+
+impl Default for HnswOptions {
+    fn default() -> Self {
+        Self {
+            m: DEFAULT_HNSW_M,
+            ef_construction: DEFAULT_HNSW_EF_CONSTRUCTION,
+            ef_search: DEFAULT_HNSW_EF_SEARCH,
+            seed: 0,
+        }
+    }
+}
+
+/// A vector of the index and its score against the query
+///
+/// The score is a similarity for [`Metric::Cosine`] and [`Metric::Dot`], and a distance for
+/// [`Metric::L2`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SearchHit {
+    pub id: u64,
+    pub score: f32,
+}
+
+/// Vectors of an index, stored contiguously
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Vectors {
+    pub(crate) dim: usize,
+    pub(crate) metric: Metric,
+    pub(crate) data: Vec<f32>,
+    /// Norm of each vector, for the cosine similarity
+    pub(crate) norms: Vec<f32>,
+}
+
+/// In-memory index of the embeddings of a single model, searchable by similarity
+///
+/// Each vector has a unique `u64` id and a JSON [`Payload`] that searches can filter on.
+#[derive(Debug, Clone)]
+pub struct VectorIndex {
+    pub(crate) model: String,
+    pub(crate) vectors: Vectors,
+    /// Id of the vector in each slot
+    pub(crate) ids: Vec<u64>,
+    pub(crate) payloads: Vec<Payload>,
+    /// Deleted slots, which stay in the HNSW graph to keep it connected
+    pub(crate) deleted: Vec<bool>,
+    /// Slot of each live id
+    pub(crate) slots: HashMap<u64, usize>,
+    pub(crate) hnsw: Option<Hnsw>,
+}
@@ -0,0 +1,27 @@
+//! In-memory vector index over the embeddings of a model, containing the main struct
+//! [VectorIndex] and its options.
+//!
+//! The index is either flat, comparing the query with every vector, which is exact and fast
+//! enough for small corpora, or an HNSW graph, which is approximate but scales to larger ones.
+
+// Constants.
+const DEFAULT_HNSW_M: usize = 16;
+const DEFAULT_HNSW_EF_CONSTRUCTION: usize = 200;
+const DEFAULT_HNSW_EF_SEARCH: usize = 64;
+
+// Payload filters.
+mod filter;
+pub use filter::*;
+
+// Initialization options.
+mod init;
+pub use init::*;
+
+// The HNSW graph.
+mod hnsw;
+
+// Reading and writing index files.
+mod file;
+
+// The implementation of the index.
+mod r#impl;
@@ -0,0 +1,342 @@
+#[cfg(feature = "hf-hub")]
+use crate::common::load_tokenizer_hf_hub;
+use crate::{
+    common::{load_tokenizer, normalize, BatchArrays},
+    models::late_interaction::{models_list, LateInteractionModel},
+    session_pool::SessionPool,
+    Embedding, ModelInfo,
+};
+#[cfg(feature = "hf-hub")]
+use anyhow::Context;
+use anyhow::Result;
+#[cfg(feature = "hf-hub")]
+use hf_hub::{
+    api::sync::{ApiBuilder, ApiRepo},
+    Cache,
+};
+use ndarray::{Array, Axis, Ix3};
+use rayon::{iter::ParallelIterator, slice::ParallelSlice};
+use std::path::PathBuf;
+use tokenizers::{Tokenizer, TruncationParams};
+
+#[cfg(feature = "hf-hub")]
+use super::LateInteractionInitOptions;
+use super::{
+    LateInteractionInitOptionsUserDefined, LateInteractionTextEmbedding,
+    UserDefinedLateInteractionModel, DEFAULT_BATCH_SIZE, DOCUMENT_MARKER_TOKEN_ID, MASK_TOKEN,
+    MIN_QUERY_LENGTH, PUNCTUATION, QUERY_MARKER_TOKEN_ID,
+};
+
+impl LateInteractionTextEmbedding {
+    /// Try to generate a new LateInteractionTextEmbedding Instance
+    ///
+    /// Uses the highest level of Graph optimization and the total number of CPUs available as
+    /// the number of intra-threads, unless configured otherwise with `session_options`
+    #[cfg(feature = "hf-hub")]
+    pub fn try_new(options: LateInteractionInitOptions) -> Result<Self> {
+        let LateInteractionInitOptions {
+            model_name,
+            execution_providers,
+            max_length,
+            cache_dir,
+            show_download_progress,
+            session_pool_size,
+            session_options,
+        } = options;
+
+        let threads = session_options.threads()?;
+
+        let model_repo = LateInteractionTextEmbedding::retrieve_model(
+            model_name.clone(),
+            cache_dir.clone(),
+            show_download_progress,
+        )?;
+
+        let model_file_name = LateInteractionTextEmbedding::get_model_info(&model_name)?.model_file;
+        let model_file_reference = model_repo
+            .get(&model_file_name)
+            .context(format!("Failed to retrieve {}", model_file_name))?;
+
+        let sessions = SessionPool::new(session_pool_size, threads, |intra_threads| {
+            session_options.commit_from_file(
+                &execution_providers,
+                intra_threads,
+                &model_file_reference,
+                &cache_dir,
+            )
+        })?;
+
+        let tokenizer = load_tokenizer_hf_hub(model_repo, max_length)?;
+        Self::new(tokenizer, sessions)
+    }
+
+    /// Create a LateInteractionTextEmbedding instance from model files provided by the user.
+    ///
+    /// This can be used for 'bring your own' late interaction models
+    pub fn try_new_from_user_defined(
+        model: UserDefinedLateInteractionModel,
+        options: LateInteractionInitOptionsUserDefined,
+    ) -> Result<Self> {
+        let LateInteractionInitOptionsUserDefined {
+            execution_providers,
+            max_length,
+            session_pool_size,
+            session_options,
+        } = options;
+
+        let threads = session_options.threads()?;
+
+        let sessions = SessionPool::new(session_pool_size, threads, |intra_threads| {
+            Ok(session_options
+                .builder(&execution_providers, intra_threads)?
+                .commit_from_memory(&model.onnx_file)?)
+        })?;
+
+        let tokenizer = load_tokenizer(model.tokenizer_files, max_length)?;
+        Self::new(tokenizer, sessions)
+    }
+
+    /// Stop profiling the inference of the model, enabled with
+    /// [`SessionOptions::with_profiling`](crate::SessionOptions::with_profiling), and return
+    /// the paths of the profile files, one per session
+    pub fn end_profiling(&self) -> Result<Vec<PathBuf>> {
+        self.sessions.end_profiling()
+    }
+
+    /// Private method to return an instance
+    fn new(mut tokenizer: Tokenizer, sessions: SessionPool) -> Result<Self> {
+        // Leave room for the marker token inserted after `[CLS]`
+        if let Some(truncation) = tokenizer.get_truncation().cloned() {
+            tokenizer
+                .with_truncation(Some(TruncationParams {
+                    max_length: truncation.max_length.saturating_sub(1),
+                    ..truncation
+                }))
+                .map_err(anyhow::Error::msg)?;
+        }
+
+        let need_token_type_ids = sessions
+            .session()
+            .inputs
+            .iter()
+            .any(|input| input.name == "token_type_ids");
+        let mask_token_id = tokenizer.token_to_id(MASK_TOKEN).ok_or_else(|| {
+            anyhow::Error::msg(format!(
+                "The tokenizer has no {MASK_TOKEN} token, which is required for query augmentation."
+            ))
+        })?;
+        let skip_list = PUNCTUATION
+            .chars()
+            .filter_map(|c| tokenizer.token_to_id(&c.to_string()))
+            .collect();
+
+        Ok(Self {
+            tokenizer,
+            sessions,
+            need_token_type_ids,
+            mask_token_id,
+            skip_list,
+        })
+    }
+
+    /// Return the LateInteractionTextEmbedding model's directory from cache or remote retrieval
+    #[cfg(feature = "hf-hub")]
+    fn retrieve_model(
+        model: LateInteractionModel,
+        cache_dir: PathBuf,
+        show_download_progress: bool,
+    ) -> Result<ApiRepo> {
+        let cache = Cache::new(cache_dir);
+        let api = ApiBuilder::from_cache(cache)
+            .with_progress(show_download_progress)
+            .build()?;
+
+        let repo = api.model(model.to_string());
+        Ok(repo)
+    }
+
+    /// Retrieve a list of supported models
+    pub fn list_supported_models() -> Vec<ModelInfo<LateInteractionModel>> {
+        models_list()
+    }
+
+    /// Get ModelInfo from LateInteractionModel
+    pub fn get_model_info(model: &LateInteractionModel) -> Result<ModelInfo<LateInteractionModel>> {
+        LateInteractionTextEmbedding::list_supported_models()
+            .into_iter()
+            .find(|m| &m.model == model)
+            .ok_or_else(|| {
+                anyhow::Error::msg(format!(
+                    "Model {model:?} not found. Please check if the model is supported \
+                    by the current version."
+                ))
+            })
+    }
+
+    /// Method to generate multi-vector embeddings for a Vec of documents
+    ///
+    /// Each document is embedded into one normalized vector per token, with padding and
+    /// punctuation tokens removed.
+    // Generic type to accept String, &str, OsString, &OsStr
+    pub fn embed<S: AsRef<str> + Send + Sync>(
+        &self,
+        documents: Vec<S>,
+        batch_size: Option<usize>,
+    ) -> Result<Vec<Vec<Embedding>>> {
+        self.embed_with_marker(documents, batch_size, true)
+    }
+
+    /// Method to generate multi-vector embeddings for a Vec of queries
+    ///
+    /// Queries shorter than the minimum query length are padded with `[MASK]` tokens,
+    /// whose embeddings are kept as part of the query (query augmentation).
+    // Generic type to accept String, &str, OsString, &OsStr
+    pub fn query_embed<S: AsRef<str> + Send + Sync>(
+        &self,
+        queries: Vec<S>,
+        batch_size: Option<usize>,
+    ) -> Result<Vec<Vec<Embedding>>> {
+        self.embed_with_marker(queries, batch_size, false)
+    }
+
+    /// Compute the MaxSim late interaction score between a query and a document.
+    ///
+    /// This is the sum, over all query token embeddings, of the highest dot product with
+    /// any of the document token embeddings.
+    pub fn max_sim(query: &[Embedding], document: &[Embedding]) -> f32 {
+        query
+            .iter()
+            .map(|q| {
+                document
+                    .iter()
+                    .map(|d| q.iter().zip(d).map(|(a, b)| a * b).sum::<f32>())
+                    .fold(f32::NEG_INFINITY, f32::max)
+            })
+            .filter(|score| score.is_finite())
+            .sum()
+    }
+
+    fn embed_with_marker<S: AsRef<str> + Send + Sync>(
+        &self,
+        texts: Vec<S>,
+        batch_size: Option<usize>,
+        is_doc: bool,
+    ) -> Result<Vec<Vec<Embedding>>> {
+        // Determine the batch size, default if not specified
+        let batch_size = batch_size.unwrap_or(DEFAULT_BATCH_SIZE);
+
+        let (marker_token_id, pad_token_id) = if is_doc {
+            let pad_id = self.tokenizer.get_padding().map_or(0, |p| p.pad_id);
+            (DOCUMENT_MARKER_TOKEN_ID, pad_id)
+        } else {
+            (QUERY_MARKER_TOKEN_ID, self.mask_token_id)
+        };
+
+        let output = texts
+            .par_chunks(batch_size)
+            .map(|batch| {
+                // Encode the texts in the batch
+                let inputs = batch.iter().map(|text| text.as_ref()).collect();
+                let encodings = self.tokenizer.encode_batch(inputs, true).map_err(|e| {
+                    anyhow::Error::msg(e.to_string()).context("Failed to encode the batch.")
+                })?;
+                if encodings.iter().any(|encoding| encoding.is_empty()) {
+                    return Err(anyhow::Error::msg(
+                        "The tokenizer returned an empty encoding, without a [CLS] token to insert the marker after.",
+                    ));
+                }
+
+                // The marker token is inserted after `[CLS]`; queries are also augmented
+                // with `[MASK]` tokens up to the minimum query length.
+                let encoding_length = if is_doc {
+                    encodings[0].len()
+                } else {
+                    encodings[0].len().max(MIN_QUERY_LENGTH)
+                } + 1;
+                let batch_size = batch.len();
+
+                let max_size = encoding_length * batch_size;
+
+                // Preallocate arrays with the maximum size
+                let mut ids_array = Vec::with_capacity(max_size);
+                let mut mask_array = Vec::with_capacity(max_size);
+                let mut lengths = Vec::with_capacity(batch_size);
+
+                encodings.iter().for_each(|encoding| {
+                    let ids = encoding.get_ids();
+                    let mask = encoding.get_attention_mask();
+                    let length = mask.iter().filter(|&&m| m != 0).count();
+
+                    ids_array.extend([ids[0] as i64, marker_token_id as i64]);
+                    mask_array.extend([mask[0] as i64, 1]);
+                    ids.iter().zip(mask).skip(1).for_each(|(&id, &m)| {
+                        ids_array.push(if m != 0 { id } else { pad_token_id } as i64);
+                        mask_array.push(m as i64);
+                    });
+                    let padding = encoding_length - ids.len() - 1;
+                    ids_array.extend(std::iter::repeat(pad_token_id as i64).take(padding));
+                    mask_array.extend(std::iter::repeat(0).take(padding));
+
+                    lengths.push(
+                        if is_doc {
+                            length
+                        } else {
+                            length.max(MIN_QUERY_LENGTH)
+                        } + 1,
+                    );
+                });
+
+                let arrays = BatchArrays {
+                    input_ids: Array::from_shape_vec((batch_size, encoding_length), ids_array)?,
+                    attention_mask: Array::from_shape_vec(
+                        (batch_size, encoding_length),
+                        mask_array,
+                    )?,
+                    token_type_ids: Array::zeros((batch_size, encoding_length)),
+                };
+                let session_inputs = arrays.session_inputs(self.need_token_type_ids)?;
+                let outputs = self.sessions.run(|session| session.run(session_inputs))?;
+
+                // Try to get the only output key
+                // If multiple, then default to `last_hidden_state`
+                let last_hidden_state_key = match outputs.len() {
+                    1 => outputs.keys().next().unwrap(),
+                    _ => "last_hidden_state",
+                };
+
+                let output_data = outputs[last_hidden_state_key].try_extract_tensor::<f32>()?;
+                let shape = output_data.shape().to_vec();
+                let output_data = output_data.into_dimensionality::<Ix3>().map_err(|_| {
+                    anyhow::Error::msg(format!(
+                        "Invalid output shape: {shape:?}. Expected 3D tensor."
+                    ))
+                })?;
+
+                let embeddings = output_data
+                    .axis_iter(Axis(0))
+                    .zip(arrays.input_ids.rows())
+                    .zip(arrays.attention_mask.rows())
+                    .zip(lengths)
+                    .map(|(((tokens, ids), mask), length)| {
+                        tokens
+                            .axis_iter(Axis(0))
+                            .zip(ids.iter().zip(mask))
+                            .take(length)
+                            .filter(|(_, (&id, &m))| {
+                                !is_doc || (m != 0 && !self.skip_list.contains(&(id as u32)))
+                            })
+                            .map(|(token, _)| normalize(&token.to_vec()))
+                            .collect::<Vec<Embedding>>()
+                    })
+                    .collect::<Vec<_>>();
+
+                Ok(embeddings)
+            })
+            .collect::<Result<Vec<_>>>()?
+            .into_iter()
+            .flatten()
+            .collect();
+
+        Ok(output)
+    }
+}
@@ -0,0 +1,188 @@
+use std::path::{Path, PathBuf};
+
+use ort::execution_providers::ExecutionProviderDispatch;
+use tokenizers::Tokenizer;
+
+use crate::{
+    models::late_interaction::LateInteractionModel, session_options::SessionOptions,
+    session_pool::SessionPool, TokenizerFiles, DEFAULT_CACHE_DIR,
+};
+
+use super::{DEFAULT_EMBEDDING_MODEL, DEFAULT_MAX_LENGTH};
+
+/// Options for initializing the LateInteractionTextEmbedding model
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct LateInteractionInitOptions {
+    pub model_name: LateInteractionModel,
+    pub execution_providers: Vec<ExecutionProviderDispatch>,
+    pub max_length: usize,
+    pub cache_dir: PathBuf,
+    pub show_download_progress: bool,
+    pub session_pool_size: usize,
+    pub session_options: SessionOptions,
+}
+
+impl LateInteractionInitOptions {
+    pub fn new(model_name: LateInteractionModel) -> Self {
+        Self {
+            model_name,
+            ..Default::default()
+        }
+    }
+
+    pub fn with_max_length(mut self, max_length: usize) -> Self {
+        self.max_length = max_length;
+        self
+    }
+
+    pub fn with_cache_dir(mut self, cache_dir: PathBuf) -> Self {
+        self.cache_dir = cache_dir;
+        self
+    }
+
+    pub fn with_execution_providers(
+        mut self,
+        execution_providers: Vec<ExecutionProviderDispatch>,
+    ) -> Self {
+        self.execution_providers = execution_providers;
+        self
+    }
+
+    pub fn with_show_download_progress(mut self, show_download_progress: bool) -> Self {
+        self.show_download_progress = show_download_progress;
+        self
+    }
+
+    /// Create the given number of ONNX sessions, each with an equal share of the threads,
+    /// and run each batch on whichever is free. Defaults to `1`
+    ///
+    /// This increases the throughput when several threads share the model, at the cost of
+    /// loading the model once per session.
+    pub fn with_session_pool_size(mut self, session_pool_size: usize) -> Self {
+        self.session_pool_size = session_pool_size;
+        self
+    }
+
+    /// Set the configuration of the ONNX Runtime sessions, such as the number of threads
+    pub fn with_session_options(mut self, session_options: SessionOptions) -> Self {
+        self.session_options = session_options;
+        self
+    }
+}
+
+impl Default for LateInteractionInitOptions {
+    fn default() -> Self {
+        Self {
+            model_name: DEFAULT_EMBEDDING_MODEL,
+            execution_providers: Default::default(),
+            max_length: DEFAULT_MAX_LENGTH,
+            cache_dir: Path::new(DEFAULT_CACHE_DIR).to_path_buf(),
+            show_download_progress: true,
+            session_pool_size: 1,
+            session_options: Default::default(),
+        }
+    }
+}
+
+/// Options for initializing UserDefinedLateInteractionModel
+///
+/// Model files are held by the UserDefinedLateInteractionModel struct
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct LateInteractionInitOptionsUserDefined {
+    pub execution_providers: Vec<ExecutionProviderDispatch>,
+    pub max_length: usize,
+    pub session_pool_size: usize,
+    pub session_options: SessionOptions,
+}
+
+impl LateInteractionInitOptionsUserDefined {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_execution_providers(
+        mut self,
+        execution_providers: Vec<ExecutionProviderDispatch>,
+    ) -> Self {
+        self.execution_providers = execution_providers;
+        self
+    }
+
+    pub fn with_max_length(mut self, max_length: usize) -> Self {
+        self.max_length = max_length;
+        self
+    }
+
+    /// Create the given number of ONNX sessions, each with an equal share of the threads,
+    /// and run each batch on whichever is free. Defaults to `1`
+    ///
+    /// This increases the throughput when several threads share the model, at the cost of
+    /// loading the model once per session.
+    pub fn with_session_pool_size(mut self, session_pool_size: usize) -> Self {
+        self.session_pool_size = session_pool_size;
+        self
+    }
+
+    /// Set the configuration of the ONNX Runtime sessions, such as the number of threads
+    pub fn with_session_options(mut self, session_options: SessionOptions) -> Self {
+        self.session_options = session_options;
+        self
+    }
+}
+
+impl Default for LateInteractionInitOptionsUserDefined {
+    fn default() -> Self {
+        Self {
+            execution_providers: Default::default(),
+            max_length: DEFAULT_MAX_LENGTH,
+            session_pool_size: 1,
+            session_options: Default::default(),
+        }
+    }
+}
+
+/// Convert LateInteractionInitOptions to LateInteractionInitOptionsUserDefined
+///
+/// This is useful for when the user wants to use the same options for both the default and user-defined models
+impl From<LateInteractionInitOptions> for LateInteractionInitOptionsUserDefined {
+    fn from(options: LateInteractionInitOptions) -> Self {
+        LateInteractionInitOptionsUserDefined {
+            execution_providers: options.execution_providers,
+            max_length: options.max_length,
+            session_pool_size: options.session_pool_size,
+            session_options: options.session_options,
+        }
+    }
+}
+
+/// Struct for "bring your own" late interaction models
+///
+/// The onnx_file and tokenizer_files are expecting the files' bytes.
+/// The tokenizer is expected to use the BERT vocabulary, with the `[unused0]` and
+/// `[unused1]` tokens used as query and document markers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct UserDefinedLateInteractionModel {
+    pub onnx_file: Vec<u8>,
+    pub tokenizer_files: TokenizerFiles,
+}
+
+impl UserDefinedLateInteractionModel {
+    pub fn new(onnx_file: Vec<u8>, tokenizer_files: TokenizerFiles) -> Self {
+        Self {
+            onnx_file,
+            tokenizer_files,
+        }
+    }
+}
+
+/// Rust representation of the LateInteractionTextEmbedding model
+pub struct LateInteractionTextEmbedding {
+    pub tokenizer: Tokenizer,
+    pub(crate) sessions: SessionPool,
+    pub(crate) need_token_type_ids: bool,
+    pub(crate) mask_token_id: u32,
+    pub(crate) skip_list: Vec<u32>,
+}
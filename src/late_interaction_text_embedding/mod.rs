@@ -0,0 +1,20 @@
+use crate::models::late_interaction::LateInteractionModel;
+
+const DEFAULT_BATCH_SIZE: usize = 256;
+const DEFAULT_MAX_LENGTH: usize = 512;
+const DEFAULT_EMBEDDING_MODEL: LateInteractionModel = LateInteractionModel::ColBERTV2;
+
+/// Token id of `[unused0]`, inserted after `[CLS]` to mark queries.
+const QUERY_MARKER_TOKEN_ID: u32 = 1;
+/// Token id of `[unused1]`, inserted after `[CLS]` to mark documents.
+const DOCUMENT_MARKER_TOKEN_ID: u32 = 2;
+/// Queries shorter than this are padded with `[MASK]` tokens (query augmentation).
+const MIN_QUERY_LENGTH: usize = 31;
+const MASK_TOKEN: &str = "[MASK]";
+/// Tokens that are dropped from document embeddings.
+const PUNCTUATION: &str = "!\"#$%&'()*+,-./:;<=>?@[\\]^_`{|}~";
+
+mod init;
+pub use init::*;
+
+mod r#impl;
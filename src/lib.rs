@@ -36,45 +36,71 @@
 # fn embedding_demo() -> anyhow::Result<()> {
 # let model: TextEmbedding = TextEmbedding::try_new(Default::default())?;
  let documents = vec![
-    "passage: Hello, World!",
-    "query: Hello, World!",
-    "passage: This is an example passage.",
-    // You can leave out the prefix but it's recommended
+    "Hello, World!",
+    "This is an example passage.",
     "fastembed-rs is licensed under MIT"
     ];
 
  // Generate embeddings with the default batch size, 256
- let embeddings = model.embed(documents, None)?;
+ let embeddings = model.embed(documents.clone(), None)?;
 
- println!("Embeddings length: {}", embeddings.len()); // -> Embeddings length: 4
+ println!("Embeddings length: {}", embeddings.len()); // -> Embeddings length: 3
+
+ // For retrieval, use the query and document prefixes the model was trained with, if any
+ let query_embeddings = model.embed_query(vec!["What is fastembed-rs?"], None)?;
+ let document_embeddings = model.embed_documents(documents, None)?;
  # Ok(())
  # }
  ```
 "#
 )]
 
+#[cfg(feature = "async")]
+mod asynchronous;
 mod common;
+mod embedding_cache;
 mod image_embedding;
+pub mod index;
+mod late_interaction_text_embedding;
 mod models;
 pub mod output;
 mod pooling;
+mod progress;
+mod quantized;
 mod reranking;
+mod session_options;
+mod session_pool;
+pub mod similarity;
 mod sparse_text_embedding;
-mod text_embedding;
+pub mod text_embedding;
 
 pub use ort::execution_providers::ExecutionProviderDispatch;
 
+#[cfg(feature = "async")]
+pub use crate::asynchronous::set_max_concurrent_inferences;
+
 pub use crate::common::{
-    read_file_to_bytes, Embedding, Error, SparseEmbedding, TokenizerFiles, DEFAULT_CACHE_DIR,
+    read_file_to_bytes, Embedding, Error, PaddingSide, SparseEmbedding, TokenEmbeddings,
+    TokenizedInput, TokenizerFiles, TruncationReport, WindowAggregation, WindowedEmbedding,
+    DEFAULT_CACHE_DIR,
 };
+pub use crate::embedding_cache::EmbeddingCacheOptions;
 pub use crate::models::{
-    model_info::ModelInfo, model_info::RerankerModelInfo, quantization::QuantizationMode,
+    model_info::EmbeddingTask, model_info::ModelInfo, model_info::RerankerModelInfo,
+    model_info::TaskPrefixes, quantization::QuantizationMode,
 };
 pub use crate::output::{EmbeddingOutput, OutputKey, OutputPrecedence, SingleBatchOutput};
-pub use crate::pooling::Pooling;
+pub use crate::pooling::{CustomPooling, Pooling};
+pub use crate::progress::{CancellationToken, Cancelled, EmbedControl, EmbedProgress};
+pub use crate::quantized::{
+    f16_dot, from_f16, to_f16, BinaryEmbedding, F16Embedding, Int8Embedding, ScalarRange,
+    Uint8Embedding,
+};
+pub use crate::session_options::{OptimizationLevel, SessionOptions};
+pub use half::f16;
 
 // For Text Embedding
-pub use crate::models::text_embedding::{EmbeddingModel, get_model_info};
+pub use crate::models::text_embedding::{get_model_info, EmbeddingModel};
 pub use crate::text_embedding::{
     InitOptions, InitOptionsUserDefined, TextEmbedding, UserDefinedEmbeddingModel,
 };
@@ -82,8 +108,15 @@ pub use crate::text_embedding::{
 // For Sparse Text Embedding
 pub use crate::models::sparse::SparseModel;
 pub use crate::sparse_text_embedding::{
-    SparseInitOptions, SparseTextEmbedding, UserDefinedSparseModel,
+    SparseInitOptions, SparseInitOptionsUserDefined, SparseTextEmbedding, UserDefinedSparseModel,
+};
+
+// For Late Interaction Text Embedding
+pub use crate::late_interaction_text_embedding::{
+    LateInteractionInitOptions, LateInteractionInitOptionsUserDefined,
+    LateInteractionTextEmbedding, UserDefinedLateInteractionModel,
 };
+pub use crate::models::late_interaction::LateInteractionModel;
 
 // For Image Embedding
 pub use crate::image_embedding::{
@@ -25,6 +25,8 @@ pub fn models_list() -> Vec<ModelInfo<ImageEmbeddingModel>> {
             model_code: String::from("Qdrant/clip-ViT-B-32-vision"),
             model_file: String::from("model.onnx"),
             additional_files: Vec::new(),
+            matryoshka_dims: Vec::new(),
+            prefixes: Default::default(),
         },
         ModelInfo {
             model: ImageEmbeddingModel::Resnet50,
@@ -33,6 +35,8 @@ pub fn models_list() -> Vec<ModelInfo<ImageEmbeddingModel>> {
             model_code: String::from("Qdrant/resnet50-onnx"),
             model_file: String::from("model.onnx"),
             additional_files: Vec::new(),
+            matryoshka_dims: Vec::new(),
+            prefixes: Default::default(),
         },
         ModelInfo {
             model: ImageEmbeddingModel::UnicomVitB16,
@@ -41,6 +45,8 @@ pub fn models_list() -> Vec<ModelInfo<ImageEmbeddingModel>> {
             model_code: String::from("Qdrant/Unicom-ViT-B-16"),
             model_file: String::from("model.onnx"),
             additional_files: Vec::new(),
+            matryoshka_dims: Vec::new(),
+            prefixes: Default::default(),
         },
         ModelInfo {
             model: ImageEmbeddingModel::UnicomVitB32,
@@ -49,6 +55,8 @@ pub fn models_list() -> Vec<ModelInfo<ImageEmbeddingModel>> {
             model_code: String::from("Qdrant/Unicom-ViT-B-32"),
             model_file: String::from("model.onnx"),
             additional_files: Vec::new(),
+            matryoshka_dims: Vec::new(),
+            prefixes: Default::default(),
         },
         ModelInfo {
             model: ImageEmbeddingModel::NomicEmbedVisionV15,
@@ -57,6 +65,8 @@ pub fn models_list() -> Vec<ModelInfo<ImageEmbeddingModel>> {
             model_code: String::from("nomic-ai/nomic-embed-vision-v1.5"),
             model_file: String::from("onnx/model.onnx"),
             additional_files: Vec::new(),
+            matryoshka_dims: Vec::new(),
+            prefixes: Default::default(),
         },
     ];
 
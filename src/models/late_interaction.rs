@@ -0,0 +1,46 @@
+use std::fmt::Display;
+
+use crate::ModelInfo;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LateInteractionModel {
+    /// colbert-ir/colbertv2.0
+    ColBERTV2,
+    /// answerdotai/answerai-colbert-small-v1
+    AnswerAIColBERTSmallV1,
+}
+
+pub fn models_list() -> Vec<ModelInfo<LateInteractionModel>> {
+    vec![
+        ModelInfo {
+            model: LateInteractionModel::ColBERTV2,
+            dim: 128,
+            description: String::from("ColBERTv2 late interaction model"),
+            model_code: String::from("colbert-ir/colbertv2.0"),
+            model_file: String::from("model.onnx"),
+            additional_files: Vec::new(),
+            matryoshka_dims: Vec::new(),
+            prefixes: Default::default(),
+        },
+        ModelInfo {
+            model: LateInteractionModel::AnswerAIColBERTSmallV1,
+            dim: 96,
+            description: String::from("Small English late interaction model from Answer.AI"),
+            model_code: String::from("answerdotai/answerai-colbert-small-v1"),
+            model_file: String::from("vespa_colbert.onnx"),
+            additional_files: Vec::new(),
+            matryoshka_dims: Vec::new(),
+            prefixes: Default::default(),
+        },
+    ]
+}
+
+impl Display for LateInteractionModel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let model_info = models_list()
+            .into_iter()
+            .find(|model| model.model == *self)
+            .unwrap();
+        write!(f, "{}", model_info.model_code)
+    }
+}
@@ -1,4 +1,5 @@
 pub mod image_embedding;
+pub mod late_interaction;
 pub mod model_info;
 pub mod quantization;
 pub mod reranking;
@@ -2,6 +2,7 @@ use crate::RerankerModel;
 
 /// Data struct about the available models
 #[derive(Debug, Clone)]
+#[non_exhaustive]
 pub struct ModelInfo<T> {
     pub model: T,
     pub dim: usize,
@@ -9,6 +10,70 @@ pub struct ModelInfo<T> {
     pub model_code: String,
     pub model_file: String,
     pub additional_files: Vec<String>,
+    /// Output dimensions the model was trained to support through Matryoshka
+    /// Representation Learning, i.e. the embeddings can be truncated to any of these with
+    /// [`InitOptions::with_truncate_dim`](crate::InitOptions::with_truncate_dim).
+    ///
+    /// Empty if the model does not support truncation.
+    pub matryoshka_dims: Vec<usize>,
+    /// Prefixes the model expects in front of its inputs for each [`EmbeddingTask`].
+    pub prefixes: TaskPrefixes,
+}
+
+/// Task that an embedding is generated for, which some models expect to be marked by a
+/// prefix in front of the input
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EmbeddingTask {
+    /// A search query, to be compared against documents
+    Query,
+    /// A document to be searched
+    Document,
+    /// An input to be classified
+    Classification,
+    /// An input to be clustered
+    Clustering,
+}
+
+/// Prefixes a model expects in front of its inputs for each [`EmbeddingTask`]
+///
+/// Empty prefixes are not applied.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TaskPrefixes {
+    pub query: String,
+    pub document: String,
+    pub classification: String,
+    pub clustering: String,
+}
+
+impl TaskPrefixes {
+    /// Create prefixes for queries and documents, without classification or clustering prefixes
+    pub fn new(query: impl Into<String>, document: impl Into<String>) -> Self {
+        Self {
+            query: query.into(),
+            document: document.into(),
+            ..Default::default()
+        }
+    }
+
+    pub fn with_classification(mut self, classification: impl Into<String>) -> Self {
+        self.classification = classification.into();
+        self
+    }
+
+    pub fn with_clustering(mut self, clustering: impl Into<String>) -> Self {
+        self.clustering = clustering.into();
+        self
+    }
+
+    /// Get the prefix for the given task
+    pub fn get(&self, task: EmbeddingTask) -> &str {
+        match task {
+            EmbeddingTask::Query => &self.query,
+            EmbeddingTask::Document => &self.document,
+            EmbeddingTask::Classification => &self.classification,
+            EmbeddingTask::Clustering => &self.clustering,
+        }
+    }
 }
 
 /// Data struct about the available reranker models
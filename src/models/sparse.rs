@@ -16,6 +16,8 @@ pub fn models_list() -> Vec<ModelInfo<SparseModel>> {
         model_code: String::from("Qdrant/Splade_PP_en_v1"),
         model_file: String::from("model.onnx"),
         additional_files: Vec::new(),
+        matryoshka_dims: Vec::new(),
+        prefixes: Default::default(),
     }]
 }
 
@@ -1,6 +1,6 @@
 use std::{collections::HashMap, fmt::Display, sync::OnceLock};
 
-use super::model_info::ModelInfo;
+use super::model_info::{ModelInfo, TaskPrefixes};
 
 /// Lazy static list of all available models.
 static MODEL_MAP: OnceLock<HashMap<EmbeddingModel, ModelInfo<EmbeddingModel>>> = OnceLock::new();
@@ -67,6 +67,29 @@ pub enum EmbeddingModel {
     JinaEmbeddingsV2BaseCode,
 }
 
+/// Query instruction recommended for retrieval with the English BGE models, also used by
+/// mixedbread-ai/mxbai-embed-large-v1. Documents are not prefixed.
+fn bge_en_prefixes() -> TaskPrefixes {
+    TaskPrefixes::new(
+        "Represent this sentence for searching relevant passages: ",
+        "",
+    )
+}
+
+/// Prefixes the nomic-embed-text models were trained with.
+fn nomic_prefixes() -> TaskPrefixes {
+    TaskPrefixes::new("search_query: ", "search_document: ")
+        .with_classification("classification: ")
+        .with_clustering("clustering: ")
+}
+
+/// Prefixes the E5 models were trained with. Symmetric tasks use the query prefix.
+fn e5_prefixes() -> TaskPrefixes {
+    TaskPrefixes::new("query: ", "passage: ")
+        .with_classification("query: ")
+        .with_clustering("query: ")
+}
+
 /// Centralized function to initialize the models map.
 fn init_models_map() -> HashMap<EmbeddingModel, ModelInfo<EmbeddingModel>> {
     let models_list = vec![
@@ -77,6 +100,8 @@ fn init_models_map() -> HashMap<EmbeddingModel, ModelInfo<EmbeddingModel>> {
             model_code: String::from("Qdrant/all-MiniLM-L6-v2-onnx"),
             model_file: String::from("model.onnx"),
             additional_files: Vec::new(),
+            matryoshka_dims: Vec::new(),
+            prefixes: TaskPrefixes::default(),
         },
         ModelInfo {
             model: EmbeddingModel::AllMiniLML6V2Q,
@@ -85,6 +110,8 @@ fn init_models_map() -> HashMap<EmbeddingModel, ModelInfo<EmbeddingModel>> {
             model_code: String::from("Xenova/all-MiniLM-L6-v2"),
             model_file: String::from("onnx/model_quantized.onnx"),
             additional_files: Vec::new(),
+            matryoshka_dims: Vec::new(),
+            prefixes: TaskPrefixes::default(),
         },
         ModelInfo {
             model: EmbeddingModel::AllMiniLML12V2,
@@ -93,6 +120,8 @@ fn init_models_map() -> HashMap<EmbeddingModel, ModelInfo<EmbeddingModel>> {
             model_code: String::from("Xenova/all-MiniLM-L12-v2"),
             model_file: String::from("onnx/model.onnx"),
             additional_files: Vec::new(),
+            matryoshka_dims: Vec::new(),
+            prefixes: TaskPrefixes::default(),
         },
         ModelInfo {
             model: EmbeddingModel::AllMiniLML12V2Q,
@@ -101,6 +130,8 @@ fn init_models_map() -> HashMap<EmbeddingModel, ModelInfo<EmbeddingModel>> {
             model_code: String::from("Xenova/all-MiniLM-L12-v2"),
             model_file: String::from("onnx/model_quantized.onnx"),
             additional_files: Vec::new(),
+            matryoshka_dims: Vec::new(),
+            prefixes: TaskPrefixes::default(),
         },
         ModelInfo {
             model: EmbeddingModel::BGEBaseENV15,
@@ -109,6 +140,8 @@ fn init_models_map() -> HashMap<EmbeddingModel, ModelInfo<EmbeddingModel>> {
             model_code: String::from("Xenova/bge-base-en-v1.5"),
             model_file: String::from("onnx/model.onnx"),
             additional_files: Vec::new(),
+            matryoshka_dims: Vec::new(),
+            prefixes: bge_en_prefixes(),
         },
         ModelInfo {
             model: EmbeddingModel::BGEBaseENV15Q,
@@ -117,6 +150,8 @@ fn init_models_map() -> HashMap<EmbeddingModel, ModelInfo<EmbeddingModel>> {
             model_code: String::from("Qdrant/bge-base-en-v1.5-onnx-Q"),
             model_file: String::from("model_optimized.onnx"),
             additional_files: Vec::new(),
+            matryoshka_dims: Vec::new(),
+            prefixes: bge_en_prefixes(),
         },
         ModelInfo {
             model: EmbeddingModel::BGELargeENV15,
@@ -125,6 +160,8 @@ fn init_models_map() -> HashMap<EmbeddingModel, ModelInfo<EmbeddingModel>> {
             model_code: String::from("Xenova/bge-large-en-v1.5"),
             model_file: String::from("onnx/model.onnx"),
             additional_files: Vec::new(),
+            matryoshka_dims: Vec::new(),
+            prefixes: bge_en_prefixes(),
         },
         ModelInfo {
             model: EmbeddingModel::BGELargeENV15Q,
@@ -133,6 +170,8 @@ fn init_models_map() -> HashMap<EmbeddingModel, ModelInfo<EmbeddingModel>> {
             model_code: String::from("Qdrant/bge-large-en-v1.5-onnx-Q"),
             model_file: String::from("model_optimized.onnx"),
             additional_files: Vec::new(),
+            matryoshka_dims: Vec::new(),
+            prefixes: bge_en_prefixes(),
         },
         ModelInfo {
             model: EmbeddingModel::BGESmallENV15,
@@ -141,6 +180,8 @@ fn init_models_map() -> HashMap<EmbeddingModel, ModelInfo<EmbeddingModel>> {
             model_code: String::from("Xenova/bge-small-en-v1.5"),
             model_file: String::from("onnx/model.onnx"),
             additional_files: Vec::new(),
+            matryoshka_dims: Vec::new(),
+            prefixes: bge_en_prefixes(),
         },
         ModelInfo {
             model: EmbeddingModel::BGESmallENV15Q,
@@ -151,6 +192,8 @@ fn init_models_map() -> HashMap<EmbeddingModel, ModelInfo<EmbeddingModel>> {
             model_code: String::from("Qdrant/bge-small-en-v1.5-onnx-Q"),
             model_file: String::from("model_optimized.onnx"),
             additional_files: Vec::new(),
+            matryoshka_dims: Vec::new(),
+            prefixes: bge_en_prefixes(),
         },
         ModelInfo {
             model: EmbeddingModel::NomicEmbedTextV1,
@@ -159,6 +202,8 @@ fn init_models_map() -> HashMap<EmbeddingModel, ModelInfo<EmbeddingModel>> {
             model_code: String::from("nomic-ai/nomic-embed-text-v1"),
             model_file: String::from("onnx/model.onnx"),
             additional_files: Vec::new(),
+            matryoshka_dims: Vec::new(),
+            prefixes: nomic_prefixes(),
         },
         ModelInfo {
             model: EmbeddingModel::NomicEmbedTextV15,
@@ -167,6 +212,8 @@ fn init_models_map() -> HashMap<EmbeddingModel, ModelInfo<EmbeddingModel>> {
             model_code: String::from("nomic-ai/nomic-embed-text-v1.5"),
             model_file: String::from("onnx/model.onnx"),
             additional_files: Vec::new(),
+            matryoshka_dims: vec![64, 128, 256, 512, 768],
+            prefixes: nomic_prefixes(),
         },
         ModelInfo {
             model: EmbeddingModel::NomicEmbedTextV15Q,
@@ -177,6 +224,8 @@ fn init_models_map() -> HashMap<EmbeddingModel, ModelInfo<EmbeddingModel>> {
             model_code: String::from("nomic-ai/nomic-embed-text-v1.5"),
             model_file: String::from("onnx/model_quantized.onnx"),
             additional_files: Vec::new(),
+            matryoshka_dims: vec![64, 128, 256, 512, 768],
+            prefixes: nomic_prefixes(),
         },
         ModelInfo {
             model: EmbeddingModel::ParaphraseMLMiniLML12V2Q,
@@ -185,6 +234,8 @@ fn init_models_map() -> HashMap<EmbeddingModel, ModelInfo<EmbeddingModel>> {
             model_code: String::from("Qdrant/paraphrase-multilingual-MiniLM-L12-v2-onnx-Q"),
             model_file: String::from("model_optimized.onnx"),
             additional_files: Vec::new(),
+            matryoshka_dims: Vec::new(),
+            prefixes: TaskPrefixes::default(),
         },
         ModelInfo {
             model: EmbeddingModel::ParaphraseMLMiniLML12V2,
@@ -193,6 +244,8 @@ fn init_models_map() -> HashMap<EmbeddingModel, ModelInfo<EmbeddingModel>> {
             model_code: String::from("Xenova/paraphrase-multilingual-MiniLM-L12-v2"),
             model_file: String::from("onnx/model.onnx"),
             additional_files: Vec::new(),
+            matryoshka_dims: Vec::new(),
+            prefixes: TaskPrefixes::default(),
         },
         ModelInfo {
             model: EmbeddingModel::ParaphraseMLMpnetBaseV2,
@@ -203,6 +256,8 @@ fn init_models_map() -> HashMap<EmbeddingModel, ModelInfo<EmbeddingModel>> {
             model_code: String::from("Xenova/paraphrase-multilingual-mpnet-base-v2"),
             model_file: String::from("onnx/model.onnx"),
             additional_files: Vec::new(),
+            matryoshka_dims: Vec::new(),
+            prefixes: TaskPrefixes::default(),
         },
         ModelInfo {
             model: EmbeddingModel::BGESmallZHV15,
@@ -211,6 +266,8 @@ fn init_models_map() -> HashMap<EmbeddingModel, ModelInfo<EmbeddingModel>> {
             model_code: String::from("Xenova/bge-small-zh-v1.5"),
             model_file: String::from("onnx/model.onnx"),
             additional_files: Vec::new(),
+            matryoshka_dims: Vec::new(),
+            prefixes: TaskPrefixes::new("为这个句子生成表示以用于检索相关文章：", ""),
         },
         ModelInfo {
             model: EmbeddingModel::ModernBertEmbedLarge,
@@ -219,6 +276,8 @@ fn init_models_map() -> HashMap<EmbeddingModel, ModelInfo<EmbeddingModel>> {
             model_code: String::from("lightonai/modernbert-embed-large"),
             model_file: String::from("onnx/model.onnx"),
             additional_files: Vec::new(),
+            matryoshka_dims: Vec::new(),
+            prefixes: TaskPrefixes::new("search_query: ", "search_document: "),
         },
         ModelInfo {
             model: EmbeddingModel::MultilingualE5Small,
@@ -227,6 +286,8 @@ fn init_models_map() -> HashMap<EmbeddingModel, ModelInfo<EmbeddingModel>> {
             model_code: String::from("intfloat/multilingual-e5-small"),
             model_file: String::from("onnx/model.onnx"),
             additional_files: Vec::new(),
+            matryoshka_dims: Vec::new(),
+            prefixes: e5_prefixes(),
         },
         ModelInfo {
             model: EmbeddingModel::MultilingualE5Base,
@@ -235,6 +296,8 @@ fn init_models_map() -> HashMap<EmbeddingModel, ModelInfo<EmbeddingModel>> {
             model_code: String::from("intfloat/multilingual-e5-base"),
             model_file: String::from("onnx/model.onnx"),
             additional_files: Vec::new(),
+            matryoshka_dims: Vec::new(),
+            prefixes: e5_prefixes(),
         },
         ModelInfo {
             model: EmbeddingModel::MultilingualE5Large,
@@ -243,6 +306,8 @@ fn init_models_map() -> HashMap<EmbeddingModel, ModelInfo<EmbeddingModel>> {
             model_code: String::from("Qdrant/multilingual-e5-large-onnx"),
             model_file: String::from("model.onnx"),
             additional_files: vec!["model.onnx_data".to_string()],
+            matryoshka_dims: Vec::new(),
+            prefixes: e5_prefixes(),
         },
         ModelInfo {
             model: EmbeddingModel::MxbaiEmbedLargeV1,
@@ -251,6 +316,8 @@ fn init_models_map() -> HashMap<EmbeddingModel, ModelInfo<EmbeddingModel>> {
             model_code: String::from("mixedbread-ai/mxbai-embed-large-v1"),
             model_file: String::from("onnx/model.onnx"),
             additional_files: Vec::new(),
+            matryoshka_dims: vec![64, 128, 256, 512, 1024],
+            prefixes: bge_en_prefixes(),
         },
         ModelInfo {
             model: EmbeddingModel::MxbaiEmbedLargeV1Q,
@@ -259,6 +326,8 @@ fn init_models_map() -> HashMap<EmbeddingModel, ModelInfo<EmbeddingModel>> {
             model_code: String::from("mixedbread-ai/mxbai-embed-large-v1"),
             model_file: String::from("onnx/model_quantized.onnx"),
             additional_files: Vec::new(),
+            matryoshka_dims: vec![64, 128, 256, 512, 1024],
+            prefixes: bge_en_prefixes(),
         },
         ModelInfo {
             model: EmbeddingModel::GTEBaseENV15,
@@ -267,6 +336,8 @@ fn init_models_map() -> HashMap<EmbeddingModel, ModelInfo<EmbeddingModel>> {
             model_code: String::from("Alibaba-NLP/gte-base-en-v1.5"),
             model_file: String::from("onnx/model.onnx"),
             additional_files: Vec::new(),
+            matryoshka_dims: Vec::new(),
+            prefixes: TaskPrefixes::default(),
         },
         ModelInfo {
             model: EmbeddingModel::GTEBaseENV15Q,
@@ -275,6 +346,8 @@ fn init_models_map() -> HashMap<EmbeddingModel, ModelInfo<EmbeddingModel>> {
             model_code: String::from("Alibaba-NLP/gte-base-en-v1.5"),
             model_file: String::from("onnx/model_quantized.onnx"),
             additional_files: Vec::new(),
+            matryoshka_dims: Vec::new(),
+            prefixes: TaskPrefixes::default(),
         },
         ModelInfo {
             model: EmbeddingModel::GTELargeENV15,
@@ -283,6 +356,8 @@ fn init_models_map() -> HashMap<EmbeddingModel, ModelInfo<EmbeddingModel>> {
             model_code: String::from("Alibaba-NLP/gte-large-en-v1.5"),
             model_file: String::from("onnx/model.onnx"),
             additional_files: Vec::new(),
+            matryoshka_dims: Vec::new(),
+            prefixes: TaskPrefixes::default(),
         },
         ModelInfo {
             model: EmbeddingModel::GTELargeENV15Q,
@@ -291,6 +366,8 @@ fn init_models_map() -> HashMap<EmbeddingModel, ModelInfo<EmbeddingModel>> {
             model_code: String::from("Alibaba-NLP/gte-large-en-v1.5"),
             model_file: String::from("onnx/model_quantized.onnx"),
             additional_files: Vec::new(),
+            matryoshka_dims: Vec::new(),
+            prefixes: TaskPrefixes::default(),
         },
         ModelInfo {
             model: EmbeddingModel::ClipVitB32,
@@ -299,6 +376,8 @@ fn init_models_map() -> HashMap<EmbeddingModel, ModelInfo<EmbeddingModel>> {
             model_code: String::from("Qdrant/clip-ViT-B-32-text"),
             model_file: String::from("model.onnx"),
             additional_files: Vec::new(),
+            matryoshka_dims: Vec::new(),
+            prefixes: TaskPrefixes::default(),
         },
         ModelInfo {
             model: EmbeddingModel::JinaEmbeddingsV2BaseCode,
@@ -307,6 +386,8 @@ fn init_models_map() -> HashMap<EmbeddingModel, ModelInfo<EmbeddingModel>> {
             model_code: String::from("jinaai/jina-embeddings-v2-base-code"),
             model_file: String::from("onnx/model.onnx"),
             additional_files: Vec::new(),
+            matryoshka_dims: Vec::new(),
+            prefixes: TaskPrefixes::default(),
         },
     ];
 
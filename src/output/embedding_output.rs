@@ -1,5 +1,6 @@
 use ndarray::{Array2, ArrayView, Dim, IxDynImpl};
 use ort::session::SessionOutputs;
+use tokenizers::Encoding;
 
 use crate::pooling;
 
@@ -12,7 +13,11 @@ use super::{OutputKey, OutputPrecedence};
 /// post-processing to be performed.
 pub struct SingleBatchOutput<'r, 's> {
     pub session_outputs: SessionOutputs<'r, 's>,
+    pub input_ids_array: Array2<i64>,
     pub attention_mask_array: Array2<i64>,
+    /// The tokenizer output for each input of the batch, including the character offsets of
+    /// each token.
+    pub encodings: Vec<Encoding>,
 }
 
 impl SingleBatchOutput<'_, '_> {
@@ -23,7 +28,7 @@ impl SingleBatchOutput<'_, '_> {
     pub fn select_output(
         &self,
         precedence: &impl OutputPrecedence,
-    ) -> anyhow::Result<ArrayView<f32, Dim<IxDynImpl>>> {
+    ) -> anyhow::Result<ArrayView<'_, f32, Dim<IxDynImpl>>> {
         let ort_output: &ort::value::Value = precedence
             .key_precedence()
             .find_map(|key| match key {
@@ -60,16 +65,26 @@ impl SingleBatchOutput<'_, '_> {
     ) -> anyhow::Result<Array2<f32>> {
         let tensor = self.select_output(precedence)?;
 
-        // If there is none pooling, default to cls so as not to break the existing implementations
-        // TODO: Consider return output as is to support custom model that has built-in pooling layer:
-        // - [] Add model with built-in pooling to the list of supported model in ``models::text_embedding::models_list``
-        // - [] Write unit test for new model
-        // - [] Update ``pooling::Pooling`` to include None type
-        // - [] Change the line below to return output as is
-        // - [] Release major version because of breaking changes
+        // If there is no pooling specified, default to cls so as not to break the existing
+        // implementations. Models with a built-in pooling layer should use `Pooling::None`
+        // to have their output returned as is.
+        // TODO: Default to `Pooling::None` with the next major version.
         match pooling_opt.unwrap_or_default() {
             pooling::Pooling::Cls => pooling::cls(&tensor),
             pooling::Pooling::Mean => pooling::mean(&tensor, self.attention_mask_array.clone()),
+            pooling::Pooling::LastToken => pooling::last_token(&tensor, &self.attention_mask_array),
+            pooling::Pooling::None => pooling::none(&tensor),
+            pooling::Pooling::Max => pooling::max(&tensor, &self.attention_mask_array),
+            pooling::Pooling::WeightedMean => {
+                pooling::weighted_mean(&tensor, &self.attention_mask_array)
+            }
+            pooling::Pooling::Custom(custom) => custom.pool(&tensor, &self.attention_mask_array),
+            pooling::Pooling::EosToken(eos_token_id) => pooling::eos_token(
+                &tensor,
+                &self.input_ids_array,
+                &self.attention_mask_array,
+                eos_token_id,
+            ),
         }
     }
 }
@@ -7,19 +7,14 @@
 //! e.g. reading the output keys from the model file.
 
 /// Enum for defining the key of the output.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub enum OutputKey {
+    #[default]
     OnlyOne,
     ByOrder(usize),
     ByName(&'static str),
 }
 
-impl Default for OutputKey {
-    fn default() -> Self {
-        Self::OnlyOne
-    }
-}
-
 /// Trait for defining a precedence of keys in the output.
 ///
 /// This defines the order of precedence for selecting the output from the session outputs.
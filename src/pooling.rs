@@ -1,11 +1,94 @@
-use ndarray::{s, Array2, ArrayView, Dim, Dimension, IxDynImpl};
+use std::{fmt, sync::Arc};
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+use ndarray::{s, Array2, ArrayView, ArrayView3, Axis, Dim, Dimension, Ix3, IxDynImpl};
+
+#[derive(Debug, Clone)]
 pub enum Pooling {
     Cls,
     Mean,
+    /// Take the embedding of the last non-padding token of each sequence, as used by
+    /// decoder-based embedding models (e.g. Qwen or Mistral based gte/e5 variants).
+    ///
+    /// The position is located with the attention mask, so this works with both left
+    /// and right padding.
+    LastToken,
+    /// Take the embedding at the first occurrence of the given EOS token id in each
+    /// sequence, as used by CLIP text towers.
+    ///
+    /// Falls back to the last non-padding token if the EOS token is not present,
+    /// e.g. because the input was truncated.
+    EosToken(u32),
+    /// Return the model output as-is, for models with pooling built into the ONNX graph.
+    ///
+    /// The selected output must already be a 2D `(batch_size, hidden_size)` tensor.
+    None,
+    /// Take the element-wise maximum over the token embeddings that are not masked out.
+    Max,
+    /// Position-weighted mean of the token embeddings, giving later tokens linearly more
+    /// weight, as used by SGPT-style decoder models.
+    WeightedMean,
+    /// A user-supplied pooling strategy, see [`CustomPooling`].
+    Custom(Arc<dyn CustomPooling>),
 }
 
+/// Trait for supplying a custom pooling strategy through [`Pooling::Custom`].
+///
+/// The implementation receives the selected model output and the attention mask of one
+/// batch, and should return one pooled vector per row of the batch. The pooled vectors
+/// are normalized afterwards by the default transformer.
+///
+/// ```
+/// use fastembed::{CustomPooling, Pooling};
+/// use ndarray::{s, Array2, ArrayView, Dim, IxDynImpl};
+/// use std::sync::Arc;
+///
+/// /// Pool on the second token of every sequence.
+/// struct SecondToken;
+///
+/// impl CustomPooling for SecondToken {
+///     fn pool(
+///         &self,
+///         token_embeddings: &ArrayView<f32, Dim<IxDynImpl>>,
+///         _attention_mask: &Array2<i64>,
+///     ) -> anyhow::Result<Array2<f32>> {
+///         Ok(token_embeddings.slice(s![.., 1, ..]).to_owned())
+///     }
+/// }
+///
+/// let pooling = Pooling::Custom(Arc::new(SecondToken));
+/// ```
+pub trait CustomPooling: Send + Sync {
+    /// Pool a `(batch_size, sequence_length, hidden_size)` tensor into a
+    /// `(batch_size, hidden_size)` tensor.
+    ///
+    /// The output selected by the [`OutputPrecedence`](crate::OutputPrecedence) might not
+    /// be 3D, so implementations should check the shape of `token_embeddings`.
+    fn pool(
+        &self,
+        token_embeddings: &ArrayView<f32, Dim<IxDynImpl>>,
+        attention_mask: &Array2<i64>,
+    ) -> anyhow::Result<Array2<f32>>;
+}
+
+impl fmt::Debug for dyn CustomPooling {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("<custom pooling>")
+    }
+}
+
+/// Custom poolings are only equal to themselves, i.e. if they share the same [`Arc`].
+impl PartialEq for Pooling {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::EosToken(a), Self::EosToken(b)) => a == b,
+            (Self::Custom(a), Self::Custom(b)) => Arc::ptr_eq(a, b),
+            _ => std::mem::discriminant(self) == std::mem::discriminant(other),
+        }
+    }
+}
+
+impl Eq for Pooling {}
+
 impl Default for Pooling {
     /// Change this to define the default pooling strategy.
     ///
@@ -26,6 +109,18 @@ pub fn cls(tensor: &ArrayView<f32, Dim<IxDynImpl>>) -> anyhow::Result<Array2<f32
     }
 }
 
+/// Pass the previous layer output through without pooling, asserting that it is already pooled.
+pub fn none(tensor: &ArrayView<f32, Dim<IxDynImpl>>) -> anyhow::Result<Array2<f32>> {
+    match tensor.dim().ndim() {
+        2 => Ok(tensor.slice(s![.., ..]).to_owned()),
+        _ => Err(anyhow::Error::msg(format!(
+            "Invalid output shape: {shape:?}. Expected a 2D tensor when pooling is `Pooling::None`; \
+            choose an output that is pooled within the model, or use another pooling method.",
+            shape = tensor.dim()
+        ))),
+    }
+}
+
 /// Pool the previous layer output by taking the element-wise arithmetic mean of the token-level embeddings after applying the attention mask.
 /// * `token_embeddings` - token embeddings in form of a tensor output of the encoding.
 /// * `attention_mask_array` - is the same mask generated by Tokenizer and used for encoding.
@@ -74,3 +169,183 @@ pub fn mean(
     let mask_sum = mask_sum.mapv(|x| if x == 0f32 { 1.0 } else { x });
     Ok(&sum / &mask_sum)
 }
+
+/// Pool the previous layer output by taking the element-wise maximum of the token-level embeddings after applying the attention mask.
+/// * `token_embeddings` - token embeddings in form of a tensor output of the encoding.
+/// * `attention_mask_array` - is the same mask generated by Tokenizer and used for encoding.
+// Please refer to the original python implementation for more details:
+// https://github.com/UKPLab/sentence-transformers/blob/c0fc0e8238f7f48a1e92dc90f6f96c86f69f1e02/sentence_transformers/models/Pooling.py#L144
+pub fn max(
+    token_embeddings: &ArrayView<f32, Dim<IxDynImpl>>,
+    attention_mask_array: &Array2<i64>,
+) -> anyhow::Result<Array2<f32>> {
+    let Some(token_embeddings) = token_embeddings_3d(token_embeddings)? else {
+        return Ok(token_embeddings.slice(s![.., ..]).to_owned());
+    };
+    check_mask_shape(&token_embeddings, attention_mask_array)?;
+
+    let (batch_size, _, hidden_size) = token_embeddings.dim();
+    let mut pooled = Array2::from_elem((batch_size, hidden_size), f32::NEG_INFINITY);
+    pooled
+        .rows_mut()
+        .into_iter()
+        .zip(token_embeddings.outer_iter())
+        .zip(attention_mask_array.rows())
+        .for_each(|((mut pooled_row, sequence), mask)| {
+            sequence
+                .outer_iter()
+                .zip(mask.iter())
+                .filter(|(_, &m)| m != 0)
+                .for_each(|(token, _)| {
+                    pooled_row.zip_mut_with(&token, |p, &t| *p = p.max(t));
+                });
+        });
+
+    // Sequences without any unmasked token are pooled into zeros rather than -inf.
+    pooled.mapv_inplace(|x| if x == f32::NEG_INFINITY { 0.0 } else { x });
+    Ok(pooled)
+}
+
+/// Pool the previous layer output by taking the position-weighted mean of the token-level embeddings after applying the attention mask.
+///
+/// The token at position `i` (starting from 0) is weighted by `i + 1`.
+/// * `token_embeddings` - token embeddings in form of a tensor output of the encoding.
+/// * `attention_mask_array` - is the same mask generated by Tokenizer and used for encoding.
+// Please refer to the original python implementation for more details:
+// https://github.com/UKPLab/sentence-transformers/blob/c0fc0e8238f7f48a1e92dc90f6f96c86f69f1e02/sentence_transformers/models/Pooling.py#L180
+pub fn weighted_mean(
+    token_embeddings: &ArrayView<f32, Dim<IxDynImpl>>,
+    attention_mask_array: &Array2<i64>,
+) -> anyhow::Result<Array2<f32>> {
+    let Some(token_embeddings) = token_embeddings_3d(token_embeddings)? else {
+        return Ok(token_embeddings.slice(s![.., ..]).to_owned());
+    };
+    check_mask_shape(&token_embeddings, attention_mask_array)?;
+
+    let weights = Array2::from_shape_fn(attention_mask_array.dim(), |(row, position)| {
+        attention_mask_array[[row, position]] as f32 * (position + 1) as f32
+    });
+
+    let weighted_sum = (&token_embeddings * &weights.view().insert_axis(Axis(2))).sum_axis(Axis(1));
+    let weight_sum = weights
+        .sum_axis(Axis(1))
+        .mapv(|x| if x == 0f32 { 1.0 } else { x })
+        .insert_axis(Axis(1));
+    Ok(&weighted_sum / &weight_sum)
+}
+
+/// Pool the previous layer output by taking the embedding of the last token that is not masked out.
+/// * `token_embeddings` - token embeddings in form of a tensor output of the encoding.
+/// * `attention_mask_array` - is the same mask generated by Tokenizer and used for encoding.
+// Please refer to the original python implementation for more details:
+// https://github.com/UKPLab/sentence-transformers/blob/c0fc0e8238f7f48a1e92dc90f6f96c86f69f1e02/sentence_transformers/models/Pooling.py#L207
+pub fn last_token(
+    token_embeddings: &ArrayView<f32, Dim<IxDynImpl>>,
+    attention_mask_array: &Array2<i64>,
+) -> anyhow::Result<Array2<f32>> {
+    let positions = attention_mask_array
+        .rows()
+        .into_iter()
+        .map(|mask| last_unmasked_position(mask.iter()))
+        .collect::<Vec<_>>();
+
+    select_positions(token_embeddings, &positions)
+}
+
+/// Pool the previous layer output by taking the embedding at the first occurrence of `eos_token_id`.
+/// * `token_embeddings` - token embeddings in form of a tensor output of the encoding.
+/// * `input_ids_array` - the token ids that were fed to the model.
+/// * `attention_mask_array` - is the same mask generated by Tokenizer and used for encoding.
+pub fn eos_token(
+    token_embeddings: &ArrayView<f32, Dim<IxDynImpl>>,
+    input_ids_array: &Array2<i64>,
+    attention_mask_array: &Array2<i64>,
+    eos_token_id: u32,
+) -> anyhow::Result<Array2<f32>> {
+    let positions = input_ids_array
+        .rows()
+        .into_iter()
+        .zip(attention_mask_array.rows())
+        .map(|(ids, mask)| {
+            ids.iter()
+                .zip(mask.iter())
+                .position(|(&id, &m)| m != 0 && id == eos_token_id as i64)
+                .unwrap_or_else(|| last_unmasked_position(mask.iter()))
+        })
+        .collect::<Vec<_>>();
+
+    select_positions(token_embeddings, &positions)
+}
+
+/// Find the index of the last position that is not masked out.
+///
+/// Defaults to the last position if the whole sequence is masked.
+fn last_unmasked_position<'a>(
+    mask: impl DoubleEndedIterator<Item = &'a i64> + ExactSizeIterator,
+) -> usize {
+    let len = mask.len();
+    mask.rev()
+        .position(|&m| m != 0)
+        .map_or(len.saturating_sub(1), |offset| len - 1 - offset)
+}
+
+/// Gather one token embedding per sequence at the given positions.
+fn select_positions(
+    token_embeddings: &ArrayView<f32, Dim<IxDynImpl>>,
+    positions: &[usize],
+) -> anyhow::Result<Array2<f32>> {
+    let Some(token_embeddings) = token_embeddings_3d(token_embeddings)? else {
+        return Ok(token_embeddings.slice(s![.., ..]).to_owned());
+    };
+
+    let (batch_size, _, hidden_size) = token_embeddings.dim();
+    if positions.len() != batch_size {
+        return Err(anyhow::Error::msg(format!(
+            "Expected {batch_size} pooling positions, got {}.",
+            positions.len()
+        )));
+    }
+
+    let mut pooled = Array2::zeros((batch_size, hidden_size));
+    pooled
+        .rows_mut()
+        .into_iter()
+        .zip(positions)
+        .enumerate()
+        .for_each(|(idx, (mut row, &position))| {
+            row.assign(&token_embeddings.slice(s![idx, position, ..]));
+        });
+    Ok(pooled)
+}
+
+/// View the token embeddings as a `(batch_size, sequence_length, hidden_size)` tensor.
+///
+/// Returns `None` for 2D tensors, for which it can be assumed that pooling is already
+/// done within the model.
+fn token_embeddings_3d<'a>(
+    token_embeddings: &'a ArrayView<f32, Dim<IxDynImpl>>,
+) -> anyhow::Result<Option<ArrayView3<'a, f32>>> {
+    match token_embeddings.dim().ndim() {
+        2 => Ok(None),
+        3 => Ok(Some(token_embeddings.view().into_dimensionality::<Ix3>()?)),
+        _ => Err(anyhow::Error::msg(format!(
+            "Invalid output shape: {shape:?}. Expected 2D or 3D tensor.",
+            shape = token_embeddings.dim()
+        ))),
+    }
+}
+
+fn check_mask_shape(
+    token_embeddings: &ArrayView3<f32>,
+    attention_mask_array: &Array2<i64>,
+) -> anyhow::Result<()> {
+    let (batch_size, sequence_length, _) = token_embeddings.dim();
+    if attention_mask_array.dim() != (batch_size, sequence_length) {
+        return Err(anyhow::Error::msg(format!(
+            "Attention mask of shape {:?} does not match token embeddings of shape {:?}.",
+            attention_mask_array.dim(),
+            token_embeddings.dim()
+        )));
+    }
+    Ok(())
+}
@@ -0,0 +1,150 @@
+//! Progress reporting and cancellation of long running calls, see [`EmbedControl`].
+//!
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use anyhow::Result;
+
+/// Progress of an embedding or reranking call, reported after each batch
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EmbedProgress {
+    pub batches_done: usize,
+    pub total_batches: usize,
+    pub items_done: usize,
+    pub total_items: usize,
+}
+
+/// Token to cancel embedding or reranking calls, e.g. from another thread
+///
+/// Clones share the same state, so cancelling any of them cancels the calls using the others.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cancel the calls using this token. Batches already running are completed first.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Error returned by calls cancelled through a [`CancellationToken`]
+///
+/// Check for it with [`anyhow::Error::is`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("The call was cancelled.")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// Progress callback and cancellation token of a single call
+///
+/// The cancellation token is checked before each batch is run, and the progress callback is
+/// called after each batch is done. Batches may run in parallel, but the callback is called
+/// for one batch at a time.
+#[derive(Clone, Default)]
+#[non_exhaustive]
+pub struct EmbedControl {
+    pub progress: Option<Arc<dyn Fn(EmbedProgress) + Send + Sync>>,
+    pub cancellation: Option<CancellationToken>,
+}
+
+impl fmt::Debug for EmbedControl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EmbedControl")
+            .field(
+                "progress",
+                &if self.progress.is_some() {
+                    "Some(<progress>)"
+                } else {
+                    "None"
+                },
+            )
+            .field("cancellation", &self.cancellation)
+            .finish()
+    }
+}
+
+impl EmbedControl {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_progress(
+        mut self,
+        progress: impl Fn(EmbedProgress) + Send + Sync + 'static,
+    ) -> Self {
+        self.progress = Some(Arc::new(progress));
+        self
+    }
+
+    pub fn with_cancellation(mut self, cancellation: CancellationToken) -> Self {
+        self.cancellation = Some(cancellation);
+        self
+    }
+}
+
+/// Tracks the batches of a single call against its [`EmbedControl`]
+pub(crate) struct BatchTracker<'c> {
+    control: &'c EmbedControl,
+    total_batches: usize,
+    total_items: usize,
+    /// Batches and items done so far
+    done: Mutex<(usize, usize)>,
+}
+
+impl<'c> BatchTracker<'c> {
+    pub(crate) fn new(control: &'c EmbedControl, total_batches: usize, total_items: usize) -> Self {
+        Self {
+            control,
+            total_batches,
+            total_items,
+            done: Mutex::new((0, 0)),
+        }
+    }
+
+    /// Run a batch of `items` inputs, unless the call was cancelled, then report the progress.
+    pub(crate) fn run<T>(&self, items: usize, run: impl FnOnce() -> Result<T>) -> Result<T> {
+        if self
+            .control
+            .cancellation
+            .as_ref()
+            .is_some_and(CancellationToken::is_cancelled)
+        {
+            return Err(Cancelled.into());
+        }
+
+        let output = run()?;
+
+        if let Some(progress) = &self.control.progress {
+            let mut done = self.done.lock().unwrap();
+            done.0 += 1;
+            done.1 += items;
+            progress(EmbedProgress {
+                batches_done: done.0,
+                total_batches: self.total_batches,
+                items_done: done.1,
+                total_items: self.total_items,
+            });
+        }
+
+        Ok(output)
+    }
+}
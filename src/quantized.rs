@@ -0,0 +1,225 @@
+//! Compact embedding formats, and the distance functions to compare them.
+//!
+use anyhow::Result;
+use half::f16;
+
+use crate::Embedding;
+
+/// Embedding stored as half-precision floats
+pub type F16Embedding = Vec<f16>;
+
+/// Embedding scalar-quantized to signed bytes, see [`ScalarRange`]
+pub type Int8Embedding = Vec<i8>;
+
+/// Embedding scalar-quantized to unsigned bytes, see [`ScalarRange`]
+pub type Uint8Embedding = Vec<u8>;
+
+/// Convert an embedding to half-precision floats
+pub fn to_f16(embedding: &[f32]) -> F16Embedding {
+    embedding.iter().copied().map(f16::from_f32).collect()
+}
+
+/// Convert a half-precision embedding back to single-precision floats
+pub fn from_f16(embedding: &[f16]) -> Embedding {
+    embedding.iter().map(|value| value.to_f32()).collect()
+}
+
+/// Dot product of a full-precision query and a half-precision embedding of the same dimension
+pub fn f16_dot(query: &[f32], embedding: &[f16]) -> Result<f32> {
+    check_dim(query.len(), embedding.len())?;
+    Ok(query
+        .iter()
+        .zip(embedding)
+        .map(|(q, value)| q * value.to_f32())
+        .sum())
+}
+
+fn check_dim(expected: usize, dim: usize) -> Result<()> {
+    if dim != expected {
+        return Err(anyhow::Error::msg(format!(
+            "Cannot compare embeddings of dimensions {expected} and {dim}."
+        )));
+    }
+    Ok(())
+}
+
+/// Per-dimension calibration range for scalar quantization to int8 and uint8
+///
+/// Each dimension is mapped linearly from `[min, max]` onto the 256 levels of a byte,
+/// clamping the values outside of the range. The range should be computed from a sample
+/// of embeddings of the same model and options, see [`ScalarRange::from_sample`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScalarRange {
+    pub min: Vec<f32>,
+    pub max: Vec<f32>,
+}
+
+impl ScalarRange {
+    /// Compute the range of each dimension over a sample of embeddings
+    pub fn from_sample(embeddings: &[Embedding]) -> Result<Self> {
+        let Some(first) = embeddings.first() else {
+            return Err(anyhow::Error::msg(
+                "Cannot calibrate the quantization range from an empty sample.",
+            ));
+        };
+
+        let mut range = Self {
+            min: first.clone(),
+            max: first.clone(),
+        };
+        for embedding in embeddings {
+            if embedding.len() != first.len() {
+                return Err(anyhow::Error::msg(format!(
+                    "Cannot calibrate the quantization range from embeddings of dimensions {} and {}.",
+                    first.len(),
+                    embedding.len()
+                )));
+            }
+            for (i, &value) in embedding.iter().enumerate() {
+                range.min[i] = range.min[i].min(value);
+                range.max[i] = range.max[i].max(value);
+            }
+        }
+
+        Ok(range)
+    }
+
+    /// The dimension of the embeddings this range applies to
+    pub fn dim(&self) -> usize {
+        self.min.len()
+    }
+
+    /// Size of a quantization step of each dimension
+    fn steps(&self) -> impl Iterator<Item = f32> + '_ {
+        self.min.iter().zip(&self.max).map(|(min, max)| {
+            let step = (max - min) / 255.0;
+            // Constant dimensions all quantize to the lowest level
+            if step > 0.0 {
+                step
+            } else {
+                1.0
+            }
+        })
+    }
+
+    /// Quantize each dimension to a level in `0..=255`
+    fn levels<'a>(&'a self, embedding: &'a [f32]) -> impl Iterator<Item = u8> + 'a {
+        embedding
+            .iter()
+            .zip(&self.min)
+            .zip(self.steps())
+            .map(|((value, min), step)| ((value - min) / step).round().clamp(0.0, 255.0) as u8)
+    }
+
+    pub fn quantize_uint8(&self, embedding: &[f32]) -> Uint8Embedding {
+        self.levels(embedding).collect()
+    }
+
+    pub fn quantize_int8(&self, embedding: &[f32]) -> Int8Embedding {
+        self.levels(embedding)
+            .map(|level| (level as i16 - 128) as i8)
+            .collect()
+    }
+
+    pub fn dequantize_uint8(&self, embedding: &[u8]) -> Embedding {
+        embedding
+            .iter()
+            .zip(&self.min)
+            .zip(self.steps())
+            .map(|((&level, min), step)| min + level as f32 * step)
+            .collect()
+    }
+
+    pub fn dequantize_int8(&self, embedding: &[i8]) -> Embedding {
+        embedding
+            .iter()
+            .zip(&self.min)
+            .zip(self.steps())
+            .map(|((&level, min), step)| min + (level as i16 + 128) as f32 * step)
+            .collect()
+    }
+
+    /// Asymmetric dot product of a full-precision query and a uint8 embedding
+    ///
+    /// Equal to the dot product of the query and the dequantized embedding, without
+    /// allocating it. Both must have the dimension of the range.
+    pub fn uint8_dot(&self, query: &[f32], embedding: &[u8]) -> Result<f32> {
+        check_dim(self.dim(), query.len())?;
+        check_dim(self.dim(), embedding.len())?;
+        Ok(query
+            .iter()
+            .zip(embedding)
+            .zip(self.min.iter().zip(self.steps()))
+            .map(|((q, &level), (min, step))| q * (min + level as f32 * step))
+            .sum())
+    }
+
+    /// Asymmetric dot product of a full-precision query and an int8 embedding
+    ///
+    /// Equal to the dot product of the query and the dequantized embedding, without
+    /// allocating it. Both must have the dimension of the range.
+    pub fn int8_dot(&self, query: &[f32], embedding: &[i8]) -> Result<f32> {
+        check_dim(self.dim(), query.len())?;
+        check_dim(self.dim(), embedding.len())?;
+        Ok(query
+            .iter()
+            .zip(embedding)
+            .zip(self.min.iter().zip(self.steps()))
+            .map(|((q, &level), (min, step))| q * (min + (level as i16 + 128) as f32 * step))
+            .sum())
+    }
+}
+
+/// Embedding quantized to one bit per dimension, set for positive values
+///
+/// The bits are packed into `u64` words, least significant bit first, and the unused bits
+/// of the last word are zero.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct BinaryEmbedding {
+    pub words: Vec<u64>,
+    pub dim: usize,
+}
+
+impl BinaryEmbedding {
+    pub fn from_embedding(embedding: &[f32]) -> Self {
+        let words = embedding
+            .chunks(64)
+            .map(|chunk| {
+                chunk
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, &value)| value > 0.0)
+                    .fold(0u64, |word, (bit, _)| word | (1 << bit))
+            })
+            .collect();
+
+        Self {
+            words,
+            dim: embedding.len(),
+        }
+    }
+
+    /// Map the set bits to `1.0` and the others to `-1.0`
+    pub fn dequantize(&self) -> Embedding {
+        (0..self.dim)
+            .map(|i| {
+                if self.words[i / 64] & (1 << (i % 64)) != 0 {
+                    1.0
+                } else {
+                    -1.0
+                }
+            })
+            .collect()
+    }
+
+    /// Number of dimensions in which two embeddings of the same dimension differ
+    pub fn hamming_distance(&self, other: &Self) -> Result<u32> {
+        check_dim(self.dim, other.dim)?;
+        Ok(self
+            .words
+            .iter()
+            .zip(&other.words)
+            .map(|(a, b)| (a ^ b).count_ones())
+            .sum())
+    }
+}
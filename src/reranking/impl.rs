@@ -1,23 +1,31 @@
 #[cfg(feature = "hf-hub")]
 use anyhow::Context;
 use anyhow::Result;
-use ort::{
-    session::{builder::GraphOptimizationLevel, Session},
-    value::Value,
-};
-use std::thread::available_parallelism;
+use std::path::PathBuf;
+#[cfg(feature = "async")]
+use std::sync::Arc;
 
+#[cfg(feature = "async")]
+use crate::asynchronous::run_in_chunks;
 #[cfg(feature = "hf-hub")]
 use crate::common::load_tokenizer_hf_hub;
 use crate::{
-    common::load_tokenizer, models::reranking::reranker_model_list, RerankerModel,
-    RerankerModelInfo,
+    common::{
+        encode_batches, load_tokenizer, restore_order, tokenize, truncation_report, BatchArrays,
+    },
+    models::reranking::reranker_model_list,
+    progress::{BatchTracker, EmbedControl},
+    session_pool::SessionPool,
+    RerankerModel, RerankerModelInfo, TokenizedInput, TruncationReport,
 };
 #[cfg(feature = "hf-hub")]
 use hf_hub::{api::sync::ApiBuilder, Cache};
-use ndarray::{s, Array};
-use rayon::{iter::ParallelIterator, slice::ParallelSlice};
-use tokenizers::Tokenizer;
+use ndarray::s;
+use rayon::{
+    iter::{IntoParallelIterator, ParallelIterator},
+    slice::ParallelSlice,
+};
+use tokenizers::{Encoding, Tokenizer};
 
 #[cfg(feature = "hf-hub")]
 use super::RerankInitOptions;
@@ -27,15 +35,23 @@ use super::{
 };
 
 impl TextRerank {
-    fn new(tokenizer: Tokenizer, session: Session) -> Self {
-        let need_token_type_ids = session
+    fn new(
+        tokenizer: Tokenizer,
+        sessions: SessionPool,
+        sort_by_length: bool,
+        max_batch_tokens: Option<usize>,
+    ) -> Self {
+        let need_token_type_ids = sessions
+            .session()
             .inputs
             .iter()
             .any(|input| input.name == "token_type_ids");
         Self {
             tokenizer,
-            session,
+            sessions,
             need_token_type_ids,
+            sort_by_length,
+            max_batch_tokens,
         }
     }
 
@@ -60,11 +76,18 @@ impl TextRerank {
             max_length,
             cache_dir,
             show_download_progress,
+            sort_by_length,
+            max_batch_tokens,
+            session_pool_size,
+            session_options,
         } = options;
 
-        let threads = available_parallelism()?.get();
+        let threads = session_options.threads()?;
+
+        let (sort_by_length, max_batch_tokens) =
+            session_options.batching(sort_by_length, max_batch_tokens);
 
-        let cache = Cache::new(cache_dir);
+        let cache = Cache::new(cache_dir.clone());
         let api = ApiBuilder::from_cache(cache)
             .with_progress(show_download_progress)
             .build()
@@ -84,14 +107,22 @@ impl TextRerank {
             ))?;
         }
 
-        let session = Session::builder()?
-            .with_execution_providers(execution_providers)?
-            .with_optimization_level(GraphOptimizationLevel::Level3)?
-            .with_intra_threads(threads)?
-            .commit_from_file(model_file_reference)?;
+        let sessions = SessionPool::new(session_pool_size, threads, |intra_threads| {
+            session_options.commit_from_file(
+                &execution_providers,
+                intra_threads,
+                &model_file_reference,
+                &cache_dir,
+            )
+        })?;
 
         let tokenizer = load_tokenizer_hf_hub(model_repo, max_length)?;
-        Ok(Self::new(tokenizer, session))
+        Ok(Self::new(
+            tokenizer,
+            sessions,
+            sort_by_length,
+            max_batch_tokens,
+        ))
     }
 
     /// Create a TextRerank instance from model files provided by the user.
@@ -104,22 +135,40 @@ impl TextRerank {
         let RerankInitOptionsUserDefined {
             execution_providers,
             max_length,
+            sort_by_length,
+            max_batch_tokens,
+            session_pool_size,
+            session_options,
         } = options;
 
-        let threads = available_parallelism()?.get();
+        let threads = session_options.threads()?;
 
-        let session = Session::builder()?
-            .with_execution_providers(execution_providers)?
-            .with_optimization_level(GraphOptimizationLevel::Level3)?
-            .with_intra_threads(threads)?;
+        let (sort_by_length, max_batch_tokens) =
+            session_options.batching(sort_by_length, max_batch_tokens);
 
-        let session = match &model.onnx_source {
-            OnnxSource::Memory(bytes) => session.commit_from_memory(bytes)?,
-            OnnxSource::File(path) => session.commit_from_file(path)?,
-        };
+        let sessions = SessionPool::new(session_pool_size, threads, |intra_threads| {
+            let session = session_options.builder(&execution_providers, intra_threads)?;
+
+            Ok(match &model.onnx_source {
+                OnnxSource::Memory(bytes) => session.commit_from_memory(bytes)?,
+                OnnxSource::File(path) => session.commit_from_file(path)?,
+            })
+        })?;
 
         let tokenizer = load_tokenizer(model.tokenizer_files, max_length)?;
-        Ok(Self::new(tokenizer, session))
+        Ok(Self::new(
+            tokenizer,
+            sessions,
+            sort_by_length,
+            max_batch_tokens,
+        ))
+    }
+
+    /// Stop profiling the inference of the model, enabled with
+    /// [`SessionOptions::with_profiling`](crate::SessionOptions::with_profiling), and return
+    /// the paths of the profile files, one per session
+    pub fn end_profiling(&self) -> Result<Vec<PathBuf>> {
+        self.sessions.end_profiling()
     }
 
     /// Rerank documents using the reranker model and returns the results sorted by score in descending order.
@@ -129,80 +178,74 @@ impl TextRerank {
         documents: Vec<S>,
         return_documents: bool,
         batch_size: Option<usize>,
+    ) -> Result<Vec<RerankResult>> {
+        self.rerank_with_control(
+            query,
+            documents,
+            return_documents,
+            batch_size,
+            &EmbedControl::default(),
+        )
+    }
+
+    /// Same as [`TextRerank::rerank`], but reports the progress after each batch and stops
+    /// before the next batch once cancelled, returning a [`Cancelled`](crate::Cancelled) error.
+    pub fn rerank_with_control<S: AsRef<str> + Send + Sync>(
+        &self,
+        query: S,
+        documents: Vec<S>,
+        return_documents: bool,
+        batch_size: Option<usize>,
+        control: &EmbedControl,
     ) -> Result<Vec<RerankResult>> {
         let batch_size = batch_size.unwrap_or(DEFAULT_BATCH_SIZE);
 
         let q = query.as_ref();
 
-        let scores: Vec<f32> = documents
-            .par_chunks(batch_size)
-            .map(|batch| {
-                let inputs = batch.iter().map(|d| (q, d.as_ref())).collect();
-
-                let encodings = self
-                    .tokenizer
-                    .encode_batch(inputs, true)
-                    .expect("Failed to encode batch");
-
-                let encoding_length = encodings[0].len();
-                let batch_size = batch.len();
-
-                let max_size = encoding_length * batch_size;
-
-                let mut ids_array = Vec::with_capacity(max_size);
-                let mut mask_array = Vec::with_capacity(max_size);
-                let mut type_ids_array = Vec::with_capacity(max_size);
-
-                encodings.iter().for_each(|encoding| {
-                    let ids = encoding.get_ids();
-                    let mask = encoding.get_attention_mask();
-                    let type_ids = encoding.get_type_ids();
-
-                    ids_array.extend(ids.iter().map(|x| *x as i64));
-                    mask_array.extend(mask.iter().map(|x| *x as i64));
-                    type_ids_array.extend(type_ids.iter().map(|x| *x as i64));
-                });
-
-                let inputs_ids_array =
-                    Array::from_shape_vec((batch_size, encoding_length), ids_array)?;
-
-                let attention_mask_array =
-                    Array::from_shape_vec((batch_size, encoding_length), mask_array)?;
-
-                let token_type_ids_array =
-                    Array::from_shape_vec((batch_size, encoding_length), type_ids_array)?;
-
-                let mut session_inputs = ort::inputs![
-                    "input_ids" => Value::from_array(inputs_ids_array)?,
-                    "attention_mask" => Value::from_array(attention_mask_array)?,
-                ]?;
-
-                if self.need_token_type_ids {
-                    session_inputs.push((
-                        "token_type_ids".into(),
-                        Value::from_array(token_type_ids_array)?.into(),
-                    ));
-                }
-
-                let outputs = self.session.run(session_inputs)?;
-
-                let outputs = outputs["logits"]
-                    .try_extract_tensor::<f32>()
-                    .expect("Failed to extract logits tensor");
-
-                let scores: Vec<f32> = outputs
-                    .slice(s![.., 0])
-                    .rows()
-                    .into_iter()
-                    .flat_map(|row| row.to_vec())
-                    .collect();
-
-                Ok(scores)
-            })
-            .collect::<Result<Vec<_>>>()?
-            .into_iter()
-            .flatten()
-            .collect();
+        let scores: Vec<f32> = if self.sort_by_length || self.max_batch_tokens.is_some() {
+            let inputs = documents.iter().map(|d| (q, d.as_ref())).collect();
+            let (encodings, order) = encode_batches(
+                &self.tokenizer,
+                inputs,
+                batch_size,
+                self.max_batch_tokens,
+                self.sort_by_length,
+            )?;
+
+            let tracker = BatchTracker::new(control, encodings.len(), documents.len());
+            let scores = encodings
+                .into_par_iter()
+                .map(|encodings| tracker.run(encodings.len(), || self.score_encodings(encodings)))
+                .collect::<Result<Vec<_>>>()?
+                .into_iter()
+                .flatten()
+                .collect();
+
+            restore_order(scores, &order)
+        } else {
+            let tracker = BatchTracker::new(
+                control,
+                documents.len().div_ceil(batch_size),
+                documents.len(),
+            );
+            documents
+                .par_chunks(batch_size)
+                .map(|batch| {
+                    tracker.run(batch.len(), || {
+                        let inputs = batch.iter().map(|d| (q, d.as_ref())).collect();
+
+                        let encodings = self
+                            .tokenizer
+                            .encode_batch(inputs, true)
+                            .expect("Failed to encode batch");
+                        self.score_encodings(encodings)
+                    })
+                })
+                .collect::<Result<Vec<_>>>()?
+                .into_iter()
+                .flatten()
+                .collect()
+        };
 
         // Return top_n_result of type Vec<RerankResult> ordered by score in descending order, don't use binary heap
         let mut top_n_result: Vec<RerankResult> = scores
@@ -219,4 +262,123 @@ impl TextRerank {
 
         Ok(top_n_result.to_vec())
     }
+
+    /// Async version of [`TextRerank::rerank`], available with the `async` feature.
+    ///
+    /// The documents are processed on the blocking thread pool, `batch_size` at a time, so that
+    /// the async executor is not blocked. Dropping the future stops any further batch from
+    /// being scheduled. See [`set_max_concurrent_inferences`](crate::set_max_concurrent_inferences)
+    /// to limit how many inference calls run at once.
+    #[cfg(feature = "async")]
+    pub async fn rerank_async<S: AsRef<str> + Send + Sync + 'static>(
+        self: Arc<Self>,
+        query: S,
+        documents: Vec<S>,
+        return_documents: bool,
+        batch_size: Option<usize>,
+    ) -> Result<Vec<RerankResult>> {
+        let chunk_size = batch_size.unwrap_or(DEFAULT_BATCH_SIZE);
+
+        let mut results = run_in_chunks(documents, chunk_size, move |offset, chunk| {
+            let results = self.rerank(
+                query.as_ref(),
+                chunk.iter().map(|d| d.as_ref()).collect(),
+                return_documents,
+                batch_size,
+            )?;
+
+            // Indices are relative to the chunk
+            Ok(results
+                .into_iter()
+                .map(|result| RerankResult {
+                    index: result.index + offset,
+                    ..result
+                })
+                .collect())
+        })
+        .await?;
+
+        results.sort_by(|a, b| a.score.total_cmp(&b.score).reverse());
+
+        Ok(results)
+    }
+
+    /// Same as [`TextRerank::rerank`], but also returns a [`TruncationReport`] for each
+    /// document, in the order of `documents`, telling whether the query and document pair
+    /// exceeded the `max_length` of the model and was cut.
+    pub fn rerank_with_report<S: AsRef<str> + Send + Sync>(
+        &self,
+        query: S,
+        documents: Vec<S>,
+        return_documents: bool,
+        batch_size: Option<usize>,
+    ) -> Result<(Vec<RerankResult>, Vec<TruncationReport>)> {
+        let q = query.as_ref();
+        let report = truncation_report(
+            &self.tokenizer,
+            documents.iter().map(|d| (q, d.as_ref())).collect(),
+        )?;
+
+        Ok((
+            self.rerank(query, documents, return_documents, batch_size)?,
+            report,
+        ))
+    }
+
+    /// Tokenize the query paired with each of the documents the way they are fed to the
+    /// model, without running it.
+    ///
+    /// The tokens are truncated to the `max_length` of the model but not padded. The offsets
+    /// of each token are relative to the query or the document, as told by its sequence id.
+    pub fn tokenize<S: AsRef<str> + Send + Sync>(
+        &self,
+        query: S,
+        documents: Vec<S>,
+    ) -> Result<Vec<TokenizedInput>> {
+        let q = query.as_ref();
+        tokenize(
+            &self.tokenizer,
+            documents.iter().map(|d| (q, d.as_ref())).collect(),
+        )
+    }
+
+    /// Count the tokens of the query paired with each of the documents, including special
+    /// tokens, without running the model.
+    ///
+    /// The counts are taken before truncation, so they may exceed the `max_length` of the model.
+    pub fn count_tokens<S: AsRef<str> + Send + Sync>(
+        &self,
+        query: S,
+        documents: Vec<S>,
+    ) -> Result<Vec<usize>> {
+        let q = query.as_ref();
+        Ok(truncation_report(
+            &self.tokenizer,
+            documents.iter().map(|d| (q, d.as_ref())).collect(),
+        )?
+        .iter()
+        .map(|report| report.token_count)
+        .collect())
+    }
+
+    /// Run the model on a batch of equally long encodings, returning one score per encoding.
+    fn score_encodings(&self, encodings: Vec<Encoding>) -> Result<Vec<f32>> {
+        let arrays = BatchArrays::from_encodings(&encodings)?;
+        let session_inputs = arrays.session_inputs(self.need_token_type_ids)?;
+
+        let outputs = self.sessions.run(|session| session.run(session_inputs))?;
+
+        let outputs = outputs["logits"]
+            .try_extract_tensor::<f32>()
+            .expect("Failed to extract logits tensor");
+
+        let scores: Vec<f32> = outputs
+            .slice(s![.., 0])
+            .rows()
+            .into_iter()
+            .flat_map(|row| row.to_vec())
+            .collect();
+
+        Ok(scores)
+    }
 }
@@ -1,17 +1,22 @@
 use std::path::{Path, PathBuf};
 
-use ort::{execution_providers::ExecutionProviderDispatch, session::Session};
+use ort::execution_providers::ExecutionProviderDispatch;
 use tokenizers::Tokenizer;
 
-use crate::{RerankerModel, TokenizerFiles, DEFAULT_CACHE_DIR};
+use crate::{
+    session_options::SessionOptions, session_pool::SessionPool, RerankerModel, TokenizerFiles,
+    DEFAULT_CACHE_DIR,
+};
 
 use super::{DEFAULT_MAX_LENGTH, DEFAULT_RE_RANKER_MODEL};
 
 #[derive(Debug)]
 pub struct TextRerank {
     pub tokenizer: Tokenizer,
-    pub(crate) session: Session,
+    pub(crate) sessions: SessionPool,
     pub(crate) need_token_type_ids: bool,
+    pub(crate) sort_by_length: bool,
+    pub(crate) max_batch_tokens: Option<usize>,
 }
 
 /// Options for initializing the reranking model
@@ -23,6 +28,10 @@ pub struct RerankInitOptions {
     pub max_length: usize,
     pub cache_dir: PathBuf,
     pub show_download_progress: bool,
+    pub sort_by_length: bool,
+    pub max_batch_tokens: Option<usize>,
+    pub session_pool_size: usize,
+    pub session_options: SessionOptions,
 }
 
 impl RerankInitOptions {
@@ -55,6 +64,46 @@ impl RerankInitOptions {
         self.show_download_progress = show_download_progress;
         self
     }
+
+    /// Set whether to sort the inputs by token length before batching them, defaults to `false`
+    ///
+    /// Batching inputs of similar length reduces the compute spent on padding. The outputs
+    /// are returned in the original order.
+    ///
+    /// Sorting tokenizes all the inputs up front, so their encodings are held in memory at
+    /// once rather than one batch at a time.
+    pub fn with_sort_by_length(mut self, sort_by_length: bool) -> Self {
+        self.sort_by_length = sort_by_length;
+        self
+    }
+
+    /// Limit each batch to the given number of padded tokens, that is its number of inputs
+    /// times the token length of its longest input
+    ///
+    /// Batches are formed greedily and are still limited to `batch_size` inputs. An input
+    /// longer than the budget is run in a batch of its own.
+    ///
+    /// Like sorting, this tokenizes all the inputs up front.
+    pub fn with_max_batch_tokens(mut self, max_batch_tokens: usize) -> Self {
+        self.max_batch_tokens = Some(max_batch_tokens);
+        self
+    }
+
+    /// Create the given number of ONNX sessions, each with an equal share of the threads,
+    /// and run each batch on whichever is free. Defaults to `1`
+    ///
+    /// This increases the throughput when several threads share the model, at the cost of
+    /// loading the model once per session.
+    pub fn with_session_pool_size(mut self, session_pool_size: usize) -> Self {
+        self.session_pool_size = session_pool_size;
+        self
+    }
+
+    /// Set the configuration of the ONNX Runtime sessions, such as the number of threads
+    pub fn with_session_options(mut self, session_options: SessionOptions) -> Self {
+        self.session_options = session_options;
+        self
+    }
 }
 
 impl Default for RerankInitOptions {
@@ -65,6 +114,10 @@ impl Default for RerankInitOptions {
             max_length: DEFAULT_MAX_LENGTH,
             cache_dir: Path::new(DEFAULT_CACHE_DIR).to_path_buf(),
             show_download_progress: true,
+            sort_by_length: false,
+            max_batch_tokens: None,
+            session_pool_size: 1,
+            session_options: Default::default(),
         }
     }
 }
@@ -77,6 +130,52 @@ impl Default for RerankInitOptions {
 pub struct RerankInitOptionsUserDefined {
     pub execution_providers: Vec<ExecutionProviderDispatch>,
     pub max_length: usize,
+    pub sort_by_length: bool,
+    pub max_batch_tokens: Option<usize>,
+    pub session_pool_size: usize,
+    pub session_options: SessionOptions,
+}
+
+impl RerankInitOptionsUserDefined {
+    /// Set whether to sort the inputs by token length before batching them, defaults to `false`
+    ///
+    /// Batching inputs of similar length reduces the compute spent on padding. The outputs
+    /// are returned in the original order.
+    ///
+    /// Sorting tokenizes all the inputs up front, so their encodings are held in memory at
+    /// once rather than one batch at a time.
+    pub fn with_sort_by_length(mut self, sort_by_length: bool) -> Self {
+        self.sort_by_length = sort_by_length;
+        self
+    }
+
+    /// Limit each batch to the given number of padded tokens, that is its number of inputs
+    /// times the token length of its longest input
+    ///
+    /// Batches are formed greedily and are still limited to `batch_size` inputs. An input
+    /// longer than the budget is run in a batch of its own.
+    ///
+    /// Like sorting, this tokenizes all the inputs up front.
+    pub fn with_max_batch_tokens(mut self, max_batch_tokens: usize) -> Self {
+        self.max_batch_tokens = Some(max_batch_tokens);
+        self
+    }
+
+    /// Create the given number of ONNX sessions, each with an equal share of the threads,
+    /// and run each batch on whichever is free. Defaults to `1`
+    ///
+    /// This increases the throughput when several threads share the model, at the cost of
+    /// loading the model once per session.
+    pub fn with_session_pool_size(mut self, session_pool_size: usize) -> Self {
+        self.session_pool_size = session_pool_size;
+        self
+    }
+
+    /// Set the configuration of the ONNX Runtime sessions, such as the number of threads
+    pub fn with_session_options(mut self, session_options: SessionOptions) -> Self {
+        self.session_options = session_options;
+        self
+    }
 }
 
 impl Default for RerankInitOptionsUserDefined {
@@ -84,6 +183,10 @@ impl Default for RerankInitOptionsUserDefined {
         Self {
             execution_providers: Default::default(),
             max_length: DEFAULT_MAX_LENGTH,
+            sort_by_length: false,
+            max_batch_tokens: None,
+            session_pool_size: 1,
+            session_options: Default::default(),
         }
     }
 }
@@ -96,6 +199,10 @@ impl From<RerankInitOptions> for RerankInitOptionsUserDefined {
         RerankInitOptionsUserDefined {
             execution_providers: options.execution_providers,
             max_length: options.max_length,
+            sort_by_length: options.sort_by_length,
+            max_batch_tokens: options.max_batch_tokens,
+            session_pool_size: options.session_pool_size,
+            session_options: options.session_options,
         }
     }
 }
@@ -0,0 +1,397 @@
+//! ONNX Runtime session configuration shared by all the init options, see [`SessionOptions`].
+//!
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+    thread::available_parallelism,
+    time::UNIX_EPOCH,
+};
+
+use anyhow::Result;
+use ort::{
+    execution_providers::{CPUExecutionProvider, ExecutionProviderDispatch},
+    session::{
+        builder::{GraphOptimizationLevel, SessionBuilder},
+        Session,
+    },
+};
+use sha2::{Digest, Sha256};
+
+/// Directory of the optimized models, under the cache directory
+const OPTIMIZED_MODEL_DIR: &str = "optimized";
+
+/// Version of the optimized model cache layout, part of the cache keys
+const OPTIMIZED_MODEL_FORMAT_VERSION: u32 = 2;
+
+/// Number of profiled sessions so far, to give each of them its own profile file
+static PROFILED_SESSIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// Number of optimized models saved so far, to give each save its own temporary directory
+static OPTIMIZED_MODEL_SAVES: AtomicUsize = AtomicUsize::new(0);
+
+/// Graph optimization level of the ONNX sessions, see [`GraphOptimizationLevel`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum OptimizationLevel {
+    Disable,
+    Level1,
+    Level2,
+    #[default]
+    Level3,
+}
+
+impl From<OptimizationLevel> for GraphOptimizationLevel {
+    fn from(level: OptimizationLevel) -> Self {
+        match level {
+            OptimizationLevel::Disable => GraphOptimizationLevel::Disable,
+            OptimizationLevel::Level1 => GraphOptimizationLevel::Level1,
+            OptimizationLevel::Level2 => GraphOptimizationLevel::Level2,
+            OptimizationLevel::Level3 => GraphOptimizationLevel::Level3,
+        }
+    }
+}
+
+/// Configuration of the ONNX Runtime sessions of a model
+///
+/// The defaults match the previous behaviour: all optimizations, as many intra-op threads as
+/// the available parallelism, and the ONNX Runtime defaults for everything else.
+#[derive(Debug, Clone, Default)]
+#[non_exhaustive]
+pub struct SessionOptions {
+    /// Threads used to parallelize the execution within nodes, split between the sessions of
+    /// the pool. Defaults to [`available_parallelism`], which ignores the CPU quota of some
+    /// containers.
+    pub intra_threads: Option<usize>,
+    /// Threads used to run independent nodes in parallel, with `parallel_execution` enabled
+    pub inter_threads: Option<usize>,
+    pub optimization_level: OptimizationLevel,
+    /// Run independent nodes in parallel instead of sequentially
+    pub parallel_execution: bool,
+    /// Enable or disable the CPU memory arena, `None` keeps the ONNX Runtime default
+    pub cpu_arena: Option<bool>,
+    /// Enable or disable the memory pattern optimization, `None` keeps the ONNX Runtime
+    /// default. Disabling it may help with inputs whose shape varies a lot.
+    pub memory_pattern: Option<bool>,
+    /// Additional session config entries, e.g. `("session.intra_op.allow_spinning", "0")`
+    pub config_entries: Vec<(String, String)>,
+    /// Save the optimized graph of downloaded models under `<cache_dir>/optimized`, and load
+    /// it instead of optimizing the model again on the next loads
+    ///
+    /// The saved graph is used as long as the model file, the ONNX Runtime build, the
+    /// optimization level, the execution providers and the config entries are unchanged. It
+    /// only holds the optimizations up to [`OptimizationLevel::Level2`], so that it can be
+    /// shared between machines, and the CPU specific ones of level 3 are applied when loading.
+    pub optimized_model_cache: bool,
+    /// Profile the inference of each session with ONNX Runtime, see
+    /// [`SessionOptions::with_profiling`]
+    pub profiling: Option<PathBuf>,
+    /// Make the outputs bit-identical between runs, see [`SessionOptions::with_deterministic`]
+    pub deterministic: bool,
+}
+
+impl SessionOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_intra_threads(mut self, intra_threads: usize) -> Self {
+        self.intra_threads = Some(intra_threads);
+        self
+    }
+
+    pub fn with_inter_threads(mut self, inter_threads: usize) -> Self {
+        self.inter_threads = Some(inter_threads);
+        self
+    }
+
+    pub fn with_optimization_level(mut self, optimization_level: OptimizationLevel) -> Self {
+        self.optimization_level = optimization_level;
+        self
+    }
+
+    pub fn with_parallel_execution(mut self, parallel_execution: bool) -> Self {
+        self.parallel_execution = parallel_execution;
+        self
+    }
+
+    pub fn with_cpu_arena(mut self, cpu_arena: bool) -> Self {
+        self.cpu_arena = Some(cpu_arena);
+        self
+    }
+
+    pub fn with_memory_pattern(mut self, memory_pattern: bool) -> Self {
+        self.memory_pattern = Some(memory_pattern);
+        self
+    }
+
+    pub fn with_config_entry(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.config_entries.push((key.into(), value.into()));
+        self
+    }
+
+    pub fn with_optimized_model_cache(mut self, optimized_model_cache: bool) -> Self {
+        self.optimized_model_cache = optimized_model_cache;
+        self
+    }
+
+    /// Profile the inference of each session, writing a Chrome trace JSON file named after
+    /// `prefix`, the number of the session and the time it was created
+    ///
+    /// The files are only complete once profiling is stopped with the `end_profiling` method
+    /// of the model, which returns their paths, or an error for models created without profiling.
+    pub fn with_profiling(mut self, prefix: impl Into<PathBuf>) -> Self {
+        self.profiling = Some(prefix.into());
+        self
+    }
+
+    /// Make the same inputs always produce bit-identical outputs on the same machine, with the
+    /// same ONNX Runtime build and execution providers
+    ///
+    /// Outputs may still differ slightly between CPU types, as ONNX Runtime picks its math
+    /// kernels by the instruction sets of the CPU, such as AVX2 or AVX-512.
+    ///
+    /// This runs each session on a single thread, executes the nodes sequentially, enables
+    /// the deterministic kernels of ONNX Runtime and skips the hardware specific graph
+    /// optimizations above [`OptimizationLevel::Level2`]. Batches are also formed in the order
+    /// of the inputs, ignoring `sort_by_length` and `max_batch_tokens`, so the outputs only
+    /// depend on the inputs and the batch size.
+    ///
+    /// Batches still run in parallel on the sessions of the pool, see `with_session_pool_size`.
+    pub fn with_deterministic(mut self, deterministic: bool) -> Self {
+        self.deterministic = deterministic;
+        self
+    }
+
+    /// Total number of intra-op threads, to split between the sessions of a pool
+    pub(crate) fn threads(&self) -> Result<usize> {
+        if self.deterministic {
+            return Ok(1);
+        }
+        match self.intra_threads {
+            Some(threads) => Ok(threads),
+            None => Ok(available_parallelism()?.get()),
+        }
+    }
+
+    /// The `sort_by_length` and `max_batch_tokens` options of the model, which are disabled in
+    /// deterministic mode
+    pub(crate) fn batching(
+        &self,
+        sort_by_length: bool,
+        max_batch_tokens: Option<usize>,
+    ) -> (bool, Option<usize>) {
+        if self.deterministic {
+            (false, None)
+        } else {
+            (sort_by_length, max_batch_tokens)
+        }
+    }
+
+    fn optimization_level(&self) -> OptimizationLevel {
+        if self.deterministic {
+            self.optimization_level.min(OptimizationLevel::Level2)
+        } else {
+            self.optimization_level
+        }
+    }
+
+    /// Session builder with these options, the given execution providers and number of
+    /// intra-op threads, to load other ONNX models the same way as the models of the crate
+    pub fn builder(
+        &self,
+        execution_providers: &[ExecutionProviderDispatch],
+        intra_threads: usize,
+    ) -> Result<SessionBuilder> {
+        let mut builder = SessionBuilder::new()?
+            .with_execution_providers(self.execution_providers(execution_providers))?
+            .with_optimization_level(self.optimization_level().into())?
+            .with_intra_threads(intra_threads)?
+            .with_parallel_execution(self.parallel_execution && !self.deterministic)?;
+
+        if self.deterministic {
+            builder = builder.with_deterministic_compute(true)?;
+        }
+        if let Some(inter_threads) = self.inter_threads {
+            builder = builder.with_inter_threads(inter_threads)?;
+        }
+        if let Some(memory_pattern) = self.memory_pattern {
+            builder = builder.with_memory_pattern(memory_pattern)?;
+        }
+        for (key, value) in &self.config_entries {
+            builder = builder.with_config_entry(key, value)?;
+        }
+        if let Some(prefix) = &self.profiling {
+            let mut prefix = prefix.clone().into_os_string();
+            prefix.push(format!(
+                "_{}",
+                PROFILED_SESSIONS.fetch_add(1, Ordering::Relaxed)
+            ));
+            builder = builder.with_profiling(prefix)?;
+        }
+
+        Ok(builder)
+    }
+
+    /// The execution providers, with the CPU memory arena setting applied
+    ///
+    /// The arena is a session option, which ort only exposes through the CPU provider. A CPU
+    /// provider of the caller is replaced in place to keep the order of the providers, and one
+    /// is only appended if there is none.
+    fn execution_providers(
+        &self,
+        execution_providers: &[ExecutionProviderDispatch],
+    ) -> Vec<ExecutionProviderDispatch> {
+        let mut execution_providers = execution_providers.to_vec();
+        let Some(cpu_arena) = self.cpu_arena else {
+            return execution_providers;
+        };
+
+        let cpu = CPUExecutionProvider::default();
+        let cpu = if cpu_arena {
+            cpu.with_arena_allocator()
+        } else {
+            cpu
+        }
+        .build();
+        // ort only tells the providers apart by name, which is part of their debug output
+        let mut has_cpu = false;
+        for provider in &mut execution_providers {
+            if format!("{provider:?}").starts_with("CPUExecutionProvider ") {
+                *provider = cpu.clone();
+                has_cpu = true;
+            }
+        }
+        if !has_cpu {
+            execution_providers.push(cpu);
+        }
+        execution_providers
+    }
+
+    /// Load the model at `model_file`, going through the optimized model cache under
+    /// `cache_dir` if it is enabled
+    #[cfg_attr(not(feature = "hf-hub"), allow(dead_code))]
+    pub(crate) fn commit_from_file(
+        &self,
+        execution_providers: &[ExecutionProviderDispatch],
+        intra_threads: usize,
+        model_file: &Path,
+        cache_dir: &Path,
+    ) -> Result<Session> {
+        if !self.optimized_model_cache {
+            return Ok(self
+                .builder(execution_providers, intra_threads)?
+                .commit_from_file(model_file)?);
+        }
+
+        let optimized_dir = self.optimized_model_dir(execution_providers, model_file, cache_dir)?;
+        let optimized_file = optimized_dir.join("model.onnx");
+        if !optimized_file.exists() {
+            // Write to a directory of our own, so that concurrent loads in this or another
+            // process never see a partial model, and clean it up if saving fails
+            let tmp_dir = optimized_dir.with_extension(format!(
+                "{}.{}.tmp",
+                std::process::id(),
+                OPTIMIZED_MODEL_SAVES.fetch_add(1, Ordering::Relaxed)
+            ));
+            if let Err(err) = self.save_optimized_model(execution_providers, model_file, &tmp_dir) {
+                let _ = fs::remove_dir_all(&tmp_dir);
+                return Err(err);
+            }
+
+            // Another load may have saved the same model in the meantime, otherwise the
+            // directory is left over from an interrupted save and is replaced
+            if fs::rename(&tmp_dir, &optimized_dir).is_err() && !optimized_file.exists() {
+                let _ = fs::remove_dir_all(&optimized_dir);
+                let _ = fs::rename(&tmp_dir, &optimized_dir);
+            }
+            let _ = fs::remove_dir_all(&tmp_dir);
+
+            // Still no optimized model, load the original one rather than failing
+            if !optimized_file.exists() {
+                return Ok(self
+                    .builder(execution_providers, intra_threads)?
+                    .commit_from_file(model_file)?);
+            }
+        }
+
+        // The saved graph only has the optimizations up to level 2, the hardware specific
+        // ones of level 3 are applied again when loading it
+        let options = Self {
+            optimization_level: if self.optimization_level() > OptimizationLevel::Level2 {
+                self.optimization_level
+            } else {
+                OptimizationLevel::Disable
+            },
+            ..self.clone()
+        };
+        Ok(options
+            .builder(execution_providers, intra_threads)?
+            .commit_from_file(&optimized_file)?)
+    }
+
+    /// Save the model at `model_file` optimized up to level 2 into `dir`, with large
+    /// initializers in a separate file to stay below the protobuf size limit
+    ///
+    /// Level 3 optimizations depend on the CPU, so saving them could break loading the graph on
+    /// another machine sharing the cache directory.
+    fn save_optimized_model(
+        &self,
+        execution_providers: &[ExecutionProviderDispatch],
+        model_file: &Path,
+        dir: &Path,
+    ) -> Result<()> {
+        fs::create_dir_all(dir)?;
+        let options = Self {
+            optimization_level: self.optimization_level().min(OptimizationLevel::Level2),
+            profiling: None,
+            ..self.clone()
+        };
+        options
+            .builder(execution_providers, 1)?
+            .with_config_entry(
+                "session.optimized_model_external_initializers_file_name",
+                "model.onnx_data",
+            )?
+            .with_optimized_model_path(dir.join("model.onnx"))?
+            .commit_from_file(model_file)?;
+        Ok(())
+    }
+
+    /// Directory of the optimized model, keyed by everything the optimized graph depends on
+    fn optimized_model_dir(
+        &self,
+        execution_providers: &[ExecutionProviderDispatch],
+        model_file: &Path,
+        cache_dir: &Path,
+    ) -> Result<PathBuf> {
+        // Downloaded models are links to blobs named after their hash, resolve them so that
+        // a new revision of the model gets a new key
+        let model_file = fs::canonicalize(model_file)?;
+        let metadata = fs::metadata(&model_file)?;
+        let modified = metadata.modified()?.duration_since(UNIX_EPOCH)?;
+
+        let mut hasher = Sha256::new();
+        hasher.update(OPTIMIZED_MODEL_FORMAT_VERSION.to_le_bytes());
+        hasher.update(ort::info());
+        hasher.update(model_file.to_string_lossy().as_bytes());
+        hasher.update(metadata.len().to_le_bytes());
+        hasher.update(modified.as_nanos().to_le_bytes());
+        hasher.update(
+            format!(
+                "{:?}|{:?}|{:?}|{}",
+                self.optimization_level().min(OptimizationLevel::Level2),
+                execution_providers,
+                self.config_entries,
+                self.deterministic
+            )
+            .as_bytes(),
+        );
+        let key = hasher
+            .finalize()
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect::<String>();
+
+        Ok(cache_dir.join(OPTIMIZED_MODEL_DIR).join(key))
+    }
+}
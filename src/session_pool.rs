@@ -0,0 +1,109 @@
+//! Pool of ONNX sessions of the same model, to run several batches at once.
+//!
+use std::{
+    path::PathBuf,
+    sync::{Condvar, Mutex},
+};
+
+use anyhow::Result;
+use ort::session::Session;
+
+/// One or more sessions of the same model, splitting the threads between them
+///
+/// Each batch is run on whichever session is free, so that concurrent calls do not compete
+/// for the intra-op thread pool of a single session.
+#[derive(Debug)]
+pub(crate) struct SessionPool {
+    sessions: Vec<Session>,
+    /// Indices of the sessions not currently running a batch
+    free: Mutex<Vec<usize>>,
+    available: Condvar,
+}
+
+impl SessionPool {
+    /// Build `size` sessions with `build`, which is given the number of intra-op threads of
+    /// each session, an equal share of `threads`
+    pub(crate) fn new(
+        size: usize,
+        threads: usize,
+        mut build: impl FnMut(usize) -> Result<Session>,
+    ) -> Result<Self> {
+        if size == 0 {
+            return Err(anyhow::Error::msg(
+                "The session pool must hold at least one session.",
+            ));
+        }
+
+        let intra_threads = (threads / size).max(1);
+        let sessions = (0..size)
+            .map(|_| build(intra_threads))
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self {
+            sessions,
+            free: Mutex::new((0..size).rev().collect()),
+            available: Condvar::new(),
+        })
+    }
+
+    /// The first session, to read the metadata of the model such as its inputs
+    pub(crate) fn session(&self) -> &Session {
+        &self.sessions[0]
+    }
+
+    /// Stop profiling the sessions, returning the paths of their profile files
+    pub(crate) fn end_profiling(&self) -> Result<Vec<PathBuf>> {
+        self.sessions.iter().map(end_profiling).collect()
+    }
+
+    /// Call `run` with a session that is not running another batch, waiting for one to be
+    /// released if needed.
+    ///
+    /// A single session is shared by concurrent calls instead.
+    pub(crate) fn run<'s, T>(&'s self, run: impl FnOnce(&'s Session) -> T) -> T {
+        if self.sessions.len() == 1 {
+            return run(&self.sessions[0]);
+        }
+
+        let mut free = self.free.lock().unwrap();
+        let index = loop {
+            match free.pop() {
+                Some(index) => break index,
+                None => free = self.available.wait(free).unwrap(),
+            }
+        };
+        drop(free);
+
+        // Release the session even if `run` panics
+        let _lease = Lease { pool: self, index };
+        run(&self.sessions[index])
+    }
+}
+
+/// Stop profiling a session, returning the path of its profile file
+fn end_profiling(session: &Session) -> Result<PathBuf> {
+    // ONNX Runtime returns an empty path for sessions created without profiling
+    let path = session.end_profiling()?;
+    if path.is_empty() {
+        return Err(anyhow::Error::msg(
+            "Profiling is not enabled, see SessionOptions::with_profiling.",
+        ));
+    }
+    Ok(PathBuf::from(path))
+}
+
+/// Returns a session to its pool when dropped
+struct Lease<'p> {
+    pool: &'p SessionPool,
+    index: usize,
+}
+
+impl Drop for Lease<'_> {
+    fn drop(&mut self) {
+        // Not unwrapping, as this may run while panicking
+        if let Ok(mut free) = self.pool.free.lock() {
+            free.push(self.index);
+            self.pool.available.notify_one();
+        }
+    }
+}
@@ -0,0 +1,345 @@
+//! Similarity and distance of embeddings, between pairs, whole matrices or a query and a
+//! corpus, see [`Metric`].
+//!
+//! The kernels accumulate in independent lanes so that the compiler vectorizes them, and the
+//! functions comparing against many embeddings run in parallel with rayon.
+use anyhow::Result;
+use rayon::prelude::*;
+
+use crate::Embedding;
+
+/// Number of independent accumulators of the kernels, enough to fill the SIMD registers
+const LANES: usize = 8;
+
+/// Dot product of two embeddings of the same dimension
+pub fn dot(a: &[f32], b: &[f32]) -> Result<f32> {
+    check_same_dim(a, b)?;
+    Ok(dot_unchecked(a, b))
+}
+
+/// Squared Euclidean distance of two embeddings of the same dimension
+pub fn l2_squared(a: &[f32], b: &[f32]) -> Result<f32> {
+    check_same_dim(a, b)?;
+    Ok(l2_squared_unchecked(a, b))
+}
+
+/// Euclidean distance of two embeddings of the same dimension
+pub fn l2(a: &[f32], b: &[f32]) -> Result<f32> {
+    Ok(l2_squared(a, b)?.sqrt())
+}
+
+/// Euclidean norm of an embedding
+pub fn norm(a: &[f32]) -> f32 {
+    dot_unchecked(a, a).sqrt()
+}
+
+/// Cosine similarity of two embeddings of the same dimension, `0.0` if either is zero
+pub fn cosine(a: &[f32], b: &[f32]) -> Result<f32> {
+    check_same_dim(a, b)?;
+    Ok(cosine_with_norms(a, norm(a), b, norm(b)))
+}
+
+fn check_same_dim(a: &[f32], b: &[f32]) -> Result<()> {
+    if a.len() != b.len() {
+        return Err(anyhow::Error::msg(format!(
+            "Cannot compare embeddings of dimensions {} and {}.",
+            a.len(),
+            b.len()
+        )));
+    }
+    Ok(())
+}
+
+/// Dot product of two embeddings whose dimensions were already checked to be the same
+pub(crate) fn dot_unchecked(a: &[f32], b: &[f32]) -> f32 {
+    let len = a.len().min(b.len());
+    let (a, b) = (&a[..len], &b[..len]);
+    let mut lanes = [0.0; LANES];
+    for (a, b) in a.chunks_exact(LANES).zip(b.chunks_exact(LANES)) {
+        for i in 0..LANES {
+            lanes[i] += a[i] * b[i];
+        }
+    }
+    let tail = len - len % LANES;
+    let tail: f32 = a[tail..].iter().zip(&b[tail..]).map(|(a, b)| a * b).sum();
+    lanes.iter().sum::<f32>() + tail
+}
+
+/// Squared Euclidean distance of two embeddings whose dimensions were already checked to be
+/// the same
+pub(crate) fn l2_squared_unchecked(a: &[f32], b: &[f32]) -> f32 {
+    let len = a.len().min(b.len());
+    let (a, b) = (&a[..len], &b[..len]);
+    let mut lanes = [0.0; LANES];
+    for (a, b) in a.chunks_exact(LANES).zip(b.chunks_exact(LANES)) {
+        for i in 0..LANES {
+            let diff = a[i] - b[i];
+            lanes[i] += diff * diff;
+        }
+    }
+    let tail = len - len % LANES;
+    let tail: f32 = a[tail..]
+        .iter()
+        .zip(&b[tail..])
+        .map(|(a, b)| (a - b) * (a - b))
+        .sum();
+    lanes.iter().sum::<f32>() + tail
+}
+
+/// Cosine similarity of two embeddings whose dimensions were already checked to be the same,
+/// given their norms
+pub(crate) fn cosine_with_norms(a: &[f32], norm_a: f32, b: &[f32], norm_b: f32) -> f32 {
+    let norms = norm_a * norm_b;
+    if norms > 0.0 {
+        dot_unchecked(a, b) / norms
+    } else {
+        0.0
+    }
+}
+
+/// How to compare embeddings
+///
+/// [`Metric::Cosine`] and [`Metric::Dot`] are similarities, higher meaning closer, while
+/// [`Metric::L2`] is a distance, lower meaning closer. The dot product equals the cosine
+/// similarity for normalized embeddings, which is the default output of the text models.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Metric {
+    #[default]
+    Cosine,
+    Dot,
+    L2,
+}
+
+impl Metric {
+    /// Score of `b` against `a` with this metric
+    pub fn score(self, a: &[f32], b: &[f32]) -> Result<f32> {
+        check_same_dim(a, b)?;
+        Ok(self.score_unchecked(a, b))
+    }
+
+    fn score_unchecked(self, a: &[f32], b: &[f32]) -> f32 {
+        match self {
+            Metric::Cosine => cosine_with_norms(a, norm(a), b, norm(b)),
+            Metric::Dot => dot_unchecked(a, b),
+            Metric::L2 => l2_squared_unchecked(a, b).sqrt(),
+        }
+    }
+
+    /// Whether lower scores are closer, as for distances
+    pub fn is_distance(self) -> bool {
+        matches!(self, Metric::L2)
+    }
+
+    /// Whether `score` is at least as close as `threshold`
+    fn within(self, score: f32, threshold: f32) -> bool {
+        if self.is_distance() {
+            score <= threshold
+        } else {
+            score >= threshold
+        }
+    }
+
+    /// Sort hits closest first, keeping the lowest indices first on ties
+    fn sort(self, hits: &mut [Hit]) {
+        hits.sort_unstable_by(|a, b| self.compare(a, b));
+    }
+
+    fn compare(self, a: &Hit, b: &Hit) -> std::cmp::Ordering {
+        let by_score = if self.is_distance() {
+            a.score.total_cmp(&b.score)
+        } else {
+            b.score.total_cmp(&a.score)
+        };
+        by_score.then(a.index.cmp(&b.index))
+    }
+
+    /// Scores of `query` against each row of `corpus`, given the norms of the rows for the
+    /// cosine similarity if they are already known
+    fn scores(self, query: &[f32], corpus: &Matrix, corpus_norms: Option<&[f32]>) -> Vec<f32> {
+        let query_norm = norm(query);
+        corpus
+            .data
+            .par_chunks(corpus.cols.max(1))
+            .enumerate()
+            .map(|(i, row)| match self {
+                Metric::Cosine => {
+                    let row_norm = corpus_norms.map_or_else(|| norm(row), |norms| norms[i]);
+                    cosine_with_norms(query, query_norm, row, row_norm)
+                }
+                _ => self.score_unchecked(query, row),
+            })
+            .collect()
+    }
+}
+
+/// Row-major matrix of `f32`, such as embeddings of the same dimension stored contiguously
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Matrix {
+    data: Vec<f32>,
+    cols: usize,
+}
+
+impl Matrix {
+    /// Matrix of `data.len() / cols` rows of `cols` values each
+    pub fn new(data: Vec<f32>, cols: usize) -> Result<Self> {
+        if cols == 0 && !data.is_empty() || cols > 0 && data.len() % cols != 0 {
+            return Err(anyhow::Error::msg(format!(
+                "Cannot split {} values into rows of {cols}.",
+                data.len()
+            )));
+        }
+        Ok(Self { data, cols })
+    }
+
+    /// Matrix with one embedding per row, which must all have the same dimension
+    pub fn from_embeddings(embeddings: &[Embedding]) -> Result<Self> {
+        let cols = embeddings.first().map_or(0, Vec::len);
+        let mut data = Vec::with_capacity(embeddings.len() * cols);
+        for embedding in embeddings {
+            if embedding.len() != cols {
+                return Err(anyhow::Error::msg(format!(
+                    "Cannot build a matrix from embeddings of dimensions {cols} and {}.",
+                    embedding.len()
+                )));
+            }
+            data.extend_from_slice(embedding);
+        }
+        Ok(Self { data, cols })
+    }
+
+    pub fn rows(&self) -> usize {
+        self.data.len().checked_div(self.cols).unwrap_or(0)
+    }
+
+    pub fn cols(&self) -> usize {
+        self.cols
+    }
+
+    pub fn row(&self, row: usize) -> &[f32] {
+        &self.data[row * self.cols..(row + 1) * self.cols]
+    }
+
+    pub fn iter_rows(&self) -> impl Iterator<Item = &[f32]> {
+        self.data.chunks_exact(self.cols.max(1))
+    }
+
+    pub fn as_slice(&self) -> &[f32] {
+        &self.data
+    }
+
+    pub fn into_vec(self) -> Vec<f32> {
+        self.data
+    }
+
+    /// The rows as separate vectors
+    pub fn to_embeddings(&self) -> Vec<Embedding> {
+        self.iter_rows().map(<[f32]>::to_vec).collect()
+    }
+
+    /// Euclidean norm of each row
+    fn norms(&self) -> Vec<f32> {
+        self.data.par_chunks(self.cols.max(1)).map(norm).collect()
+    }
+
+    /// Check that embeddings of dimension `dim` can be compared with the rows
+    fn check_dim(&self, dim: usize) -> Result<()> {
+        if self.rows() > 0 && self.cols != dim {
+            return Err(anyhow::Error::msg(format!(
+                "Cannot compare embeddings of dimension {dim} with a corpus of dimension {}.",
+                self.cols
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// A row of the corpus and its score against the query
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Hit {
+    pub index: usize,
+    pub score: f32,
+}
+
+/// Scores of each query against each row of the corpus, as a matrix with one row per query
+/// and one column per row of the corpus
+pub fn matrix(metric: Metric, queries: &Matrix, corpus: &Matrix) -> Result<Matrix> {
+    if queries.rows() > 0 {
+        corpus.check_dim(queries.cols)?;
+    }
+    let corpus_norms = (metric == Metric::Cosine).then(|| corpus.norms());
+    let data = queries
+        .data
+        .par_chunks(queries.cols.max(1))
+        .flat_map_iter(|query| metric.scores(query, corpus, corpus_norms.as_deref()))
+        .collect();
+    Ok(Matrix {
+        data,
+        cols: corpus.rows(),
+    })
+}
+
+/// The `k` rows of the corpus closest to the query, closest first
+pub fn top_k(metric: Metric, query: &[f32], corpus: &Matrix, k: usize) -> Result<Vec<Hit>> {
+    corpus.check_dim(query.len())?;
+    let scores = metric.scores(query, corpus, None);
+    Ok(select_top_k(metric, scores, k))
+}
+
+/// The `k` rows of the corpus closest to each query, closest first
+pub fn top_k_batch(
+    metric: Metric,
+    queries: &Matrix,
+    corpus: &Matrix,
+    k: usize,
+) -> Result<Vec<Vec<Hit>>> {
+    if queries.rows() > 0 {
+        corpus.check_dim(queries.cols)?;
+    }
+    let corpus_norms = (metric == Metric::Cosine).then(|| corpus.norms());
+    Ok(queries
+        .iter_rows()
+        .map(|query| {
+            let scores = metric.scores(query, corpus, corpus_norms.as_deref());
+            select_top_k(metric, scores, k)
+        })
+        .collect())
+}
+
+/// The rows of the corpus at least as close to the query as `threshold`, closest first
+///
+/// That is, with a score of at least `threshold` for similarities, and at most `threshold`
+/// for [`Metric::L2`].
+pub fn within_threshold(
+    metric: Metric,
+    query: &[f32],
+    corpus: &Matrix,
+    threshold: f32,
+) -> Result<Vec<Hit>> {
+    corpus.check_dim(query.len())?;
+    let mut hits: Vec<Hit> = metric
+        .scores(query, corpus, None)
+        .into_iter()
+        .enumerate()
+        .map(|(index, score)| Hit { index, score })
+        .filter(|hit| metric.within(hit.score, threshold))
+        .collect();
+    metric.sort(&mut hits);
+    Ok(hits)
+}
+
+fn select_top_k(metric: Metric, scores: Vec<f32>, k: usize) -> Vec<Hit> {
+    if k == 0 {
+        return Vec::new();
+    }
+    let mut hits: Vec<Hit> = scores
+        .into_iter()
+        .enumerate()
+        .map(|(index, score)| Hit { index, score })
+        .collect();
+    if k < hits.len() {
+        hits.select_nth_unstable_by(k - 1, |a, b| metric.compare(a, b));
+        hits.truncate(k);
+    }
+    metric.sort(&mut hits);
+    hits
+}
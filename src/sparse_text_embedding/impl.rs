@@ -1,8 +1,16 @@
+#[cfg(feature = "async")]
+use crate::asynchronous::{forward, run_in_chunks};
 #[cfg(feature = "hf-hub")]
 use crate::common::load_tokenizer_hf_hub;
 use crate::{
+    common::{
+        encode_batches, lazy_batches, load_tokenizer, restore_order, truncation_report, BatchArrays,
+    },
+    embedding_cache::{files_digest, EmbeddingCache},
     models::sparse::{models_list, SparseModel},
-    ModelInfo, SparseEmbedding,
+    progress::{BatchTracker, EmbedControl},
+    session_pool::SessionPool,
+    ModelInfo, SparseEmbedding, TruncationReport,
 };
 #[cfg(feature = "hf-hub")]
 use anyhow::Context;
@@ -12,31 +20,33 @@ use hf_hub::{
     api::sync::{ApiBuilder, ApiRepo},
     Cache,
 };
-use ndarray::{Array, ArrayViewD, Axis, CowArray, Dim};
-use ort::{session::Session, value::Value};
+use ndarray::{Array2, ArrayViewD, Axis};
 #[cfg_attr(not(feature = "hf-hub"), allow(unused_imports))]
-use rayon::{iter::ParallelIterator, slice::ParallelSlice};
-#[cfg(feature = "hf-hub")]
+use rayon::{
+    iter::{IntoParallelIterator, ParallelIterator},
+    slice::ParallelSlice,
+};
 use std::path::PathBuf;
-use tokenizers::Tokenizer;
-
-#[cfg_attr(not(feature = "hf-hub"), allow(unused_imports))]
-use std::thread::available_parallelism;
+#[cfg(feature = "async")]
+use std::sync::Arc;
+use tokenizers::{Encoding, Tokenizer};
+#[cfg(feature = "async")]
+use tokio::sync::mpsc::{channel, Receiver};
 
 #[cfg(feature = "hf-hub")]
 use super::SparseInitOptions;
-use super::{SparseTextEmbedding, DEFAULT_BATCH_SIZE};
+use super::{
+    SparseInitOptionsUserDefined, SparseTextEmbedding, UserDefinedSparseModel, DEFAULT_BATCH_SIZE,
+};
 
 impl SparseTextEmbedding {
     /// Try to generate a new SparseTextEmbedding Instance
     ///
-    /// Uses the highest level of Graph optimization
-    ///
-    /// Uses the total number of CPUs available as the number of intra-threads
+    /// Uses the highest level of Graph optimization and the total number of CPUs available as
+    /// the number of intra-threads, unless configured otherwise with `session_options`
     #[cfg(feature = "hf-hub")]
     pub fn try_new(options: SparseInitOptions) -> Result<Self> {
         use super::SparseInitOptions;
-        use ort::{session::builder::GraphOptimizationLevel, session::Session};
 
         let SparseInitOptions {
             model_name,
@@ -44,9 +54,17 @@ impl SparseTextEmbedding {
             max_length,
             cache_dir,
             show_download_progress,
+            sort_by_length,
+            max_batch_tokens,
+            embedding_cache,
+            session_pool_size,
+            session_options,
         } = options;
 
-        let threads = available_parallelism()?.get();
+        let threads = session_options.threads()?;
+
+        let (sort_by_length, max_batch_tokens) =
+            session_options.batching(sort_by_length, max_batch_tokens);
 
         let model_repo = SparseTextEmbedding::retrieve_model(
             model_name.clone(),
@@ -59,28 +77,129 @@ impl SparseTextEmbedding {
             .get(&model_file_name)
             .context(format!("Failed to retrieve {} ", model_file_name))?;
 
-        let session = Session::builder()?
-            .with_execution_providers(execution_providers)?
-            .with_optimization_level(GraphOptimizationLevel::Level3)?
-            .with_intra_threads(threads)?
-            .commit_from_file(model_file_reference)?;
+        let sessions = SessionPool::new(session_pool_size, threads, |intra_threads| {
+            session_options.commit_from_file(
+                &execution_providers,
+                intra_threads,
+                &model_file_reference,
+                &cache_dir,
+            )
+        })?;
+
+        // Everything affecting the output besides the input text is part of the cache key
+        let cache = embedding_cache
+            .map(|options| {
+                let model_info = SparseTextEmbedding::get_model_info(&model_name);
+                let namespace = format!(
+                    "{}|file={}|max_length={max_length}",
+                    model_info.model_code, model_info.model_file
+                );
+                EmbeddingCache::new(&options, namespace, &cache_dir, None)
+            })
+            .transpose()?;
 
         let tokenizer = load_tokenizer_hf_hub(model_repo, max_length)?;
-        Ok(Self::new(tokenizer, session, model_name))
+        Ok(Self {
+            cache,
+            ..Self::new(
+                tokenizer,
+                sessions,
+                model_name,
+                sort_by_length,
+                max_batch_tokens,
+            )
+        })
+    }
+
+    /// Create a SparseTextEmbedding instance from model files provided by the user.
+    ///
+    /// This can be used for 'bring your own' SPLADE models, whose outputs are post-processed
+    /// like those of [`SparseModel::SPLADEPPV1`]
+    pub fn try_new_from_user_defined(
+        model: UserDefinedSparseModel,
+        options: SparseInitOptionsUserDefined,
+    ) -> Result<Self> {
+        let SparseInitOptionsUserDefined {
+            execution_providers,
+            max_length,
+            cache_dir,
+            sort_by_length,
+            max_batch_tokens,
+            embedding_cache,
+            session_pool_size,
+            session_options,
+        } = options;
+
+        let threads = session_options.threads()?;
+
+        let (sort_by_length, max_batch_tokens) =
+            session_options.batching(sort_by_length, max_batch_tokens);
+
+        let sessions = SessionPool::new(session_pool_size, threads, |intra_threads| {
+            Ok(session_options
+                .builder(&execution_providers, intra_threads)?
+                .commit_from_memory(&model.onnx_file)?)
+        })?;
+
+        // The files of the model stand in for its name in the cache key
+        let cache = embedding_cache
+            .map(|options| {
+                let files = &model.tokenizer_files;
+                let namespace = format!(
+                    "user-defined={}|max_length={max_length}",
+                    files_digest(&[
+                        &model.onnx_file,
+                        &files.tokenizer_file,
+                        &files.config_file,
+                        &files.special_tokens_map_file,
+                        &files.tokenizer_config_file,
+                    ])
+                );
+                EmbeddingCache::new(&options, namespace, &cache_dir, None)
+            })
+            .transpose()?;
+
+        let tokenizer = load_tokenizer(model.tokenizer_files, max_length)?;
+        Ok(Self {
+            cache,
+            ..Self::new(
+                tokenizer,
+                sessions,
+                SparseModel::SPLADEPPV1,
+                sort_by_length,
+                max_batch_tokens,
+            )
+        })
+    }
+
+    /// Stop profiling the inference of the model, enabled with
+    /// [`SessionOptions::with_profiling`](crate::SessionOptions::with_profiling), and return
+    /// the paths of the profile files, one per session
+    pub fn end_profiling(&self) -> Result<Vec<PathBuf>> {
+        self.sessions.end_profiling()
     }
 
     /// Private method to return an instance
-    #[cfg_attr(not(feature = "hf-hub"), allow(dead_code))]
-    fn new(tokenizer: Tokenizer, session: Session, model: SparseModel) -> Self {
-        let need_token_type_ids = session
+    fn new(
+        tokenizer: Tokenizer,
+        sessions: SessionPool,
+        model: SparseModel,
+        sort_by_length: bool,
+        max_batch_tokens: Option<usize>,
+    ) -> Self {
+        let need_token_type_ids = sessions
+            .session()
             .inputs
             .iter()
             .any(|input| input.name == "token_type_ids");
         Self {
             tokenizer,
-            session,
+            sessions,
             need_token_type_ids,
             model,
+            sort_by_length,
+            max_batch_tokens,
+            cache: None,
         }
     }
     /// Return the SparseTextEmbedding model's directory from cache or remote retrieval
@@ -113,86 +232,80 @@ impl SparseTextEmbedding {
     }
 
     /// Method to generate sentence embeddings for a Vec of texts
+    ///
+    /// With an embedding cache configured through
+    /// [`SparseInitOptions::with_embedding_cache`](crate::SparseInitOptions::with_embedding_cache),
+    /// only the texts that are not cached are run through the model.
     // Generic type to accept String, &str, OsString, &OsStr
     pub fn embed<S: AsRef<str> + Send + Sync>(
         &self,
         texts: Vec<S>,
         batch_size: Option<usize>,
+    ) -> Result<Vec<SparseEmbedding>> {
+        self.embed_with_control(texts, batch_size, &EmbedControl::default())
+    }
+
+    /// Same as [`SparseTextEmbedding::embed`], but reports the progress after each batch and
+    /// stops before the next batch once cancelled, returning a [`Cancelled`](crate::Cancelled)
+    /// error.
+    ///
+    /// With an embedding cache, only the texts that are not cached are counted.
+    pub fn embed_with_control<S: AsRef<str> + Send + Sync>(
+        &self,
+        texts: Vec<S>,
+        batch_size: Option<usize>,
+        control: &EmbedControl,
+    ) -> Result<Vec<SparseEmbedding>> {
+        match &self.cache {
+            Some(cache) => cache.get_or_compute(&texts, |misses| {
+                self.embed_uncached(misses, batch_size, control)
+            }),
+            None => self.embed_uncached(texts, batch_size, control),
+        }
+    }
+
+    /// Run [`SparseTextEmbedding::embed`] without looking up the embedding cache.
+    fn embed_uncached<S: AsRef<str> + Send + Sync>(
+        &self,
+        texts: Vec<S>,
+        batch_size: Option<usize>,
+        control: &EmbedControl,
     ) -> Result<Vec<SparseEmbedding>> {
         // Determine the batch size, default if not specified
         let batch_size = batch_size.unwrap_or(DEFAULT_BATCH_SIZE);
 
+        if self.sort_by_length || self.max_batch_tokens.is_some() {
+            let inputs = texts.iter().map(|text| text.as_ref()).collect();
+            let (encodings, order) = encode_batches(
+                &self.tokenizer,
+                inputs,
+                batch_size,
+                self.max_batch_tokens,
+                self.sort_by_length,
+            )?;
+
+            let tracker = BatchTracker::new(control, encodings.len(), texts.len());
+            let output = encodings
+                .into_par_iter()
+                .map(|encodings| tracker.run(encodings.len(), || self.embed_encodings(encodings)))
+                .collect::<Result<Vec<_>>>()?
+                .into_iter()
+                .flatten()
+                .collect();
+
+            return Ok(restore_order(output, &order));
+        }
+
+        let tracker = BatchTracker::new(control, texts.len().div_ceil(batch_size), texts.len());
         let output = texts
             .par_chunks(batch_size)
             .map(|batch| {
-                // Encode the texts in the batch
-                let inputs = batch.iter().map(|text| text.as_ref()).collect();
-                let encodings = self.tokenizer.encode_batch(inputs, true).unwrap();
-
-                // Extract the encoding length and batch size
-                let encoding_length = encodings[0].len();
-                let batch_size = batch.len();
-
-                let max_size = encoding_length * batch_size;
-
-                // Preallocate arrays with the maximum size
-                let mut ids_array = Vec::with_capacity(max_size);
-                let mut mask_array = Vec::with_capacity(max_size);
-                let mut type_ids_array = Vec::with_capacity(max_size);
-
-                // Not using par_iter because the closure needs to be FnMut
-                encodings.iter().for_each(|encoding| {
-                    let ids = encoding.get_ids();
-                    let mask = encoding.get_attention_mask();
-                    let type_ids = encoding.get_type_ids();
-
-                    // Extend the preallocated arrays with the current encoding
-                    // Requires the closure to be FnMut
-                    ids_array.extend(ids.iter().map(|x| *x as i64));
-                    mask_array.extend(mask.iter().map(|x| *x as i64));
-                    type_ids_array.extend(type_ids.iter().map(|x| *x as i64));
-                });
-
-                // Create CowArrays from vectors
-                let inputs_ids_array =
-                    Array::from_shape_vec((batch_size, encoding_length), ids_array)?;
-                let owned_attention_mask =
-                    Array::from_shape_vec((batch_size, encoding_length), mask_array)?;
-                let attention_mask_array = CowArray::from(&owned_attention_mask);
-
-                let token_type_ids_array =
-                    Array::from_shape_vec((batch_size, encoding_length), type_ids_array)?;
-
-                let mut session_inputs = ort::inputs![
-                    "input_ids" => Value::from_array(inputs_ids_array)?,
-                    "attention_mask" => Value::from_array(&attention_mask_array)?,
-                ]?;
-
-                if self.need_token_type_ids {
-                    session_inputs.push((
-                        "token_type_ids".into(),
-                        Value::from_array(token_type_ids_array)?.into(),
-                    ));
-                }
-
-                let outputs = self.session.run(session_inputs)?;
-
-                // Try to get the only output key
-                // If multiple, then default to `last_hidden_state`
-                let last_hidden_state_key = match outputs.len() {
-                    1 => outputs.keys().next().unwrap(),
-                    _ => "last_hidden_state",
-                };
-
-                let output_data = outputs[last_hidden_state_key].try_extract_tensor::<f32>()?;
-
-                let embeddings = SparseTextEmbedding::post_process(
-                    &self.model,
-                    &output_data,
-                    &attention_mask_array,
-                );
-
-                Ok(embeddings)
+                tracker.run(batch.len(), || {
+                    // Encode the texts in the batch
+                    let inputs = batch.iter().map(|text| text.as_ref()).collect();
+                    let encodings = self.tokenizer.encode_batch(inputs, true).unwrap();
+                    self.embed_encodings(encodings)
+                })
             })
             .collect::<Result<Vec<_>>>()?
             .into_iter()
@@ -202,10 +315,32 @@ impl SparseTextEmbedding {
         Ok(output)
     }
 
+    /// Run the model on a batch of equally long encodings.
+    fn embed_encodings(&self, encodings: Vec<Encoding>) -> Result<Vec<SparseEmbedding>> {
+        let arrays = BatchArrays::from_encodings(&encodings)?;
+        let session_inputs = arrays.session_inputs(self.need_token_type_ids)?;
+
+        let outputs = self.sessions.run(|session| session.run(session_inputs))?;
+
+        // Try to get the only output key
+        // If multiple, then default to `last_hidden_state`
+        let last_hidden_state_key = match outputs.len() {
+            1 => outputs.keys().next().unwrap(),
+            _ => "last_hidden_state",
+        };
+
+        let output_data = outputs[last_hidden_state_key].try_extract_tensor::<f32>()?;
+
+        let embeddings =
+            SparseTextEmbedding::post_process(&self.model, &output_data, &arrays.attention_mask);
+
+        Ok(embeddings)
+    }
+
     fn post_process(
         model_name: &SparseModel,
         model_output: &ArrayViewD<f32>,
-        attention_mask: &CowArray<i64, Dim<[usize; 2]>>,
+        attention_mask: &Array2<i64>,
     ) -> Vec<SparseEmbedding> {
         match model_name {
             SparseModel::SPLADEPPV1 => {
@@ -241,4 +376,86 @@ impl SparseTextEmbedding {
             }
         }
     }
+
+    /// Method to lazily generate sparse embeddings for an iterator of texts.
+    ///
+    /// The texts are pulled from the iterator one batch at a time, and the embeddings are
+    /// yielded along with the index of their text, so that arbitrarily large corpora can be
+    /// embedded with bounded memory. Iteration stops after the first error.
+    pub fn embed_iter<'a, S, I>(
+        &'a self,
+        texts: I,
+        batch_size: Option<usize>,
+    ) -> impl Iterator<Item = Result<(usize, SparseEmbedding)>> + 'a
+    where
+        I: IntoIterator<Item = S>,
+        I::IntoIter: 'a,
+        S: AsRef<str> + Send + Sync + 'a,
+    {
+        let chunk_size = batch_size.unwrap_or(DEFAULT_BATCH_SIZE);
+
+        lazy_batches(texts.into_iter(), chunk_size, move |batch| {
+            self.embed(batch, batch_size)
+        })
+    }
+
+    /// Streaming version of [`SparseTextEmbedding::embed_iter`], available with the `async`
+    /// feature.
+    ///
+    /// The embeddings are computed on the blocking thread pool and sent through the returned
+    /// channel, which holds at most one batch of them. Dropping the receiver stops any further
+    /// batch from being run. This must be called from within a tokio runtime.
+    #[cfg(feature = "async")]
+    pub fn embed_stream<S, I>(
+        self: Arc<Self>,
+        texts: I,
+        batch_size: Option<usize>,
+    ) -> Receiver<Result<(usize, SparseEmbedding)>>
+    where
+        I: IntoIterator<Item = S>,
+        I::IntoIter: Send + 'static,
+        S: AsRef<str> + Send + Sync + 'static,
+    {
+        let (sender, receiver) = channel(batch_size.unwrap_or(DEFAULT_BATCH_SIZE));
+
+        let texts = texts.into_iter();
+        tokio::task::spawn_blocking(move || forward(self.embed_iter(texts, batch_size), sender));
+
+        receiver
+    }
+
+    /// Async version of [`SparseTextEmbedding::embed`], available with the `async` feature.
+    ///
+    /// The inputs are processed on the blocking thread pool, `batch_size` at a time, so that
+    /// the async executor is not blocked. Dropping the future stops any further batch from
+    /// being scheduled. See [`set_max_concurrent_inferences`](crate::set_max_concurrent_inferences)
+    /// to limit how many inference calls run at once.
+    #[cfg(feature = "async")]
+    pub async fn embed_async<S: AsRef<str> + Send + Sync + 'static>(
+        self: Arc<Self>,
+        texts: Vec<S>,
+        batch_size: Option<usize>,
+    ) -> Result<Vec<SparseEmbedding>> {
+        let chunk_size = batch_size.unwrap_or(DEFAULT_BATCH_SIZE);
+
+        run_in_chunks(texts, chunk_size, move |_, chunk| {
+            self.embed(chunk, batch_size)
+        })
+        .await
+    }
+
+    /// Same as [`SparseTextEmbedding::embed`], but also returns a [`TruncationReport`] for
+    /// each input, telling whether it exceeded the `max_length` of the model and was cut.
+    pub fn embed_with_report<S: AsRef<str> + Send + Sync>(
+        &self,
+        texts: Vec<S>,
+        batch_size: Option<usize>,
+    ) -> Result<(Vec<SparseEmbedding>, Vec<TruncationReport>)> {
+        let report = truncation_report(
+            &self.tokenizer,
+            texts.iter().map(|text| text.as_ref()).collect(),
+        )?;
+
+        Ok((self.embed(texts, batch_size)?, report))
+    }
 }
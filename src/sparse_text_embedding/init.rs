@@ -1,9 +1,15 @@
 use std::path::{Path, PathBuf};
 
-use ort::{execution_providers::ExecutionProviderDispatch, session::Session};
+use ort::execution_providers::ExecutionProviderDispatch;
 use tokenizers::Tokenizer;
 
-use crate::{models::sparse::SparseModel, TokenizerFiles, DEFAULT_CACHE_DIR};
+use crate::{
+    embedding_cache::{EmbeddingCache, EmbeddingCacheOptions},
+    models::sparse::SparseModel,
+    session_options::SessionOptions,
+    session_pool::SessionPool,
+    SparseEmbedding, TokenizerFiles, DEFAULT_CACHE_DIR,
+};
 
 use super::{DEFAULT_EMBEDDING_MODEL, DEFAULT_MAX_LENGTH};
 
@@ -16,6 +22,11 @@ pub struct SparseInitOptions {
     pub max_length: usize,
     pub cache_dir: PathBuf,
     pub show_download_progress: bool,
+    pub sort_by_length: bool,
+    pub max_batch_tokens: Option<usize>,
+    pub embedding_cache: Option<EmbeddingCacheOptions>,
+    pub session_pool_size: usize,
+    pub session_options: SessionOptions,
 }
 
 impl SparseInitOptions {
@@ -48,6 +59,55 @@ impl SparseInitOptions {
         self.show_download_progress = show_download_progress;
         self
     }
+
+    /// Set whether to sort the inputs by token length before batching them, defaults to `false`
+    ///
+    /// Batching inputs of similar length reduces the compute spent on padding. The outputs
+    /// are returned in the original order.
+    ///
+    /// Sorting tokenizes all the inputs up front, so their encodings are held in memory at
+    /// once rather than one batch at a time.
+    pub fn with_sort_by_length(mut self, sort_by_length: bool) -> Self {
+        self.sort_by_length = sort_by_length;
+        self
+    }
+
+    /// Limit each batch to the given number of padded tokens, that is its number of inputs
+    /// times the token length of its longest input
+    ///
+    /// Batches are formed greedily and are still limited to `batch_size` inputs. An input
+    /// longer than the budget is run in a batch of its own.
+    ///
+    /// Like sorting, this tokenizes all the inputs up front.
+    pub fn with_max_batch_tokens(mut self, max_batch_tokens: usize) -> Self {
+        self.max_batch_tokens = Some(max_batch_tokens);
+        self
+    }
+
+    /// Cache the computed embeddings, so that [`SparseTextEmbedding::embed`] only runs the
+    /// model on texts it has not embedded before
+    ///
+    /// Persistent caches are stored under the `cache_dir`.
+    pub fn with_embedding_cache(mut self, embedding_cache: EmbeddingCacheOptions) -> Self {
+        self.embedding_cache = Some(embedding_cache);
+        self
+    }
+
+    /// Create the given number of ONNX sessions, each with an equal share of the threads,
+    /// and run each batch on whichever is free. Defaults to `1`
+    ///
+    /// This increases the throughput when several threads share the model, at the cost of
+    /// loading the model once per session.
+    pub fn with_session_pool_size(mut self, session_pool_size: usize) -> Self {
+        self.session_pool_size = session_pool_size;
+        self
+    }
+
+    /// Set the configuration of the ONNX Runtime sessions, such as the number of threads
+    pub fn with_session_options(mut self, session_options: SessionOptions) -> Self {
+        self.session_options = session_options;
+        self
+    }
 }
 
 impl Default for SparseInitOptions {
@@ -58,6 +118,135 @@ impl Default for SparseInitOptions {
             max_length: DEFAULT_MAX_LENGTH,
             cache_dir: Path::new(DEFAULT_CACHE_DIR).to_path_buf(),
             show_download_progress: true,
+            sort_by_length: false,
+            max_batch_tokens: None,
+            embedding_cache: None,
+            session_pool_size: 1,
+            session_options: Default::default(),
+        }
+    }
+}
+
+/// Options for initializing UserDefinedSparseModel
+///
+/// Model files are held by the UserDefinedSparseModel struct
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct SparseInitOptionsUserDefined {
+    pub execution_providers: Vec<ExecutionProviderDispatch>,
+    pub max_length: usize,
+    pub cache_dir: PathBuf,
+    pub sort_by_length: bool,
+    pub max_batch_tokens: Option<usize>,
+    pub embedding_cache: Option<EmbeddingCacheOptions>,
+    pub session_pool_size: usize,
+    pub session_options: SessionOptions,
+}
+
+impl SparseInitOptionsUserDefined {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_execution_providers(
+        mut self,
+        execution_providers: Vec<ExecutionProviderDispatch>,
+    ) -> Self {
+        self.execution_providers = execution_providers;
+        self
+    }
+
+    pub fn with_max_length(mut self, max_length: usize) -> Self {
+        self.max_length = max_length;
+        self
+    }
+
+    /// Set the directory persistent embedding caches are stored under
+    pub fn with_cache_dir(mut self, cache_dir: PathBuf) -> Self {
+        self.cache_dir = cache_dir;
+        self
+    }
+
+    /// Set whether to sort the inputs by token length before batching them, defaults to `false`
+    ///
+    /// Batching inputs of similar length reduces the compute spent on padding. The outputs
+    /// are returned in the original order.
+    ///
+    /// Sorting tokenizes all the inputs up front, so their encodings are held in memory at
+    /// once rather than one batch at a time.
+    pub fn with_sort_by_length(mut self, sort_by_length: bool) -> Self {
+        self.sort_by_length = sort_by_length;
+        self
+    }
+
+    /// Limit each batch to the given number of padded tokens, that is its number of inputs
+    /// times the token length of its longest input
+    ///
+    /// Batches are formed greedily and are still limited to `batch_size` inputs. An input
+    /// longer than the budget is run in a batch of its own.
+    ///
+    /// Like sorting, this tokenizes all the inputs up front.
+    pub fn with_max_batch_tokens(mut self, max_batch_tokens: usize) -> Self {
+        self.max_batch_tokens = Some(max_batch_tokens);
+        self
+    }
+
+    /// Cache the computed embeddings, so that [`SparseTextEmbedding::embed`] only runs the
+    /// model on texts it has not embedded before
+    ///
+    /// The model is identified by a hash of its files. Persistent caches are stored under the
+    /// `cache_dir`.
+    pub fn with_embedding_cache(mut self, embedding_cache: EmbeddingCacheOptions) -> Self {
+        self.embedding_cache = Some(embedding_cache);
+        self
+    }
+
+    /// Create the given number of ONNX sessions, each with an equal share of the threads,
+    /// and run each batch on whichever is free. Defaults to `1`
+    ///
+    /// This increases the throughput when several threads share the model, at the cost of
+    /// loading the model once per session.
+    pub fn with_session_pool_size(mut self, session_pool_size: usize) -> Self {
+        self.session_pool_size = session_pool_size;
+        self
+    }
+
+    /// Set the configuration of the ONNX Runtime sessions, such as the number of threads
+    pub fn with_session_options(mut self, session_options: SessionOptions) -> Self {
+        self.session_options = session_options;
+        self
+    }
+}
+
+impl Default for SparseInitOptionsUserDefined {
+    fn default() -> Self {
+        Self {
+            execution_providers: Default::default(),
+            max_length: DEFAULT_MAX_LENGTH,
+            cache_dir: Path::new(DEFAULT_CACHE_DIR).to_path_buf(),
+            sort_by_length: false,
+            max_batch_tokens: None,
+            embedding_cache: None,
+            session_pool_size: 1,
+            session_options: Default::default(),
+        }
+    }
+}
+
+/// Convert SparseInitOptions to SparseInitOptionsUserDefined
+///
+/// This is useful for when the user wants to use the same options for both the default and user-defined models
+impl From<SparseInitOptions> for SparseInitOptionsUserDefined {
+    fn from(options: SparseInitOptions) -> Self {
+        SparseInitOptionsUserDefined {
+            execution_providers: options.execution_providers,
+            max_length: options.max_length,
+            cache_dir: options.cache_dir,
+            sort_by_length: options.sort_by_length,
+            max_batch_tokens: options.max_batch_tokens,
+            embedding_cache: options.embedding_cache,
+            session_pool_size: options.session_pool_size,
+            session_options: options.session_options,
         }
     }
 }
@@ -84,7 +273,10 @@ impl UserDefinedSparseModel {
 /// Rust representation of the SparseTextEmbedding model
 pub struct SparseTextEmbedding {
     pub tokenizer: Tokenizer,
-    pub(crate) session: Session,
+    pub(crate) sessions: SessionPool,
     pub(crate) need_token_type_ids: bool,
     pub(crate) model: SparseModel,
+    pub(crate) sort_by_length: bool,
+    pub(crate) max_batch_tokens: Option<usize>,
+    pub(crate) cache: Option<EmbeddingCache<SparseEmbedding>>,
 }
@@ -1,12 +1,27 @@
 //! The definition of the main struct for text embeddings - [`TextEmbedding`].
 
+#[cfg(feature = "async")]
+use crate::asynchronous::{forward, run_in_chunks};
 #[cfg(feature = "hf-hub")]
 use crate::common::load_tokenizer_hf_hub;
 use crate::{
-    common::load_tokenizer,
-    models::text_embedding::{get_model_info, models_list},
+    common::{
+        batch_encodings, encode_batches, lazy_batches, load_tokenizer_with_padding, normalize,
+        restore_order, tokenize, truncation_report, BatchArrays,
+    },
+    embedding_cache::{files_digest, EmbeddingCache},
+    models::{
+        model_info::TaskPrefixes,
+        text_embedding::{get_model_info, models_list},
+    },
     pooling::Pooling,
-    Embedding, EmbeddingModel, EmbeddingOutput, ModelInfo, QuantizationMode, SingleBatchOutput,
+    progress::{BatchTracker, EmbedControl},
+    quantized::to_f16,
+    session_pool::SessionPool,
+    similarity::{self, Matrix, Metric},
+    BinaryEmbedding, Embedding, EmbeddingModel, EmbeddingOutput, EmbeddingTask, F16Embedding,
+    Int8Embedding, ModelInfo, QuantizationMode, ScalarRange, SingleBatchOutput, TokenEmbeddings,
+    TokenizedInput, TruncationReport, Uint8Embedding, WindowAggregation, WindowedEmbedding,
 };
 #[cfg(feature = "hf-hub")]
 use anyhow::Context;
@@ -16,19 +31,16 @@ use hf_hub::{
     api::sync::{ApiBuilder, ApiRepo},
     Cache,
 };
-use ndarray::Array;
-use ort::{
-    session::{builder::GraphOptimizationLevel, Session},
-    value::Value,
-};
 use rayon::{
-    iter::{FromParallelIterator, ParallelIterator},
+    iter::{FromParallelIterator, IntoParallelIterator, IntoParallelRefIterator, ParallelIterator},
     slice::ParallelSlice,
 };
-#[cfg(feature = "hf-hub")]
 use std::path::PathBuf;
-use std::thread::available_parallelism;
-use tokenizers::Tokenizer;
+#[cfg(feature = "async")]
+use std::sync::Arc;
+use tokenizers::{Encoding, PostProcessor, Tokenizer, TruncationParams};
+#[cfg(feature = "async")]
+use tokio::sync::mpsc::{channel, Receiver};
 
 #[cfg(feature = "hf-hub")]
 use super::InitOptions;
@@ -56,9 +68,8 @@ impl<'a> hf_hub::api::Progress for BoxedProgressWrapper<'a> {
 impl TextEmbedding {
     /// Try to generate a new TextEmbedding Instance
     ///
-    /// Uses the highest level of Graph optimization
-    ///
-    /// Uses the total number of CPUs available as the number of intra-threads
+    /// Uses the highest level of Graph optimization and the total number of CPUs available as
+    /// the number of intra-threads, unless configured otherwise with `session_options`
     #[cfg(feature = "hf-hub")]
     pub fn try_new(options: InitOptions) -> Result<Self> {
         let InitOptions {
@@ -68,9 +79,19 @@ impl TextEmbedding {
             cache_dir,
             show_download_progress,
             custom_progress,
+            truncate_dim,
+            normalize,
+            sort_by_length,
+            max_batch_tokens,
+            embedding_cache,
+            session_pool_size,
+            session_options,
         } = options;
         
-        let threads = available_parallelism()?.get();
+        let threads = session_options.threads()?;
+        
+        let (sort_by_length, max_batch_tokens) =
+            session_options.batching(sort_by_length, max_batch_tokens);
         
         let model_repo = TextEmbedding::retrieve_model(
             model_name.clone(),
@@ -80,6 +101,16 @@ impl TextEmbedding {
         
         let model_info = TextEmbedding::get_model_info(&model_name)?;
         let model_file_name = &model_info.model_file;
+
+        if let Some(dim) = truncate_dim {
+            if !model_info.matryoshka_dims.contains(&dim) {
+                return Err(anyhow::Error::msg(format!(
+                    "Model {model_name:?} does not support truncating embeddings to {dim} dimensions. \
+                    Supported dimensions: {:?}",
+                    model_info.matryoshka_dims
+                )));
+            }
+        }
         
         /***
          * This may be able to patch the multiple-downloads issue from hf-hub, but
@@ -137,19 +168,50 @@ impl TextEmbedding {
         // prioritise loading pooling config if available, if not (thanks qdrant!), look for it in hardcoded
         let post_processing = TextEmbedding::get_default_pooling_method(&model_name);
         
-        let session = Session::builder()?
-        .with_execution_providers(execution_providers)?
-        .with_optimization_level(GraphOptimizationLevel::Level3)?
-        .with_intra_threads(threads)?
-        .commit_from_file(model_file_reference)?;
+        let sessions = SessionPool::new(session_pool_size, threads, |intra_threads| {
+            session_options.commit_from_file(
+                &execution_providers,
+                intra_threads,
+                &model_file_reference,
+                &cache_dir,
+            )
+        })?;
+        
+        // Everything affecting the output besides the input text is part of the cache key
+        let cache = embedding_cache
+            .map(|options| {
+                // Variants of a model share its code, but not its file or quantization
+                let namespace = format!(
+                    "{}|file={}|quantization={:?}|pooling={:?}|normalize={}|max_length={}|truncate_dim={:?}",
+                    model_info.model_code,
+                    model_info.model_file,
+                    TextEmbedding::get_quantization_mode(&model_name),
+                    post_processing,
+                    normalize,
+                    max_length,
+                    truncate_dim
+                );
+                let dim = truncate_dim.unwrap_or(model_info.dim);
+                EmbeddingCache::new(&options, namespace, &cache_dir, Some(dim))
+            })
+            .transpose()?;
         
         let tokenizer = load_tokenizer_hf_hub(model_repo, max_length)?;
-        Ok(Self::new(
-            tokenizer,
-            session,
-            post_processing,
-            TextEmbedding::get_quantization_mode(&model_name),
-        ))
+        Ok(Self {
+            prefixes: model_info.prefixes.clone(),
+            cache,
+            matryoshka_layer_norm: TextEmbedding::uses_matryoshka_layer_norm(&model_name),
+            ..Self::new(
+                tokenizer,
+                sessions,
+                post_processing,
+                TextEmbedding::get_quantization_mode(&model_name),
+                truncate_dim,
+                normalize,
+                sort_by_length,
+                max_batch_tokens,
+            )
+        })
     }
     
     /// Create a TextEmbedding instance from model files provided by the user.
@@ -162,43 +224,105 @@ impl TextEmbedding {
         let InitOptionsUserDefined {
             execution_providers,
             max_length,
+            cache_dir,
+            normalize,
+            sort_by_length,
+            max_batch_tokens,
+            embedding_cache,
+            session_pool_size,
+            session_options,
         } = options;
         
-        let threads = available_parallelism()?.get();
+        let threads = session_options.threads()?;
         
-        let session = Session::builder()?
-        .with_execution_providers(execution_providers)?
-        .with_optimization_level(GraphOptimizationLevel::Level3)?
-        .with_intra_threads(threads)?
-        .commit_from_memory(&model.onnx_file)?;
+        let (sort_by_length, max_batch_tokens) =
+            session_options.batching(sort_by_length, max_batch_tokens);
         
-        let tokenizer = load_tokenizer(model.tokenizer_files, max_length)?;
-        Ok(Self::new(
-            tokenizer,
-            session,
-            model.pooling,
-            model.quantization,
-        ))
+        let sessions = SessionPool::new(session_pool_size, threads, |intra_threads| {
+            Ok(session_options
+            .builder(&execution_providers, intra_threads)?
+            .commit_from_memory(&model.onnx_file)?)
+        })?;
+        
+        // The files of the model stand in for its name in the cache key
+        let cache = embedding_cache
+            .map(|options| {
+                let files = &model.tokenizer_files;
+                let namespace = format!(
+                    "user-defined={}|quantization={:?}|pooling={:?}|padding_side={:?}|normalize={}|max_length={}",
+                    files_digest(&[
+                        &model.onnx_file,
+                        &files.tokenizer_file,
+                        &files.config_file,
+                        &files.special_tokens_map_file,
+                        &files.tokenizer_config_file,
+                    ]),
+                    model.quantization,
+                    model.pooling,
+                    model.padding_side,
+                    normalize,
+                    max_length
+                );
+                EmbeddingCache::new(&options, namespace, &cache_dir, None)
+            })
+            .transpose()?;
+        
+        let tokenizer =
+            load_tokenizer_with_padding(model.tokenizer_files, max_length, model.padding_side)?;
+        Ok(Self {
+            prefixes: model.prefixes,
+            cache,
+            ..Self::new(
+                tokenizer,
+                sessions,
+                model.pooling,
+                model.quantization,
+                None,
+                normalize,
+                sort_by_length,
+                max_batch_tokens,
+            )
+        })
     }
     
+    /// Stop profiling the inference of the model, enabled with
+    /// [`SessionOptions::with_profiling`](crate::SessionOptions::with_profiling), and return
+    /// the paths of the profile files, one per session
+    pub fn end_profiling(&self) -> Result<Vec<PathBuf>> {
+        self.sessions.end_profiling()
+    }
+
     /// Private method to return an instance
+    #[allow(clippy::too_many_arguments)]
     fn new(
         tokenizer: Tokenizer,
-        session: Session,
+        sessions: SessionPool,
         post_process: Option<Pooling>,
         quantization: QuantizationMode,
+        truncate_dim: Option<usize>,
+        normalize: bool,
+        sort_by_length: bool,
+        max_batch_tokens: Option<usize>,
     ) -> Self {
-        let need_token_type_ids = session
+        let need_token_type_ids = sessions
+        .session()
         .inputs
         .iter()
         .any(|input| input.name == "token_type_ids");
         
         Self {
             tokenizer,
-            session,
+            sessions,
             need_token_type_ids,
             pooling: post_process,
             quantization,
+            truncate_dim,
+            matryoshka_layer_norm: false,
+            normalize,
+            sort_by_length,
+            max_batch_tokens,
+            prefixes: TaskPrefixes::default(),
+            cache: None,
         }
     }
     /// Return the TextEmbedding model's directory from cache or remote retrieval
@@ -288,6 +412,15 @@ impl TextEmbedding {
             _ => QuantizationMode::None,
         }
     }
+
+    /// Whether the model expects a layer norm of its pooled embeddings before they are
+    /// truncated to one of its [`ModelInfo::matryoshka_dims`].
+    fn uses_matryoshka_layer_norm(model_name: &EmbeddingModel) -> bool {
+        matches!(
+            model_name,
+            EmbeddingModel::NomicEmbedTextV15 | EmbeddingModel::NomicEmbedTextV15Q
+        )
+    }
     
     /// Retrieve a list of supported models
     pub fn list_supported_models() -> Vec<ModelInfo<EmbeddingModel>> {
@@ -304,6 +437,62 @@ impl TextEmbedding {
         })
     }
     
+    /// Determine the batch size according to the quantization method used.
+    /// Default if not specified
+    fn resolve_batch_size(&self, batch_size: Option<usize>, input_count: usize) -> Result<usize> {
+        match self.quantization {
+            QuantizationMode::Dynamic => {
+                if self.max_batch_tokens.is_some() {
+                    Err(anyhow::Error::msg(
+                        "Dynamic quantization cannot be used with a token budget per batch, \
+                        as the embeddings would be incompatible across batches.",
+                    ))
+                } else if let Some(batch_size) = batch_size {
+                    if batch_size < input_count {
+                        Err(anyhow::Error::msg(
+                            "Dynamic quantization cannot be used with batching. \
+                            This is due to the dynamic quantization process adjusting \
+                            the data range to fit each batch, making the embeddings \
+                            incompatible across batches. Try specifying a batch size \
+                            of `None`, or use a model with static or no quantization.",
+                        ))
+                    } else {
+                        Ok(input_count)
+                    }
+                } else {
+                    Ok(input_count)
+                }
+            }
+            _ => Ok(batch_size.unwrap_or(DEFAULT_BATCH_SIZE)),
+        }
+    }
+    
+    /// Run the model on a batch of equally long encodings.
+    fn run_encodings<'r, 's>(
+        &'s self,
+        encodings: Vec<Encoding>,
+    ) -> Result<SingleBatchOutput<'r, 's>>
+    where
+        's: 'r,
+    {
+        let arrays = BatchArrays::from_encodings(&encodings)?;
+        let session_inputs = arrays.session_inputs(self.need_token_type_ids)?;
+        
+        Ok(
+            // Package all the data required for post-processing (e.g. pooling)
+            // into a SingleBatchOutput struct.
+            SingleBatchOutput {
+                session_outputs: self
+                .sessions
+                .run(|session| session.run(session_inputs))
+                .map_err(anyhow::Error::new)?,
+                input_ids_array: arrays.input_ids,
+                attention_mask_array: arrays.attention_mask,
+                encodings,
+            },
+        )
+    }
+    
     /// Method to generate an [`ort::SessionOutputs`] wrapped in a [`EmbeddingOutput`]
     /// instance, which can be used to extract the embeddings with default or custom
     /// methods as well as output key precedence.
@@ -335,97 +524,110 @@ impl TextEmbedding {
     'e: 'r,
     'e: 's,
     {
-        // Determine the batch size according to the quantization method used.
-        // Default if not specified
-        let batch_size = match self.quantization {
-            QuantizationMode::Dynamic => {
-                if let Some(batch_size) = batch_size {
-                    if batch_size < texts.len() {
-                        Err(anyhow::Error::msg(
-                            "Dynamic quantization cannot be used with batching. \
-                            This is due to the dynamic quantization process adjusting \
-                            the data range to fit each batch, making the embeddings \
-                            incompatible across batches. Try specifying a batch size \
-                            of `None`, or use a model with static or no quantization.",
-                        ))
-                    } else {
-                        Ok(texts.len())
-                    }
-                } else {
-                    Ok(texts.len())
-                }
-            }
-            _ => Ok(batch_size.unwrap_or(DEFAULT_BATCH_SIZE)),
-        }?;
+        self.transform_with_control(texts, batch_size, &EmbedControl::default())
+    }
+    
+    /// [`TextEmbedding::transform`] with progress reporting and cancellation between batches.
+    fn transform_with_control<'e, 'r, 's, S: AsRef<str> + Send + Sync>(
+        &'e self,
+        texts: Vec<S>,
+        batch_size: Option<usize>,
+        control: &EmbedControl,
+    ) -> Result<EmbeddingOutput<'r, 's>>
+    where
+    'e: 'r,
+    'e: 's,
+    {
+        let batch_size = self.resolve_batch_size(batch_size, texts.len())?;
+        let tracker = BatchTracker::new(control, texts.len().div_ceil(batch_size), texts.len());
         
         let batches = Result::<Vec<_>>::from_par_iter(texts.par_chunks(batch_size).map(|batch| {
-            // Encode the texts in the batch
-            let inputs = batch.iter().map(|text| text.as_ref()).collect();
-            let encodings = self.tokenizer.encode_batch(inputs, true).map_err(|e| {
-                anyhow::Error::msg(e.to_string()).context("Failed to encode the batch.")
-            })?;
-            
-            // Extract the encoding length and batch size
-            let encoding_length = encodings[0].len();
-            let batch_size = batch.len();
-            
-            let max_size = encoding_length * batch_size;
-            
-            // Preallocate arrays with the maximum size
-            let mut ids_array = Vec::with_capacity(max_size);
-            let mut mask_array = Vec::with_capacity(max_size);
-            let mut type_ids_array = Vec::with_capacity(max_size);
-            
-            // Not using par_iter because the closure needs to be FnMut
-            encodings.iter().for_each(|encoding| {
-                let ids = encoding.get_ids();
-                let mask = encoding.get_attention_mask();
-                let type_ids = encoding.get_type_ids();
+            tracker.run(batch.len(), || {
+                // Encode the texts in the batch
+                let inputs = batch.iter().map(|text| text.as_ref()).collect();
+                let encodings = self.tokenizer.encode_batch(inputs, true).map_err(|e| {
+                    anyhow::Error::msg(e.to_string()).context("Failed to encode the batch.")
+                })?;
                 
-                // Extend the preallocated arrays with the current encoding
-                // Requires the closure to be FnMut
-                ids_array.extend(ids.iter().map(|x| *x as i64));
-                mask_array.extend(mask.iter().map(|x| *x as i64));
-                type_ids_array.extend(type_ids.iter().map(|x| *x as i64));
-            });
-            
-            // Create CowArrays from vectors
-            let inputs_ids_array = Array::from_shape_vec((batch_size, encoding_length), ids_array)?;
-            
-            let attention_mask_array =
-            Array::from_shape_vec((batch_size, encoding_length), mask_array)?;
-            
-            let token_type_ids_array =
-            Array::from_shape_vec((batch_size, encoding_length), type_ids_array)?;
-            
-            let mut session_inputs = ort::inputs![
-            "input_ids" => Value::from_array(inputs_ids_array)?,
-            "attention_mask" => Value::from_array(attention_mask_array.view())?,
-            ]?;
-            
-            if self.need_token_type_ids {
-                session_inputs.push((
-                    "token_type_ids".into(),
-                    Value::from_array(token_type_ids_array)?.into(),
-                ));
-            }
-            
-            Ok(
-                // Package all the data required for post-processing (e.g. pooling)
-                // into a SingleBatchOutput struct.
-                SingleBatchOutput {
-                    session_outputs: self
-                    .session
-                    .run(session_inputs)
-                    .map_err(anyhow::Error::new)?,
-                    attention_mask_array,
-                },
-            )
+                self.run_encodings(encodings)
+            })
         }))?;
         
         Ok(EmbeddingOutput::new(batches))
     }
     
+    /// Run [`TextEmbedding::transform`] and export the outputs with the given transformer,
+    /// which must return one item per input.
+    ///
+    /// If the inputs are to be sorted by length or batched by token budget, they are batched
+    /// accordingly instead, and the exported items are restored to the input order.
+    fn transform_and_export<S: AsRef<str> + Send + Sync, R>(
+        &self,
+        texts: Vec<S>,
+        batch_size: Option<usize>,
+        transformer: impl Fn(&[SingleBatchOutput]) -> Result<Vec<R>>,
+        control: &EmbedControl,
+    ) -> Result<Vec<R>> {
+        if !self.sort_by_length && self.max_batch_tokens.is_none() {
+            return self
+                .transform_with_control(texts, batch_size, control)?
+                .export_with_transformer(transformer);
+        }
+        
+        let batch_size = self.resolve_batch_size(batch_size, texts.len())?;
+        let inputs = texts.iter().map(|text| text.as_ref()).collect();
+        let (encodings, order) = encode_batches(
+            &self.tokenizer,
+            inputs,
+            batch_size,
+            self.max_batch_tokens,
+            self.sort_by_length,
+        )?;
+        
+        self.run_batches(encodings, &order, transformer, control)
+    }
+
+    /// Run batches of encodings, reporting their progress to `control`, and export the outputs
+    /// with the given transformer, restored to the order of the encodings given by `order`.
+    fn run_batches<R>(
+        &self,
+        batches: Vec<Vec<Encoding>>,
+        order: &[usize],
+        transformer: impl Fn(&[SingleBatchOutput]) -> Result<Vec<R>>,
+        control: &EmbedControl,
+    ) -> Result<Vec<R>> {
+        let tracker = BatchTracker::new(control, batches.len(), order.len());
+        let batches = Result::<Vec<_>>::from_par_iter(
+            batches
+                .into_par_iter()
+                .map(|encodings| tracker.run(encodings.len(), || self.run_encodings(encodings))),
+        )?;
+        let items = EmbeddingOutput::new(batches).export_with_transformer(transformer)?;
+
+        Ok(restore_order(items, order))
+    }
+    
+    /// Method to generate token-level embeddings for a Vec of texts.
+    ///
+    /// Accepts a [`Vec`] consisting of elements of either [`String`], &[`str`],
+    /// [`std::ffi::OsString`], &[`std::ffi::OsStr`].
+    ///
+    /// The output is a [`Vec`] of [`TokenEmbeddings`], one per input, each containing the
+    /// un-normalized embedding, token id and byte offsets of every token that is not
+    /// padding.
+    pub fn embed_tokens<S: AsRef<str> + Send + Sync>(
+        &self,
+        texts: Vec<S>,
+        batch_size: Option<usize>,
+    ) -> Result<Vec<TokenEmbeddings>> {
+        self.transform_and_export(
+            texts,
+            batch_size,
+            output::token_transformer_with_precedence(output::TOKEN_OUTPUT_TYPE_PRECEDENCE),
+            &EmbedControl::default(),
+        )
+    }
+
     /// Method to generate sentence embeddings for a Vec of texts.
     ///
     /// Accepts a [`Vec`] consisting of elements of either [`String`], &[`str`],
@@ -437,16 +639,505 @@ impl TextEmbedding {
     ///
     /// This method is a higher level method than [`TextEmbedding::transform`] by utilizing
     /// the default output precedence and array transformer for the [`TextEmbedding`] model.
+    ///
+    /// With an embedding cache configured through
+    /// [`InitOptions::with_embedding_cache`](crate::InitOptions::with_embedding_cache),
+    /// only the texts that are not cached are run through the model.
     pub fn embed<S: AsRef<str> + Send + Sync>(
         &self,
         texts: Vec<S>,
         batch_size: Option<usize>,
     ) -> Result<Vec<Embedding>> {
-        let batches = self.transform(texts, batch_size)?;
+        self.embed_with_control(texts, batch_size, &EmbedControl::default())
+    }
+
+    /// Same as [`TextEmbedding::embed`], but reports the progress after each batch and stops
+    /// before the next batch once cancelled, returning a [`Cancelled`](crate::Cancelled) error.
+    ///
+    /// With an embedding cache, only the texts that are not cached are counted.
+    pub fn embed_with_control<S: AsRef<str> + Send + Sync>(
+        &self,
+        texts: Vec<S>,
+        batch_size: Option<usize>,
+        control: &EmbedControl,
+    ) -> Result<Vec<Embedding>> {
+        match &self.cache {
+            Some(cache) => cache.get_or_compute(&texts, |misses| {
+                self.embed_uncached(misses, batch_size, control)
+            }),
+            None => self.embed_uncached(texts, batch_size, control),
+        }
+    }
+
+    /// Run [`TextEmbedding::embed`] without looking up the embedding cache.
+    fn embed_uncached<S: AsRef<str> + Send + Sync>(
+        &self,
+        texts: Vec<S>,
+        batch_size: Option<usize>,
+        control: &EmbedControl,
+    ) -> Result<Vec<Embedding>> {
+        self.transform_and_export(
+            texts,
+            batch_size,
+            output::pooled_transformer(
+                output::OUTPUT_TYPE_PRECEDENCE,
+                self.pooling.clone(),
+                self.truncate_dim,
+                self.matryoshka_layer_norm,
+                self.normalize,
+            ),
+            control,
+        )
+    }
+
+    /// Method to generate sentence embeddings as half-precision floats.
+    ///
+    /// See [`TextEmbedding::embed`].
+    pub fn embed_f16<S: AsRef<str> + Send + Sync>(
+        &self,
+        texts: Vec<S>,
+        batch_size: Option<usize>,
+    ) -> Result<Vec<F16Embedding>> {
+        Ok(self
+            .embed(texts, batch_size)?
+            .iter()
+            .map(|embedding| to_f16(embedding))
+            .collect())
+    }
+
+    /// Method to generate sentence embeddings scalar-quantized to int8 with the given range.
+    ///
+    /// The range is usually computed with [`ScalarRange::from_sample`] from the embeddings of
+    /// a sample of the corpus, generated by [`TextEmbedding::embed`].
+    pub fn embed_int8<S: AsRef<str> + Send + Sync>(
+        &self,
+        texts: Vec<S>,
+        range: &ScalarRange,
+        batch_size: Option<usize>,
+    ) -> Result<Vec<Int8Embedding>> {
+        self.embed_quantized(texts, range, batch_size, |embedding| {
+            range.quantize_int8(embedding)
+        })
+    }
+
+    /// Method to generate sentence embeddings scalar-quantized to uint8 with the given range.
+    ///
+    /// See [`TextEmbedding::embed_int8`].
+    pub fn embed_uint8<S: AsRef<str> + Send + Sync>(
+        &self,
+        texts: Vec<S>,
+        range: &ScalarRange,
+        batch_size: Option<usize>,
+    ) -> Result<Vec<Uint8Embedding>> {
+        self.embed_quantized(texts, range, batch_size, |embedding| {
+            range.quantize_uint8(embedding)
+        })
+    }
+
+    /// Method to generate sentence embeddings quantized to one bit per dimension.
+    ///
+    /// Compare them with [`BinaryEmbedding::hamming_distance`].
+    pub fn embed_binary<S: AsRef<str> + Send + Sync>(
+        &self,
+        texts: Vec<S>,
+        batch_size: Option<usize>,
+    ) -> Result<Vec<BinaryEmbedding>> {
+        Ok(self
+            .embed(texts, batch_size)?
+            .iter()
+            .map(|embedding| BinaryEmbedding::from_embedding(embedding))
+            .collect())
+    }
+
+    /// Scalar-quantize the embeddings, after checking that they match the range.
+    fn embed_quantized<S: AsRef<str> + Send + Sync, T>(
+        &self,
+        texts: Vec<S>,
+        range: &ScalarRange,
+        batch_size: Option<usize>,
+        quantize: impl Fn(&[f32]) -> T,
+    ) -> Result<Vec<T>> {
+        self.embed(texts, batch_size)?
+            .iter()
+            .map(|embedding| {
+                if embedding.len() != range.dim() {
+                    return Err(anyhow::Error::msg(format!(
+                        "Cannot quantize embeddings of dimension {} with a range of dimension {}.",
+                        embedding.len(),
+                        range.dim()
+                    )));
+                }
+                Ok(quantize(embedding))
+            })
+            .collect()
+    }
+
+    /// Method to generate embeddings for search queries, with the query prefix the model
+    /// expects prepended to each text.
+    ///
+    /// See [`TextEmbedding::embed_for_task`].
+    pub fn embed_query<S: AsRef<str> + Send + Sync>(
+        &self,
+        texts: Vec<S>,
+        batch_size: Option<usize>,
+    ) -> Result<Vec<Embedding>> {
+        self.embed_for_task(EmbeddingTask::Query, texts, batch_size)
+    }
+
+    /// Method to generate embeddings for documents to be searched, with the document prefix
+    /// the model expects prepended to each text.
+    ///
+    /// See [`TextEmbedding::embed_for_task`].
+    pub fn embed_documents<S: AsRef<str> + Send + Sync>(
+        &self,
+        texts: Vec<S>,
+        batch_size: Option<usize>,
+    ) -> Result<Vec<Embedding>> {
+        self.embed_for_task(EmbeddingTask::Document, texts, batch_size)
+    }
+
+    /// Method to generate embeddings for the given task, with the prefix the model expects
+    /// for it prepended to each text.
+    ///
+    /// The prefixes come from [`ModelInfo::prefixes`](crate::ModelInfo::prefixes), or from
+    /// [`UserDefinedEmbeddingModel::with_prefixes`] for user-defined models. Models without a
+    /// prefix for the task embed the texts as they are, the same as [`TextEmbedding::embed`].
+    pub fn embed_for_task<S: AsRef<str> + Send + Sync>(
+        &self,
+        task: EmbeddingTask,
+        texts: Vec<S>,
+        batch_size: Option<usize>,
+    ) -> Result<Vec<Embedding>> {
+        let prefix = self.prefixes.get(task);
+        if prefix.is_empty() {
+            return self.embed(texts, batch_size);
+        }
+
+        let texts = texts
+            .iter()
+            .map(|text| format!("{prefix}{}", text.as_ref()))
+            .collect::<Vec<_>>();
+        self.embed(texts, batch_size)
+    }
+
+    /// Async version of [`TextEmbedding::embed`], available with the `async` feature.
+    ///
+    /// The inputs are processed on the blocking thread pool, `batch_size` at a time, so that
+    /// the async executor is not blocked. Dropping the future stops any further batch from
+    /// being scheduled. See [`set_max_concurrent_inferences`](crate::set_max_concurrent_inferences)
+    /// to limit how many inference calls run at once.
+    #[cfg(feature = "async")]
+    pub async fn embed_async<S: AsRef<str> + Send + Sync + 'static>(
+        self: Arc<Self>,
+        texts: Vec<S>,
+        batch_size: Option<usize>,
+    ) -> Result<Vec<Embedding>> {
+        // Dynamically quantized models need all the texts in a single batch
+        let chunk_size = self.resolve_batch_size(batch_size, texts.len())?;
+        
+        run_in_chunks(texts, chunk_size, move |_, chunk| {
+            self.embed(chunk, batch_size)
+        })
+        .await
+    }
+    
+    /// Method to lazily generate sentence embeddings for an iterator of texts.
+    ///
+    /// The texts are pulled from the iterator one batch at a time, and the embeddings are
+    /// yielded along with the index of their text, so that arbitrarily large corpora can be
+    /// embedded with bounded memory. Iteration stops after the first error.
+    ///
+    /// Dynamically quantized models must embed every text in the same batch, so all the texts
+    /// are pulled from the iterator at once and memory is not bounded. Giving them a
+    /// `batch_size` yields an error as the first item.
+    pub fn embed_iter<'a, S, I>(
+        &'a self,
+        texts: I,
+        batch_size: Option<usize>,
+    ) -> impl Iterator<Item = Result<(usize, Embedding)>> + 'a
+    where
+        I: IntoIterator<Item = S>,
+        I::IntoIter: 'a,
+        S: AsRef<str> + Send + Sync + 'a,
+    {
+        let (chunk_size, mut error) = match self.resolve_batch_size(batch_size, usize::MAX) {
+            Ok(chunk_size) => (chunk_size, None),
+            Err(err) => (1, Some(err)),
+        };
         
-        batches.export_with_transformer(output::transformer_with_precedence(
-            output::OUTPUT_TYPE_PRECEDENCE,
-            self.pooling.clone(),
-        ))
+        lazy_batches(texts.into_iter(), chunk_size, move |batch| {
+            match error.take() {
+                Some(err) => Err(err),
+                None => self.embed(batch, batch_size),
+            }
+        })
     }
+    
+    /// Streaming version of [`TextEmbedding::embed_iter`], available with the `async` feature.
+    ///
+    /// The embeddings are computed on the blocking thread pool and sent through the returned
+    /// channel, which holds at most one batch of them. Dropping the receiver stops any further
+    /// batch from being run. This must be called from within a tokio runtime.
+    #[cfg(feature = "async")]
+    pub fn embed_stream<S, I>(
+        self: Arc<Self>,
+        texts: I,
+        batch_size: Option<usize>,
+    ) -> Receiver<Result<(usize, Embedding)>>
+    where
+        I: IntoIterator<Item = S>,
+        I::IntoIter: Send + 'static,
+        S: AsRef<str> + Send + Sync + 'static,
+    {
+        let (sender, receiver) = channel(batch_size.unwrap_or(DEFAULT_BATCH_SIZE));
+        
+        let texts = texts.into_iter();
+        tokio::task::spawn_blocking(move || forward(self.embed_iter(texts, batch_size), sender));
+        
+        receiver
+    }
+    
+    /// Same as [`TextEmbedding::embed`], but also returns a [`TruncationReport`] for each input,
+    /// telling whether it exceeded the `max_length` of the model and was cut.
+    ///
+    /// The report requires tokenizing the inputs a second time without truncation.
+    pub fn embed_with_report<S: AsRef<str> + Send + Sync>(
+        &self,
+        texts: Vec<S>,
+        batch_size: Option<usize>,
+    ) -> Result<(Vec<Embedding>, Vec<TruncationReport>)> {
+        let report = truncation_report(
+            &self.tokenizer,
+            texts.iter().map(|text| text.as_ref()).collect(),
+        )?;
+        
+        Ok((self.embed(texts, batch_size)?, report))
+    }
+
+    /// Method to tokenize texts the way they are fed to the model, without running it.
+    ///
+    /// The tokens are truncated to the `max_length` of the model but not padded.
+    pub fn tokenize<S: AsRef<str> + Send + Sync>(
+        &self,
+        texts: Vec<S>,
+    ) -> Result<Vec<TokenizedInput>> {
+        tokenize(
+            &self.tokenizer,
+            texts.iter().map(|text| text.as_ref()).collect(),
+        )
+    }
+
+    /// Method to count the tokens of texts, including special tokens, without running the model.
+    ///
+    /// The counts are taken before truncation, so they may exceed the `max_length` of the model.
+    pub fn count_tokens<S: AsRef<str> + Send + Sync>(&self, texts: Vec<S>) -> Result<Vec<usize>> {
+        Ok(truncation_report(
+            &self.tokenizer,
+            texts.iter().map(|text| text.as_ref()).collect(),
+        )?
+        .iter()
+        .map(|report| report.token_count)
+        .collect())
+    }
+
+    /// Method to embed two texts and return their cosine similarity.
+    pub fn similarity<S: AsRef<str> + Send + Sync>(&self, a: S, b: S) -> Result<f32> {
+        let embeddings = self.embed(vec![a, b], None)?;
+        similarity::cosine(&embeddings[0], &embeddings[1])
+    }
+
+    /// Method to embed two lists of texts and return the scores of each text of `a` against
+    /// each text of `b` with `metric`, as a matrix with one row per text of `a`.
+    pub fn similarity_matrix<S: AsRef<str> + Send + Sync>(
+        &self,
+        a: Vec<S>,
+        b: Vec<S>,
+        metric: Metric,
+        batch_size: Option<usize>,
+    ) -> Result<Matrix> {
+        let a = Matrix::from_embeddings(&self.embed(a, batch_size)?)?;
+        let b = Matrix::from_embeddings(&self.embed(b, batch_size)?)?;
+        similarity::matrix(metric, &a, &b)
+    }
+
+    /// Method to generate embeddings for texts longer than the `max_length` of the model.
+    ///
+    /// Each text is split into windows of at most `max_length` tokens, with consecutive
+    /// windows sharing `overlap` tokens. All windows are embedded together in batches of
+    /// `batch_size`, and the window embeddings of each text are combined according to
+    /// `aggregation`.
+    ///
+    /// The output is a [`Vec`] of [`WindowedEmbedding`]s, one per input, which also contains
+    /// the byte offsets of each window in the input text.
+    ///
+    /// Windows are batched like the texts of [`TextEmbedding::embed`], and with an embedding
+    /// cache, only the windows that are not cached are run through the model.
+    pub fn embed_windowed<S: AsRef<str> + Send + Sync>(
+        &self,
+        texts: Vec<S>,
+        overlap: usize,
+        aggregation: WindowAggregation,
+        batch_size: Option<usize>,
+    ) -> Result<Vec<WindowedEmbedding>> {
+        self.embed_windowed_with_control(
+            texts,
+            overlap,
+            aggregation,
+            batch_size,
+            &EmbedControl::default(),
+        )
+    }
+
+    /// Same as [`TextEmbedding::embed_windowed`], but reports the progress after each batch
+    /// and stops before the next batch once cancelled, returning a
+    /// [`Cancelled`](crate::Cancelled) error.
+    ///
+    /// The progress counts windows rather than texts, and only the windows that are not
+    /// cached.
+    pub fn embed_windowed_with_control<S: AsRef<str> + Send + Sync>(
+        &self,
+        texts: Vec<S>,
+        overlap: usize,
+        aggregation: WindowAggregation,
+        batch_size: Option<usize>,
+        control: &EmbedControl,
+    ) -> Result<Vec<WindowedEmbedding>> {
+        let mut tokenizer = self.tokenizer.clone();
+        if let Some(truncation) = tokenizer.get_truncation().cloned() {
+            // Each window also holds the special tokens, and must advance by at least one token
+            let added_tokens = tokenizer
+                .get_post_processor()
+                .map_or(0, |processor| processor.added_tokens(false));
+            let window_length = truncation.max_length.saturating_sub(added_tokens);
+            if overlap >= window_length {
+                return Err(anyhow::Error::msg(format!(
+                    "The overlap of {overlap} tokens must be smaller than the {window_length} tokens of each window."
+                )));
+            }
+            tokenizer
+                .with_truncation(Some(TruncationParams {
+                    stride: overlap,
+                    ..truncation
+                }))
+                .map_err(|e| anyhow::Error::msg(e.to_string()))?;
+        }
+
+        // Unlike `encode_batch`, `encode` does not pad, as windows are padded per batch
+        let encodings = texts
+            .par_iter()
+            .map(|text| tokenizer.encode(text.as_ref(), true))
+            .collect::<tokenizers::Result<Vec<_>>>()
+            .map_err(|e| {
+                anyhow::Error::msg(e.to_string()).context("Failed to encode the texts.")
+            })?;
+
+        // Flatten the overflowing windows of each text, remembering which text they belong to
+        let mut window_counts = Vec::with_capacity(encodings.len());
+        let windows: Vec<Encoding> = encodings
+            .into_iter()
+            .flat_map(|mut encoding| {
+                let overflowing = encoding.take_overflowing();
+                window_counts.push(overflowing.len() + 1);
+                std::iter::once(encoding).chain(overflowing)
+            })
+            .collect();
+        let window_offsets: Vec<(usize, usize)> = windows.iter().map(window_offsets).collect();
+
+        let embeddings = match &self.cache {
+            Some(cache) => cache.get_or_compute_windows(&windows, |misses| {
+                self.embed_windows(misses.into_iter().cloned().collect(), batch_size, control)
+            })?,
+            None => self.embed_windows(windows, batch_size, control)?,
+        };
+
+        let mut embeddings = embeddings.into_iter();
+        let mut window_offsets = window_offsets.into_iter();
+        Ok(window_counts
+            .into_iter()
+            .map(|count| {
+                let windows: Vec<Embedding> = embeddings.by_ref().take(count).collect();
+                let embeddings = match aggregation {
+                    WindowAggregation::All => windows,
+                    WindowAggregation::Mean => vec![aggregate(windows, |acc, x| *acc += x)
+                        .into_iter()
+                        .map(|x| x / count as f32)
+                        .collect()],
+                    WindowAggregation::Max => vec![aggregate(windows, |acc, x| *acc = acc.max(x))],
+                };
+
+                WindowedEmbedding {
+                    embeddings: embeddings
+                        .into_iter()
+                        .map(|embedding| {
+                            if self.normalize {
+                                normalize(&embedding)
+                            } else {
+                                embedding
+                            }
+                        })
+                        .collect(),
+                    window_offsets: window_offsets.by_ref().take(count).collect(),
+                }
+            })
+            .collect())
+    }
+
+    /// Run the windows of [`TextEmbedding::embed_windowed`] through the model, returning
+    /// their un-normalized embeddings.
+    fn embed_windows(
+        &self,
+        windows: Vec<Encoding>,
+        batch_size: Option<usize>,
+        control: &EmbedControl,
+    ) -> Result<Vec<Embedding>> {
+        let batch_size = self.resolve_batch_size(batch_size, windows.len())?;
+        let (batches, order) = batch_encodings(
+            windows,
+            self.tokenizer.get_padding(),
+            batch_size,
+            self.max_batch_tokens,
+            self.sort_by_length,
+        )?;
+
+        // Normalize after aggregating, so that the aggregated embedding has a unit norm. Windows
+        // are then weighted by the norm of their un-normalized embedding.
+        self.run_batches(
+            batches,
+            &order,
+            output::pooled_transformer(
+                output::OUTPUT_TYPE_PRECEDENCE,
+                self.pooling.clone(),
+                self.truncate_dim,
+                self.matryoshka_layer_norm,
+                false,
+            ),
+            control,
+        )
+    }
+}
+
+/// The `(start, end)` byte offsets in the input text spanned by a window, ignoring
+/// special tokens.
+fn window_offsets(encoding: &Encoding) -> (usize, usize) {
+    encoding
+        .get_offsets()
+        .iter()
+        .zip(encoding.get_special_tokens_mask())
+        .filter(|(_, &special)| special == 0)
+        .fold(None, |span: Option<(usize, usize)>, (&(start, end), _)| {
+            Some(span.map_or((start, end), |(s, e)| (s.min(start), e.max(end))))
+        })
+        .unwrap_or_default()
+}
+
+/// Combine the window embeddings of a text element-wise.
+fn aggregate(windows: Vec<Embedding>, combine: impl Fn(&mut f32, f32)) -> Embedding {
+    let mut windows = windows.into_iter();
+    let first = windows.next().unwrap_or_default();
+    windows.fold(first, |mut acc, window| {
+        acc.iter_mut()
+            .zip(window)
+            .for_each(|(acc, x)| combine(acc, x));
+        acc
+    })
 }
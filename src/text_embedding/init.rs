@@ -3,11 +3,15 @@
 use std::fmt::{self, Debug, Formatter};
 
 use crate::{
-    common::{TokenizerFiles, DEFAULT_CACHE_DIR},
+    common::{PaddingSide, TokenizerFiles, DEFAULT_CACHE_DIR},
+    embedding_cache::{EmbeddingCache, EmbeddingCacheOptions},
+    models::model_info::TaskPrefixes,
     pooling::Pooling,
-    EmbeddingModel, QuantizationMode,
+    session_options::SessionOptions,
+    session_pool::SessionPool,
+    Embedding, EmbeddingModel, QuantizationMode,
 };
-use ort::{execution_providers::ExecutionProviderDispatch, session::Session};
+use ort::execution_providers::ExecutionProviderDispatch;
 use std::path::{Path, PathBuf};
 use tokenizers::Tokenizer;
 
@@ -32,6 +36,13 @@ pub struct InitOptions {
     pub cache_dir: PathBuf,
     pub show_download_progress: bool,
     pub custom_progress: Option<Box<dyn hf_hub::api::Progress + Send + Sync + 'static>>,
+    pub truncate_dim: Option<usize>,
+    pub normalize: bool,
+    pub sort_by_length: bool,
+    pub max_batch_tokens: Option<usize>,
+    pub embedding_cache: Option<EmbeddingCacheOptions>,
+    pub session_pool_size: usize,
+    pub session_options: SessionOptions,
 }
 
 // Manual Debug implementation
@@ -44,6 +55,13 @@ impl std::fmt::Debug for InitOptions {
             .field("cache_dir", &self.cache_dir)
             .field("show_download_progress", &self.show_download_progress)
             .field("custom_progress", &if self.custom_progress.is_some() { "Some(<progress>)" } else { "None" })
+            .field("truncate_dim", &self.truncate_dim)
+            .field("normalize", &self.normalize)
+            .field("sort_by_length", &self.sort_by_length)
+            .field("max_batch_tokens", &self.max_batch_tokens)
+            .field("embedding_cache", &self.embedding_cache)
+            .field("session_pool_size", &self.session_pool_size)
+            .field("session_options", &self.session_options)
             .finish()
     }
 }
@@ -58,6 +76,13 @@ impl Clone for InitOptions {
             cache_dir: self.cache_dir.clone(),
             show_download_progress: self.show_download_progress,
             custom_progress: None, // Progress can't be cloned
+            truncate_dim: self.truncate_dim,
+            normalize: self.normalize,
+            sort_by_length: self.sort_by_length,
+            max_batch_tokens: self.max_batch_tokens,
+            embedding_cache: self.embedding_cache.clone(),
+            session_pool_size: self.session_pool_size,
+            session_options: self.session_options.clone(),
         }
     }
 }
@@ -106,6 +131,70 @@ impl InitOptions {
         self.show_download_progress = show_download_progress;
         self
     }
+
+    /// Truncate the embeddings to the given dimension before normalizing them
+    ///
+    /// Only supported by models trained with Matryoshka Representation Learning, and only
+    /// for the dimensions listed in [`ModelInfo::matryoshka_dims`](crate::ModelInfo::matryoshka_dims).
+    pub fn with_truncate_dim(mut self, truncate_dim: usize) -> Self {
+        self.truncate_dim = Some(truncate_dim);
+        self
+    }
+
+    /// Set whether to L2 normalize the embeddings, defaults to `true`
+    pub fn with_normalize(mut self, normalize: bool) -> Self {
+        self.normalize = normalize;
+        self
+    }
+
+    /// Set whether to sort the inputs by token length before batching them, defaults to `false`
+    ///
+    /// Batching inputs of similar length reduces the compute spent on padding. The outputs
+    /// are returned in the original order.
+    ///
+    /// Sorting tokenizes all the inputs up front, so their encodings are held in memory at
+    /// once rather than one batch at a time.
+    pub fn with_sort_by_length(mut self, sort_by_length: bool) -> Self {
+        self.sort_by_length = sort_by_length;
+        self
+    }
+
+    /// Limit each batch to the given number of padded tokens, that is its number of inputs
+    /// times the token length of its longest input
+    ///
+    /// Batches are formed greedily and are still limited to `batch_size` inputs. An input
+    /// longer than the budget is run in a batch of its own.
+    ///
+    /// Like sorting, this tokenizes all the inputs up front.
+    pub fn with_max_batch_tokens(mut self, max_batch_tokens: usize) -> Self {
+        self.max_batch_tokens = Some(max_batch_tokens);
+        self
+    }
+
+    /// Cache the computed embeddings, so that [`TextEmbedding::embed`] only runs the model
+    /// on texts it has not embedded before
+    ///
+    /// Persistent caches are stored under the `cache_dir`.
+    pub fn with_embedding_cache(mut self, embedding_cache: EmbeddingCacheOptions) -> Self {
+        self.embedding_cache = Some(embedding_cache);
+        self
+    }
+
+    /// Create the given number of ONNX sessions, each with an equal share of the threads,
+    /// and run each batch on whichever is free. Defaults to `1`
+    ///
+    /// This increases the throughput when several threads share the model, at the cost of
+    /// loading the model once per session.
+    pub fn with_session_pool_size(mut self, session_pool_size: usize) -> Self {
+        self.session_pool_size = session_pool_size;
+        self
+    }
+
+    /// Set the configuration of the ONNX Runtime sessions, such as the number of threads
+    pub fn with_session_options(mut self, session_options: SessionOptions) -> Self {
+        self.session_options = session_options;
+        self
+    }
 }
 
 impl Default for InitOptions {
@@ -117,6 +206,13 @@ impl Default for InitOptions {
             cache_dir: Path::new(DEFAULT_CACHE_DIR).to_path_buf(),
             show_download_progress: true,
             custom_progress: None,
+            truncate_dim: None,
+            normalize: true,
+            sort_by_length: false,
+            max_batch_tokens: None,
+            embedding_cache: None,
+            session_pool_size: 1,
+            session_options: Default::default(),
         }
     }
 }
@@ -129,6 +225,13 @@ impl Default for InitOptions {
 pub struct InitOptionsUserDefined {
     pub execution_providers: Vec<ExecutionProviderDispatch>,
     pub max_length: usize,
+    pub cache_dir: PathBuf,
+    pub normalize: bool,
+    pub sort_by_length: bool,
+    pub max_batch_tokens: Option<usize>,
+    pub embedding_cache: Option<EmbeddingCacheOptions>,
+    pub session_pool_size: usize,
+    pub session_options: SessionOptions,
 }
 
 impl InitOptionsUserDefined {
@@ -150,6 +253,68 @@ impl InitOptionsUserDefined {
         self.max_length = max_length;
         self
     }
+
+    /// Set the directory persistent embedding caches are stored under
+    pub fn with_cache_dir(mut self, cache_dir: PathBuf) -> Self {
+        self.cache_dir = cache_dir;
+        self
+    }
+
+    /// Set whether to L2 normalize the embeddings, defaults to `true`
+    pub fn with_normalize(mut self, normalize: bool) -> Self {
+        self.normalize = normalize;
+        self
+    }
+
+    /// Set whether to sort the inputs by token length before batching them, defaults to `false`
+    ///
+    /// Batching inputs of similar length reduces the compute spent on padding. The outputs
+    /// are returned in the original order.
+    ///
+    /// Sorting tokenizes all the inputs up front, so their encodings are held in memory at
+    /// once rather than one batch at a time.
+    pub fn with_sort_by_length(mut self, sort_by_length: bool) -> Self {
+        self.sort_by_length = sort_by_length;
+        self
+    }
+
+    /// Limit each batch to the given number of padded tokens, that is its number of inputs
+    /// times the token length of its longest input
+    ///
+    /// Batches are formed greedily and are still limited to `batch_size` inputs. An input
+    /// longer than the budget is run in a batch of its own.
+    ///
+    /// Like sorting, this tokenizes all the inputs up front.
+    pub fn with_max_batch_tokens(mut self, max_batch_tokens: usize) -> Self {
+        self.max_batch_tokens = Some(max_batch_tokens);
+        self
+    }
+
+    /// Cache the computed embeddings, so that [`TextEmbedding::embed`] only runs the model
+    /// on texts it has not embedded before
+    ///
+    /// The model is identified by a hash of its files. Persistent caches are stored under the
+    /// `cache_dir`.
+    pub fn with_embedding_cache(mut self, embedding_cache: EmbeddingCacheOptions) -> Self {
+        self.embedding_cache = Some(embedding_cache);
+        self
+    }
+
+    /// Create the given number of ONNX sessions, each with an equal share of the threads,
+    /// and run each batch on whichever is free. Defaults to `1`
+    ///
+    /// This increases the throughput when several threads share the model, at the cost of
+    /// loading the model once per session.
+    pub fn with_session_pool_size(mut self, session_pool_size: usize) -> Self {
+        self.session_pool_size = session_pool_size;
+        self
+    }
+
+    /// Set the configuration of the ONNX Runtime sessions, such as the number of threads
+    pub fn with_session_options(mut self, session_options: SessionOptions) -> Self {
+        self.session_options = session_options;
+        self
+    }
 }
 
 impl Default for InitOptionsUserDefined {
@@ -157,6 +322,13 @@ impl Default for InitOptionsUserDefined {
         Self {
             execution_providers: Default::default(),
             max_length: DEFAULT_MAX_LENGTH,
+            cache_dir: Path::new(DEFAULT_CACHE_DIR).to_path_buf(),
+            normalize: true,
+            sort_by_length: false,
+            max_batch_tokens: None,
+            embedding_cache: None,
+            session_pool_size: 1,
+            session_options: Default::default(),
         }
     }
 }
@@ -169,6 +341,13 @@ impl From<InitOptions> for InitOptionsUserDefined {
         InitOptionsUserDefined {
             execution_providers: options.execution_providers,
             max_length: options.max_length,
+            cache_dir: options.cache_dir,
+            normalize: options.normalize,
+            sort_by_length: options.sort_by_length,
+            max_batch_tokens: options.max_batch_tokens,
+            embedding_cache: options.embedding_cache,
+            session_pool_size: options.session_pool_size,
+            session_options: options.session_options,
         }
     }
 }
@@ -183,6 +362,8 @@ pub struct UserDefinedEmbeddingModel {
     pub tokenizer_files: TokenizerFiles,
     pub pooling: Option<Pooling>,
     pub quantization: QuantizationMode,
+    pub padding_side: Option<PaddingSide>,
+    pub prefixes: TaskPrefixes,
 }
 
 impl UserDefinedEmbeddingModel {
@@ -192,6 +373,8 @@ impl UserDefinedEmbeddingModel {
             tokenizer_files,
             quantization: QuantizationMode::None,
             pooling: None,
+            padding_side: None,
+            prefixes: TaskPrefixes::default(),
         }
     }
     
@@ -204,13 +387,38 @@ impl UserDefinedEmbeddingModel {
         self.pooling = Some(pooling);
         self
     }
+
+    /// Set the side on which the tokenizer pads the inputs.
+    ///
+    /// Defaults to the `padding_side` declared in `tokenizer_config.json`, or right padding.
+    /// Decoder-based models pooled with [`Pooling::LastToken`] typically expect left padding.
+    pub fn with_padding_side(mut self, padding_side: PaddingSide) -> Self {
+        self.padding_side = Some(padding_side);
+        self
+    }
+
+    /// Set the prefixes the model expects in front of its inputs for each task.
+    ///
+    /// These are used by [`TextEmbedding::embed_query`], [`TextEmbedding::embed_documents`]
+    /// and [`TextEmbedding::embed_for_task`]. Defaults to no prefixes.
+    pub fn with_prefixes(mut self, prefixes: TaskPrefixes) -> Self {
+        self.prefixes = prefixes;
+        self
+    }
 }
 
 /// Rust representation of the TextEmbedding model
 pub struct TextEmbedding {
     pub tokenizer: Tokenizer,
     pub(crate) pooling: Option<Pooling>,
-    pub(crate) session: Session,
+    pub(crate) sessions: SessionPool,
     pub(crate) need_token_type_ids: bool,
     pub(crate) quantization: QuantizationMode,
+    pub(crate) truncate_dim: Option<usize>,
+    pub(crate) matryoshka_layer_norm: bool,
+    pub(crate) normalize: bool,
+    pub(crate) sort_by_length: bool,
+    pub(crate) max_batch_tokens: Option<usize>,
+    pub(crate) prefixes: TaskPrefixes,
+    pub(crate) cache: Option<EmbeddingCache<Embedding>>,
 }
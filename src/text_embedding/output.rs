@@ -1,7 +1,9 @@
 //! Output types and functions for the [`TextEmbedding`] model.
 //!
+use ndarray::{Axis, Ix3};
+
 use crate::{
-    common::{normalize, Embedding},
+    common::{normalize, Embedding, TokenEmbeddings},
     output::{OutputKey, OutputPrecedence, SingleBatchOutput},
     pooling::Pooling,
 };
@@ -18,15 +20,86 @@ pub const OUTPUT_TYPE_PRECEDENCE: &[OutputKey] = &[
     // OutputKey::ByName("token_embeddings"),
 ];
 
+/// The default output precedence for token-level embeddings of the TextEmbedding model.
+///
+/// Unlike [`OUTPUT_TYPE_PRECEDENCE`], this requires an un-pooled output.
+pub const TOKEN_OUTPUT_TYPE_PRECEDENCE: &[OutputKey] = &[
+    OutputKey::ByName("last_hidden_state"),
+    OutputKey::ByName("token_embeddings"),
+    OutputKey::OnlyOne,
+];
+
 /// Generates thea default array transformer for the [`TextEmbedding`] model using the
 /// provided output precedence.
 ///
 // TODO (denwong47): now that pooling is done in SingleBatchOutput, it is possible that
 // all the models will use this same generic transformer. Move this into SingleBatchOutput?
-#[allow(unused_variables)]
 pub fn transformer_with_precedence(
     output_precedence: impl OutputPrecedence,
     pooling: Option<Pooling>,
+) -> impl Fn(&[SingleBatchOutput]) -> anyhow::Result<Vec<Embedding>> {
+    pooled_transformer(output_precedence, pooling, None, false, true)
+}
+
+/// Generates an array transformer for the [`TextEmbedding`] model using the provided
+/// output precedence, which returns the pooled embeddings without L2 normalization.
+///
+/// This is useful for models trained with dot-product similarity, or when the norm of the
+/// embeddings is of interest.
+pub fn raw_transformer_with_precedence(
+    output_precedence: impl OutputPrecedence,
+    pooling: Option<Pooling>,
+) -> impl Fn(&[SingleBatchOutput]) -> anyhow::Result<Vec<Embedding>> {
+    pooled_transformer(output_precedence, pooling, None, false, false)
+}
+
+/// Generates an array transformer for the [`TextEmbedding`] model using the provided
+/// output precedence, which truncates the pooled embeddings to `dim` dimensions
+/// before normalizing them.
+///
+/// This is only meaningful for models trained with Matryoshka Representation Learning,
+/// see [`ModelInfo::matryoshka_dims`](crate::ModelInfo::matryoshka_dims). Models such as
+/// nomic-embed-text-v1.5 also expect a layer norm before truncating, which is applied by
+/// [`TextEmbedding`] when created with a `truncate_dim` but not by this transformer.
+pub fn truncated_transformer_with_precedence(
+    output_precedence: impl OutputPrecedence,
+    pooling: Option<Pooling>,
+    dim: usize,
+) -> impl Fn(&[SingleBatchOutput]) -> anyhow::Result<Vec<Embedding>> {
+    pooled_transformer(output_precedence, pooling, Some(dim), false, true)
+}
+
+/// Generates an array transformer for the [`TextEmbedding`] model using the provided
+/// output precedence, which converts the normalized embeddings with `quantize`.
+///
+/// This is meant for the compact formats, e.g. with
+/// [`BinaryEmbedding::from_embedding`](crate::BinaryEmbedding::from_embedding) or
+/// [`ScalarRange::quantize_int8`](crate::ScalarRange::quantize_int8).
+pub fn quantized_transformer_with_precedence<T>(
+    output_precedence: impl OutputPrecedence,
+    pooling: Option<Pooling>,
+    quantize: impl Fn(&[f32]) -> T,
+) -> impl Fn(&[SingleBatchOutput]) -> anyhow::Result<Vec<T>> {
+    let transformer = transformer_with_precedence(output_precedence, pooling);
+    move |batches| {
+        Ok(transformer(batches)?
+            .iter()
+            .map(|embedding| quantize(embedding))
+            .collect())
+    }
+}
+
+/// Generates an array transformer that pools, optionally truncates to `truncate_dim`
+/// dimensions and optionally normalizes the embeddings, in that order.
+///
+/// If `layer_norm` is set, the pooled embeddings also go through a layer norm before being
+/// truncated, as some Matryoshka models expect. It has no effect without `truncate_dim`.
+pub(crate) fn pooled_transformer(
+    output_precedence: impl OutputPrecedence,
+    pooling: Option<Pooling>,
+    truncate_dim: Option<usize>,
+    layer_norm: bool,
+    normalize_output: bool,
 ) -> impl Fn(&[SingleBatchOutput]) -> anyhow::Result<Vec<Embedding>> {
     move |batches| {
         // Not using `par_iter` here: the operations here is probably not
@@ -36,12 +109,32 @@ pub fn transformer_with_precedence(
             .map(|batch| {
                 batch
                     .select_and_pool_output(&output_precedence, pooling.clone())
-                    .map(|array| {
-                        array
+                    .and_then(|array| {
+                        let dim = truncate_dim.unwrap_or(array.ncols());
+                        if dim > array.ncols() {
+                            return Err(anyhow::Error::msg(format!(
+                                "Cannot truncate embeddings of dimension {} to {dim}.",
+                                array.ncols()
+                            )));
+                        }
+
+                        Ok(array
                             .rows()
                             .into_iter()
-                            .map(|row| normalize(row.as_slice().unwrap()))
-                            .collect::<Vec<Embedding>>()
+                            .map(|row| {
+                                let mut row = if layer_norm && truncate_dim.is_some() {
+                                    self::layer_norm(&row.to_vec())
+                                } else {
+                                    row.to_vec()
+                                };
+                                row.truncate(dim);
+                                if normalize_output {
+                                    normalize(&row)
+                                } else {
+                                    row
+                                }
+                            })
+                            .collect::<Vec<Embedding>>())
                     })
             })
             .try_fold(Vec::new(), |mut acc, res| {
@@ -50,3 +143,62 @@ pub fn transformer_with_precedence(
             })
     }
 }
+
+/// Layer norm without learned parameters, i.e. shift and scale the embedding to a mean of 0
+/// and a variance of 1.
+fn layer_norm(embedding: &[f32]) -> Embedding {
+    const EPSILON: f32 = 1e-5;
+
+    let len = embedding.len() as f32;
+    let mean = embedding.iter().sum::<f32>() / len;
+    let variance = embedding.iter().map(|x| (x - mean).powi(2)).sum::<f32>() / len;
+    let scale = (variance + EPSILON).sqrt();
+    embedding.iter().map(|x| (x - mean) / scale).collect()
+}
+
+/// Generates the default token-level array transformer for the [`TextEmbedding`] model
+/// using the provided output precedence.
+///
+/// The selected output must be a `(batch_size, sequence_length, hidden_size)` tensor.
+/// Padding tokens are removed, and the token embeddings are not normalized.
+pub fn token_transformer_with_precedence(
+    output_precedence: impl OutputPrecedence,
+) -> impl Fn(&[SingleBatchOutput]) -> anyhow::Result<Vec<TokenEmbeddings>> {
+    move |batches| {
+        batches
+            .iter()
+            .map(|batch| {
+                let tensor = batch.select_output(&output_precedence)?;
+                let shape = tensor.shape().to_vec();
+                let tensor = tensor.into_dimensionality::<Ix3>().map_err(|_| {
+                    anyhow::Error::msg(format!(
+                        "Invalid output shape: {shape:?}. Expected a 3D tensor for token embeddings."
+                    ))
+                })?;
+
+                Ok(tensor
+                    .axis_iter(Axis(0))
+                    .zip(&batch.encodings)
+                    .map(|(tokens, encoding)| {
+                        let (embeddings, (token_ids, offsets)) = tokens
+                            .axis_iter(Axis(0))
+                            .zip(encoding.get_attention_mask())
+                            .zip(encoding.get_ids().iter().zip(encoding.get_offsets()))
+                            .filter(|((_, &mask), _)| mask != 0)
+                            .map(|((token, _), (&id, &offset))| (token.to_vec(), (id, offset)))
+                            .unzip();
+
+                        TokenEmbeddings {
+                            embeddings,
+                            token_ids,
+                            offsets,
+                        }
+                    })
+                    .collect::<Vec<_>>())
+            })
+            .try_fold(Vec::new(), |mut acc, res: anyhow::Result<Vec<_>>| {
+                acc.extend(res?);
+                Ok(acc)
+            })
+    }
+}
@@ -0,0 +1,95 @@
+#![cfg(feature = "hf-hub")]
+#![cfg(feature = "async")]
+
+use std::sync::Arc;
+
+use fastembed::{
+    EmbeddingModel, InitOptions, RerankInitOptions, RerankerModel, TextEmbedding, TextRerank,
+};
+
+fn block_on<F: std::future::Future>(future: F) -> F::Output {
+    tokio::runtime::Builder::new_current_thread()
+        .build()
+        .expect("Failed to build the runtime")
+        .block_on(future)
+}
+
+#[test]
+fn test_embed_async_matches_embed() {
+    let model =
+        Arc::new(TextEmbedding::try_new(InitOptions::new(EmbeddingModel::AllMiniLML6V2)).unwrap());
+    let documents = vec![
+        "Hello, World!",
+        "This is an example passage.",
+        "fastembed-rs is licensed under Apache-2.0",
+        "Some other short text here blah blah blah",
+    ];
+
+    let expected = model.embed(documents.clone(), Some(3)).unwrap();
+    let embeddings = block_on(model.clone().embed_async(documents, Some(3))).unwrap();
+
+    assert_eq!(embeddings.len(), expected.len());
+    for (a, b) in embeddings.iter().zip(&expected) {
+        for (x, y) in a.iter().zip(b) {
+            assert!((x - y).abs() < 1e-5);
+        }
+    }
+}
+
+#[test]
+fn test_rerank_async_matches_rerank() {
+    let model = Arc::new(
+        TextRerank::try_new(RerankInitOptions::new(RerankerModel::BGERerankerBase)).unwrap(),
+    );
+    let documents = vec![
+        "hi",
+        "The giant panda (Ailuropoda melanoleuca), sometimes called a panda bear, is a bear species endemic to China.",
+        "panda is animal",
+        "i dont know",
+        "kind of mammal",
+    ];
+
+    let expected = model
+        .rerank("what is panda?", documents.clone(), true, Some(2))
+        .unwrap();
+    let results = block_on(
+        model
+            .clone()
+            .rerank_async("what is panda?", documents, true, Some(2)),
+    )
+    .unwrap();
+
+    assert_eq!(results.len(), expected.len());
+    for (a, b) in results.iter().zip(&expected) {
+        assert_eq!(a.index, b.index);
+        assert_eq!(a.document, b.document);
+        assert!((a.score - b.score).abs() < 1e-5);
+    }
+}
+
+#[test]
+fn test_embed_stream() {
+    let model =
+        Arc::new(TextEmbedding::try_new(InitOptions::new(EmbeddingModel::AllMiniLML6V2)).unwrap());
+    let documents = (0..10).map(|i| format!("Document number {i}"));
+    let expected = model.embed(documents.clone().collect(), None).unwrap();
+
+    let embeddings = block_on(async {
+        let mut receiver = model.clone().embed_stream(documents, Some(3));
+        let mut embeddings = Vec::new();
+        while let Some(result) = receiver.recv().await {
+            embeddings.push(result.unwrap());
+        }
+        embeddings
+    });
+
+    assert_eq!(embeddings.len(), expected.len());
+    for ((index, embedding), (expected_index, expected)) in
+        embeddings.iter().zip(expected.iter().enumerate())
+    {
+        assert_eq!(*index, expected_index);
+        for (a, b) in embedding.iter().zip(expected) {
+            assert!((a - b).abs() < 1e-5);
+        }
+    }
+}
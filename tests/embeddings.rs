@@ -2,16 +2,32 @@
 
 use std::fs;
 use std::path::Path;
+use std::sync::Arc;
 
 use hf_hub::Repo;
+use ndarray::{s, Array2, ArrayView, Dim, IxDynImpl};
+use ort::{
+    execution_providers::CPUExecutionProvider,
+    memory::{AllocationDevice, AllocatorType, MemoryInfo, MemoryType},
+};
 use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+use serde_json::json;
 
 use fastembed::{
-    read_file_to_bytes, Embedding, EmbeddingModel, ImageEmbedding, ImageEmbeddingModel,
-    ImageInitOptions, InitOptions, InitOptionsUserDefined, ModelInfo, OnnxSource, Pooling,
+    f16_dot, from_f16,
+    index::{Filter, HnswOptions, IndexOptions, Payload, VectorIndex},
+    read_file_to_bytes,
+    similarity::{self, Matrix, Metric},
+    text_embedding::output,
+    BinaryEmbedding, CancellationToken, Cancelled, CustomPooling, EmbedControl, Embedding,
+    EmbeddingCacheOptions, EmbeddingModel, EmbeddingTask, ImageEmbedding, ImageEmbeddingModel,
+    ImageInitOptions, InitOptions, InitOptionsUserDefined, LateInteractionInitOptions,
+    LateInteractionTextEmbedding, ModelInfo, OnnxSource, OptimizationLevel, PaddingSide, Pooling,
     QuantizationMode, RerankInitOptions, RerankInitOptionsUserDefined, RerankerModel,
-    RerankerModelInfo, SparseInitOptions, SparseTextEmbedding, TextEmbedding, TextRerank,
-    TokenizerFiles, UserDefinedEmbeddingModel, UserDefinedRerankingModel, DEFAULT_CACHE_DIR,
+    RerankerModelInfo, ScalarRange, SessionOptions, SparseInitOptions,
+    SparseInitOptionsUserDefined, SparseModel, SparseTextEmbedding, TaskPrefixes, TextEmbedding,
+    TextRerank, TokenizerFiles, TruncationReport, UserDefinedEmbeddingModel,
+    UserDefinedRerankingModel, UserDefinedSparseModel, WindowAggregation, DEFAULT_CACHE_DIR,
 };
 
 /// A small epsilon value for floating point comparisons.
@@ -95,6 +111,17 @@ fn verify_embeddings(model: &EmbeddingModel, embeddings: &[Embedding]) -> Result
     }
 }
 
+/// Assert that the embeddings are the expected ones, up to the tolerance
+fn assert_embeddings_eq(embeddings: &[Embedding], expected: &[Embedding], tolerance: f32) {
+    assert_eq!(embeddings.len(), expected.len());
+    for (embedding, expected) in embeddings.iter().zip(expected) {
+        assert_eq!(embedding.len(), expected.len());
+        for (x, y) in embedding.iter().zip(expected) {
+            assert!((x - y).abs() < tolerance, "{x} != {y}");
+        }
+    }
+}
+
 macro_rules! create_embeddings_test {
     (
         name: $name:ident,
@@ -166,6 +193,1100 @@ create_embeddings_test!(
     batch_size: Some(3),
 );
 
+#[test]
+fn test_matryoshka_truncation() {
+    let documents = vec!["Hello, World!", "This is an example passage."];
+
+    let full = TextEmbedding::try_new(
+        InitOptions::new(EmbeddingModel::NomicEmbedTextV15).with_normalize(false),
+    )
+    .unwrap()
+    .embed(documents.clone(), None)
+    .unwrap();
+    let truncated = TextEmbedding::try_new(
+        InitOptions::new(EmbeddingModel::NomicEmbedTextV15).with_truncate_dim(256),
+    )
+    .unwrap()
+    .embed(documents, None)
+    .unwrap();
+
+    for (full, truncated) in full.iter().zip(&truncated) {
+        assert_eq!(truncated.len(), 256);
+
+        // Nomic embeddings go through a layer norm, then are truncated and normalized
+        let mean = full.iter().sum::<f32>() / full.len() as f32;
+        let variance = full.iter().map(|v| (v - mean).powi(2)).sum::<f32>() / full.len() as f32;
+        let layer_norm = full[..256]
+            .iter()
+            .map(|v| (v - mean) / (variance + 1e-5).sqrt())
+            .collect::<Vec<_>>();
+        let norm = layer_norm.iter().map(|v| v * v).sum::<f32>().sqrt();
+        for (a, b) in layer_norm.iter().zip(truncated) {
+            assert!((a / norm - b).abs() < 1e-5);
+        }
+    }
+
+    assert!(TextEmbedding::try_new(
+        InitOptions::new(EmbeddingModel::AllMiniLML6V2).with_truncate_dim(256)
+    )
+    .is_err());
+}
+
+#[test]
+fn test_windowed_embeddings() {
+    let model =
+        TextEmbedding::try_new(InitOptions::new(EmbeddingModel::AllMiniLML6V2).with_max_length(32))
+            .unwrap();
+
+    let long_document = "The giant panda is a bear species endemic to China. ".repeat(20);
+    let documents = vec!["Hello, World!", long_document.as_str()];
+
+    let windowed = model
+        .embed_windowed(documents.clone(), 8, WindowAggregation::All, Some(3))
+        .unwrap();
+    assert_eq!(windowed.len(), documents.len());
+
+    // A short text fits in a single window, equal to its regular embedding
+    let regular = model.embed(vec![documents[0]], None).unwrap();
+    assert_eq!(windowed[0].embeddings.len(), 1);
+    assert_eq!(windowed[0].window_offsets, vec![(0, documents[0].len())]);
+    for (a, b) in windowed[0].embeddings[0].iter().zip(&regular[0]) {
+        assert!((a - b).abs() < 1e-5);
+    }
+
+    // Windows overlap and together cover the whole long document
+    let long = &windowed[1];
+    assert!(long.embeddings.len() > 1);
+    assert_eq!(long.embeddings.len(), long.window_offsets.len());
+    assert_eq!(long.window_offsets[0].0, 0);
+    assert_eq!(
+        long.window_offsets.last().unwrap().1,
+        long_document.trim_end().len()
+    );
+    for pair in long.window_offsets.windows(2) {
+        assert!(pair[1].0 < pair[0].1);
+    }
+
+    for aggregation in [WindowAggregation::Mean, WindowAggregation::Max] {
+        let aggregated = model
+            .embed_windowed(documents.clone(), 8, aggregation, None)
+            .unwrap();
+        assert_eq!(aggregated[1].embeddings.len(), 1);
+        assert_eq!(aggregated[1].window_offsets, long.window_offsets);
+        let norm = aggregated[1].embeddings[0]
+            .iter()
+            .map(|v| v * v)
+            .sum::<f32>()
+            .sqrt();
+        assert!((norm - 1.0).abs() < 1e-5);
+    }
+
+    // Windows of 30 tokens besides [CLS] and [SEP] cannot overlap by 30 tokens
+    assert!(model
+        .embed_windowed(documents.clone(), 29, WindowAggregation::All, None)
+        .is_ok());
+    assert!(model
+        .embed_windowed(documents.clone(), 30, WindowAggregation::All, None)
+        .is_err());
+
+    // Windows are batched by length and token budget, and cached like texts
+    let model = TextEmbedding::try_new(
+        InitOptions::new(EmbeddingModel::AllMiniLML6V2)
+            .with_max_length(32)
+            .with_sort_by_length(true)
+            .with_max_batch_tokens(64)
+            .with_embedding_cache(EmbeddingCacheOptions::in_memory(100)),
+    )
+    .unwrap();
+    for _ in 0..2 {
+        let batched = model
+            .embed_windowed(documents.clone(), 8, WindowAggregation::All, Some(3))
+            .unwrap();
+        for (batched, windowed) in batched.iter().zip(&windowed) {
+            assert_eq!(batched.window_offsets, windowed.window_offsets);
+            assert_embeddings_eq(&batched.embeddings, &windowed.embeddings, 1e-5);
+        }
+    }
+
+    // Progress counts the windows, and cancellation stops before the first batch
+    let progress = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let control = EmbedControl::new().with_progress({
+        let progress = progress.clone();
+        move |update| progress.lock().unwrap().push(update)
+    });
+    let model =
+        TextEmbedding::try_new(InitOptions::new(EmbeddingModel::AllMiniLML6V2).with_max_length(32))
+            .unwrap();
+    model
+        .embed_windowed_with_control(
+            documents.clone(),
+            8,
+            WindowAggregation::Mean,
+            Some(3),
+            &control,
+        )
+        .unwrap();
+    let windows = windowed.iter().map(|w| w.embeddings.len()).sum::<usize>();
+    assert_eq!(progress.lock().unwrap().last().unwrap().items_done, windows);
+
+    let cancellation = CancellationToken::new();
+    cancellation.cancel();
+    let err = model
+        .embed_windowed_with_control(
+            documents,
+            8,
+            WindowAggregation::All,
+            None,
+            &EmbedControl::new().with_cancellation(cancellation),
+        )
+        .unwrap_err();
+    assert!(err.is::<Cancelled>());
+}
+
+#[test]
+fn test_truncation_report() {
+    let long_document = "The giant panda is a bear species endemic to China. ".repeat(5);
+    let documents = vec!["Hello, World!", long_document.as_str()];
+    let assert_report = |report: &[TruncationReport]| {
+        assert_eq!(report.len(), documents.len());
+        assert!(!report[0].truncated);
+        assert!(report[0].token_count <= 16);
+        assert!(report[1].truncated);
+        assert!(report[1].token_count > 16);
+    };
+
+    let model =
+        TextEmbedding::try_new(InitOptions::new(EmbeddingModel::AllMiniLML6V2).with_max_length(16))
+            .unwrap();
+    let (embeddings, report) = model.embed_with_report(documents.clone(), None).unwrap();
+    assert_eq!(embeddings.len(), documents.len());
+    assert_report(&report);
+
+    let model =
+        SparseTextEmbedding::try_new(SparseInitOptions::default().with_max_length(16)).unwrap();
+    let (embeddings, report) = model.embed_with_report(documents.clone(), None).unwrap();
+    assert_eq!(embeddings.len(), documents.len());
+    assert_report(&report);
+
+    let model = TextRerank::try_new(RerankInitOptions::default().with_max_length(16)).unwrap();
+    let (results, report) = model
+        .rerank_with_report("what is panda?", documents.clone(), false, None)
+        .unwrap();
+    assert_eq!(results.len(), documents.len());
+    assert_report(&report);
+}
+
+#[test]
+fn test_tokenize() {
+    let long_document = "The giant panda is a bear species endemic to China. ".repeat(5);
+    let documents = vec!["Hello, World!", long_document.as_str()];
+
+    let model =
+        TextEmbedding::try_new(InitOptions::new(EmbeddingModel::AllMiniLML6V2).with_max_length(16))
+            .unwrap();
+    let tokenized = model.tokenize(documents.clone()).unwrap();
+    let counts = model.count_tokens(documents.clone()).unwrap();
+    assert_eq!(tokenized.len(), documents.len());
+    for (tokens, count) in tokenized.iter().zip(&counts) {
+        assert_eq!(tokens.truncation.token_count, *count);
+        assert_eq!(tokens.ids.len(), tokens.special_tokens_mask.len());
+        assert_eq!(tokens.ids.len(), tokens.offsets.len());
+        assert!(tokens.ids.len() <= 16);
+        // [CLS] and [SEP]
+        assert_eq!(tokens.special_tokens_mask.iter().sum::<u32>(), 2);
+    }
+    // Not padded to the longest input
+    assert_eq!(tokenized[0].ids.len(), counts[0]);
+    assert!(!tokenized[0].truncation.truncated);
+    assert!(tokenized[1].truncation.truncated);
+    assert!(counts[1] > 16);
+
+    let model = TextRerank::try_new(RerankInitOptions::default().with_max_length(16)).unwrap();
+    let tokenized = model.tokenize("what is panda?", documents.clone()).unwrap();
+    let counts = model
+        .count_tokens("what is panda?", documents.clone())
+        .unwrap();
+    assert_eq!(tokenized.len(), counts.len());
+    assert!(!tokenized[0].truncation.truncated);
+    assert_eq!(tokenized[0].ids.len(), counts[0]);
+    assert!(tokenized[1].truncation.truncated);
+    assert_eq!(tokenized[1].ids.len(), 16);
+
+    // Offsets are byte offsets into the query or the document, told apart by sequence id
+    let tokens = &tokenized[0];
+    for (&(start, end), sequence_id) in tokens.offsets.iter().zip(&tokens.sequence_ids) {
+        match sequence_id {
+            Some(0) => assert!("what is panda?".get(start..end).is_some()),
+            Some(1) => assert!(documents[0].get(start..end).is_some()),
+            _ => assert_eq!(start, end),
+        }
+    }
+    assert!(tokens.sequence_ids.contains(&Some(1)));
+}
+
+#[test]
+fn test_embed_progress_and_cancellation() {
+    let documents = vec![
+        "Hello, World!",
+        "This is an example passage.",
+        "fastembed-rs is licensed under Apache-2.0",
+        "Some other short text here blah blah blah",
+        "Yet another text",
+    ];
+
+    let progress = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let control = EmbedControl::new().with_progress({
+        let progress = progress.clone();
+        move |update| progress.lock().unwrap().push(update)
+    });
+
+    let model = TextEmbedding::try_new(InitOptions::new(EmbeddingModel::AllMiniLML6V2)).unwrap();
+    let embeddings = model
+        .embed_with_control(documents.clone(), Some(2), &control)
+        .unwrap();
+    assert_eq!(embeddings, model.embed(documents.clone(), Some(2)).unwrap());
+
+    let progress = progress.lock().unwrap();
+    assert_eq!(progress.len(), 3);
+    for (i, update) in progress.iter().enumerate() {
+        assert_eq!(update.batches_done, i + 1);
+        assert_eq!(update.total_batches, 3);
+        assert_eq!(update.total_items, documents.len());
+    }
+    assert_eq!(progress[2].items_done, documents.len());
+
+    let cancellation = CancellationToken::new();
+    let control = EmbedControl::new().with_cancellation(cancellation.clone());
+    cancellation.cancel();
+
+    let err = model
+        .embed_with_control(documents.clone(), Some(2), &control)
+        .unwrap_err();
+    assert!(err.is::<Cancelled>());
+
+    let model = TextRerank::try_new(RerankInitOptions::default()).unwrap();
+    let err = model
+        .rerank_with_control("hello", documents, false, None, &control)
+        .unwrap_err();
+    assert!(err.is::<Cancelled>());
+}
+
+#[test]
+fn test_session_pool() {
+    let documents = vec![
+        "Hello, World!",
+        "This is an example passage.",
+        "fastembed-rs is licensed under Apache-2.0",
+        "Some other short text here blah blah blah",
+    ];
+    let expected = TextEmbedding::try_new(InitOptions::new(EmbeddingModel::AllMiniLML6V2))
+        .unwrap()
+        .embed(documents.clone(), Some(1))
+        .unwrap();
+
+    assert!(TextEmbedding::try_new(
+        InitOptions::new(EmbeddingModel::AllMiniLML6V2).with_session_pool_size(0)
+    )
+    .is_err());
+
+    let model = TextEmbedding::try_new(
+        InitOptions::new(EmbeddingModel::AllMiniLML6V2).with_session_pool_size(2),
+    )
+    .unwrap();
+
+    // Several threads sharing the model, each running several batches at once
+    std::thread::scope(|scope| {
+        for _ in 0..3 {
+            scope.spawn(|| {
+                let embeddings = model.embed(documents.clone(), Some(1)).unwrap();
+                assert_embeddings_eq(&embeddings, &expected, 1e-5);
+            });
+        }
+    });
+
+    let model =
+        TextRerank::try_new(RerankInitOptions::default().with_session_pool_size(2)).unwrap();
+    let results = model
+        .rerank("hello", documents.clone(), false, Some(1))
+        .unwrap();
+    assert_eq!(results.len(), documents.len());
+
+    let expected = LateInteractionTextEmbedding::try_new(LateInteractionInitOptions::default())
+        .unwrap()
+        .embed(documents.clone(), Some(1))
+        .unwrap();
+    let model = LateInteractionTextEmbedding::try_new(
+        LateInteractionInitOptions::default().with_session_pool_size(2),
+    )
+    .unwrap();
+    let embeddings = model.embed(documents.clone(), Some(1)).unwrap();
+    assert_eq!(embeddings.len(), expected.len());
+    for (embeddings, expected) in embeddings.iter().zip(&expected) {
+        assert_embeddings_eq(embeddings, expected, 1e-5);
+    }
+}
+
+#[test]
+fn test_session_options() {
+    let documents = vec!["Hello, World!", "This is an example passage."];
+    let expected = TextEmbedding::try_new(InitOptions::new(EmbeddingModel::AllMiniLML6V2))
+        .unwrap()
+        .embed(documents.clone(), None)
+        .unwrap();
+
+    let session_options = SessionOptions::new()
+        .with_intra_threads(2)
+        .with_inter_threads(1)
+        .with_optimization_level(OptimizationLevel::Level1)
+        .with_parallel_execution(true)
+        .with_cpu_arena(false)
+        .with_memory_pattern(false)
+        .with_config_entry("session.intra_op.allow_spinning", "0");
+    let model = TextEmbedding::try_new(
+        InitOptions::new(EmbeddingModel::AllMiniLML6V2)
+            .with_session_options(session_options.clone()),
+    )
+    .unwrap();
+    let embeddings = model.embed(documents.clone(), None).unwrap();
+    assert_embeddings_eq(&embeddings, &expected, 1e-4);
+
+    let model =
+        TextRerank::try_new(RerankInitOptions::default().with_session_options(session_options))
+            .unwrap();
+    let results = model
+        .rerank("hello", documents.clone(), false, None)
+        .unwrap();
+    assert_eq!(results.len(), documents.len());
+
+    // The arena setting reaches the CPU allocator of the session, overriding the one of a CPU
+    // provider given by the caller
+    let (onnx_file, _) = user_defined_model_files(&EmbeddingModel::AllMiniLML6V2);
+    for execution_providers in [vec![], vec![CPUExecutionProvider::default().build()]] {
+        for cpu_arena in [false, true] {
+            let info = MemoryInfo::new(
+                AllocationDevice::CPU,
+                0,
+                AllocatorType::Device,
+                MemoryType::Default,
+            )
+            .unwrap();
+            let session = SessionOptions::new()
+                .with_cpu_arena(cpu_arena)
+                .builder(&execution_providers, 1)
+                .unwrap()
+                .with_allocator(info)
+                .unwrap()
+                .commit_from_memory(&onnx_file)
+                .unwrap();
+            assert_eq!(
+                session.allocator().memory_info().allocator_type(),
+                if cpu_arena {
+                    AllocatorType::Arena
+                } else {
+                    AllocatorType::Device
+                }
+            );
+        }
+    }
+}
+
+#[test]
+fn test_optimized_model_cache() {
+    let documents = vec!["Hello, World!", "This is an example passage."];
+    let expected = TextEmbedding::try_new(InitOptions::new(EmbeddingModel::AllMiniLML6V2))
+        .unwrap()
+        .embed(documents.clone(), None)
+        .unwrap();
+
+    let session_options = SessionOptions::new().with_optimized_model_cache(true);
+
+    // A fresh cache directory, so that the first load has to save the optimized model
+    let cache_dir = std::env::temp_dir().join(format!(
+        "fastembed_optimized_model_cache_{}",
+        std::process::id()
+    ));
+    let optimized_dir = cache_dir.join("optimized");
+    assert!(!optimized_dir.exists());
+
+    // Once to save the optimized model, once to load it
+    for _ in 0..2 {
+        let model = TextEmbedding::try_new(
+            InitOptions::new(EmbeddingModel::AllMiniLML6V2)
+                .with_cache_dir(cache_dir.clone())
+                .with_session_options(session_options.clone()),
+        )
+        .unwrap();
+
+        // A single saved model, without leftover temporary directories
+        let saved = fs::read_dir(&optimized_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect::<Vec<_>>();
+        assert_eq!(saved.len(), 1);
+        assert!(saved[0].join("model.onnx").is_file());
+
+        let embeddings = model.embed(documents.clone(), None).unwrap();
+        assert_embeddings_eq(&embeddings, &expected, 1e-4);
+    }
+
+    // A saved model missing its graph, as left by an interrupted save, is saved again
+    let saved = fs::read_dir(&optimized_dir)
+        .unwrap()
+        .next()
+        .unwrap()
+        .unwrap()
+        .path();
+    fs::remove_file(saved.join("model.onnx")).unwrap();
+    let model = TextEmbedding::try_new(
+        InitOptions::new(EmbeddingModel::AllMiniLML6V2)
+            .with_cache_dir(cache_dir.clone())
+            .with_session_options(session_options.clone()),
+    )
+    .unwrap();
+    assert!(saved.join("model.onnx").is_file());
+    assert_eq!(fs::read_dir(&optimized_dir).unwrap().count(), 1);
+    let embeddings = model.embed(documents.clone(), None).unwrap();
+    assert_embeddings_eq(&embeddings, &expected, 1e-4);
+
+    fs::remove_dir_all(&cache_dir).unwrap();
+}
+
+#[test]
+fn test_profiling() {
+    let documents = vec!["Hello, World!", "This is an example passage."];
+    let prefix = std::env::temp_dir().join("fastembed_profile");
+
+    let model = TextEmbedding::try_new(
+        InitOptions::new(EmbeddingModel::AllMiniLML6V2)
+            .with_session_pool_size(2)
+            .with_session_options(SessionOptions::new().with_profiling(&prefix)),
+    )
+    .unwrap();
+    model.embed(documents.clone(), Some(1)).unwrap();
+    let mut profiles = model.end_profiling().unwrap();
+    assert_eq!(profiles.len(), 2);
+
+    let model = TextRerank::try_new(
+        RerankInitOptions::default()
+            .with_session_options(SessionOptions::new().with_profiling(&prefix)),
+    )
+    .unwrap();
+    model.rerank("hello", documents, false, None).unwrap();
+    profiles.extend(model.end_profiling().unwrap());
+
+    // One Chrome trace per session
+    assert_eq!(
+        profiles
+            .iter()
+            .collect::<std::collections::HashSet<_>>()
+            .len(),
+        3
+    );
+    for profile in profiles {
+        let trace = fs::read_to_string(&profile).unwrap();
+        assert!(trace.trim_start().starts_with('['));
+        fs::remove_file(profile).unwrap();
+    }
+
+    // Without profiling there is no profile file to return
+    let model = TextEmbedding::try_new(InitOptions::new(EmbeddingModel::AllMiniLML6V2)).unwrap();
+    assert!(model.end_profiling().is_err());
+}
+
+#[test]
+fn test_deterministic_mode() {
+    let documents = vec![
+        "Books are no more threatened by Kindle than stairs by elevators.",
+        "You are who you are when nobody's watching.",
+        "I can resist anything except temptation.",
+        "It is absurd to divide people into good and bad. People are either charming or tedious.",
+    ];
+    let session_options = SessionOptions::new().with_deterministic(true);
+
+    // Two separately loaded models, the second with batching options that are ignored
+    let first = TextEmbedding::try_new(
+        InitOptions::new(EmbeddingModel::AllMiniLML6V2)
+            .with_session_options(session_options.clone()),
+    )
+    .unwrap()
+    .embed(documents.clone(), Some(2))
+    .unwrap();
+    let second = TextEmbedding::try_new(
+        InitOptions::new(EmbeddingModel::AllMiniLML6V2)
+            .with_sort_by_length(true)
+            .with_max_batch_tokens(16)
+            .with_session_pool_size(2)
+            .with_session_options(session_options.clone()),
+    )
+    .unwrap()
+    .embed(documents.clone(), Some(2))
+    .unwrap();
+    assert_eq!(first, second);
+
+    let rerank = |session_options: SessionOptions| {
+        TextRerank::try_new(RerankInitOptions::default().with_session_options(session_options))
+            .unwrap()
+            .rerank("temptation", documents.clone(), false, Some(2))
+            .unwrap()
+            .into_iter()
+            .map(|result| (result.index, result.score.to_bits()))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(rerank(session_options.clone()), rerank(session_options));
+}
+
+#[test]
+fn test_similarity() {
+    let corpus = vec![
+        vec![1.0, 0.0, 0.0],
+        vec![0.0, 2.0, 0.0],
+        vec![1.0, 1.0, 0.0],
+        vec![-1.0, 0.0, 0.0],
+    ];
+    let query = [1.0, 0.5, 0.0];
+
+    assert_eq!(similarity::dot(&corpus[0], &corpus[2]).unwrap(), 1.0);
+    assert!((similarity::cosine(&corpus[0], &corpus[2]).unwrap() - 0.5f32.sqrt()).abs() < 1e-6);
+    assert_eq!(similarity::cosine(&corpus[0], &[0.0; 3]).unwrap(), 0.0);
+    assert_eq!(similarity::l2(&corpus[0], &corpus[3]).unwrap(), 2.0);
+    assert_eq!(Metric::L2.score(&corpus[0], &corpus[3]).unwrap(), 2.0);
+
+    // Embeddings of different dimensions cannot be compared
+    assert!(similarity::dot(&corpus[0], &[1.0; 2]).is_err());
+    assert!(similarity::l2_squared(&corpus[0], &[1.0; 4]).is_err());
+    assert!(similarity::cosine(&corpus[0], &[]).is_err());
+    assert!(Metric::Dot.score(&corpus[0], &[1.0; 2]).is_err());
+
+    // Longer than the lanes of the kernels, with a remainder
+    let a: Vec<f32> = (0..19).map(|i| i as f32).collect();
+    let b: Vec<f32> = (0..19).map(|i| (i % 3) as f32).collect();
+    let expected: f32 = a.iter().zip(&b).map(|(a, b)| a * b).sum();
+    assert_eq!(similarity::dot(&a, &b).unwrap(), expected);
+
+    let matrix = Matrix::from_embeddings(&corpus).unwrap();
+    assert_eq!((matrix.rows(), matrix.cols()), (4, 3));
+    assert!(Matrix::from_embeddings(&[vec![1.0], vec![1.0, 2.0]]).is_err());
+    assert!(Matrix::new(vec![1.0; 5], 2).is_err());
+
+    let scores = similarity::matrix(Metric::Dot, &matrix, &matrix).unwrap();
+    assert_eq!((scores.rows(), scores.cols()), (4, 4));
+    assert_eq!(scores.row(1), &[0.0, 4.0, 2.0, 0.0]);
+
+    let hits = similarity::top_k(Metric::Cosine, &query, &matrix, 2).unwrap();
+    assert_eq!(
+        hits.iter().map(|hit| hit.index).collect::<Vec<_>>(),
+        vec![2, 0]
+    );
+    let hits = similarity::top_k(Metric::L2, &query, &matrix, 10).unwrap();
+    assert_eq!(
+        hits.iter().map(|hit| hit.index).collect::<Vec<_>>(),
+        vec![0, 2, 1, 3]
+    );
+    assert!(similarity::top_k(Metric::Dot, &[1.0], &matrix, 1).is_err());
+
+    let batch = similarity::top_k_batch(Metric::Cosine, &matrix, &matrix, 1).unwrap();
+    for (i, hits) in batch.iter().enumerate() {
+        assert_eq!(hits[0].index, i);
+    }
+
+    let hits = similarity::within_threshold(Metric::Cosine, &query, &matrix, 0.5).unwrap();
+    assert_eq!(
+        hits.iter().map(|hit| hit.index).collect::<Vec<_>>(),
+        vec![2, 0]
+    );
+
+    let model = TextEmbedding::try_new(InitOptions::new(EmbeddingModel::AllMiniLML6V2)).unwrap();
+    let close = model
+        .similarity("A cat sits on the mat", "A kitten is on the rug")
+        .unwrap();
+    let far = model
+        .similarity(
+            "A cat sits on the mat",
+            "Quarterly revenue grew by ten percent",
+        )
+        .unwrap();
+    assert!(close > far);
+
+    let scores = model
+        .similarity_matrix(
+            vec!["cat", "stock market"],
+            vec!["kitten", "finance", "dog"],
+            Metric::Cosine,
+            None,
+        )
+        .unwrap();
+    assert_eq!((scores.rows(), scores.cols()), (2, 3));
+    assert!(scores.row(0)[0] > scores.row(0)[1]);
+    assert!(scores.row(1)[1] > scores.row(1)[0]);
+}
+
+#[test]
+fn test_vector_index() {
+    // Deterministic pseudo-random vectors
+    let mut state = 42u64;
+    let mut random_vector = |dim: usize| -> Embedding {
+        (0..dim)
+            .map(|_| {
+                state = state
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                ((state >> 40) as f32 / (1u64 << 24) as f32) - 0.5
+            })
+            .collect()
+    };
+    let payload = |i: u64| {
+        json!({ "parity": if i % 2 == 0 { "even" } else { "odd" }, "rank": i })
+            .as_object()
+            .unwrap()
+            .clone()
+    };
+
+    let dim = 16;
+    let vectors: Vec<Embedding> = (0..500).map(|_| random_vector(dim)).collect();
+    let queries: Vec<Embedding> = (0..20).map(|_| random_vector(dim)).collect();
+
+    let mut flat = VectorIndex::new(IndexOptions::new("random", dim)).unwrap();
+    let mut hnsw = VectorIndex::new(
+        IndexOptions::new("random", dim).with_hnsw(HnswOptions::new().with_seed(7)),
+    )
+    .unwrap();
+    for (id, vector) in vectors.iter().enumerate() {
+        flat.insert(id as u64, vector, payload(id as u64)).unwrap();
+        hnsw.insert(id as u64, vector, payload(id as u64)).unwrap();
+    }
+    assert_eq!(flat.len(), 500);
+    assert!(flat.insert(0, &[1.0; 3], Payload::new()).is_err());
+    assert!(hnsw.search(&[1.0; 3], 1, None).is_err());
+    assert!(VectorIndex::new(
+        IndexOptions::new("random", dim).with_hnsw(HnswOptions::new().with_ef_construction(0))
+    )
+    .is_err());
+
+    // The flat index is exact
+    let matrix = Matrix::from_embeddings(&vectors).unwrap();
+    let exact = similarity::top_k_batch(
+        Metric::Cosine,
+        &Matrix::from_embeddings(&queries).unwrap(),
+        &matrix,
+        10,
+    )
+    .unwrap();
+    let flat_hits = flat.search_batch(&queries, 10, None).unwrap();
+    for (exact, hits) in exact.iter().zip(&flat_hits) {
+        assert_eq!(
+            exact.iter().map(|hit| hit.index as u64).collect::<Vec<_>>(),
+            hits.iter().map(|hit| hit.id).collect::<Vec<_>>()
+        );
+    }
+
+    // The HNSW graph finds almost all of them
+    let hnsw_hits = hnsw.search_batch(&queries, 10, None).unwrap();
+    let found = flat_hits
+        .iter()
+        .zip(&hnsw_hits)
+        .map(|(flat, hnsw)| flat.iter().filter(|hit| hnsw.contains(hit)).count())
+        .sum::<usize>();
+    assert!(found >= 190, "recall of {found}/200");
+
+    // Filters, deletes and replacements
+    let odd = Filter::equals("parity", "odd").and(Filter::range("rank", None, Some(100.0)));
+    for index in [&mut flat, &mut hnsw] {
+        let hits = index.search(&queries[0], 100, Some(&odd)).unwrap();
+        assert_eq!(hits.len(), 50);
+        assert!(hits.iter().all(|hit| hit.id % 2 == 1 && hit.id <= 100));
+
+        let nearest = index.search(&queries[0], 1, None).unwrap()[0].id;
+        assert!(index.delete(nearest));
+        assert!(!index.delete(nearest));
+        assert!(index
+            .search(&queries[0], 500, None)
+            .unwrap()
+            .iter()
+            .all(|hit| hit.id != nearest));
+
+        index.insert(1000, &queries[0], payload(1000)).unwrap();
+        let hit = index.search(&queries[0], 1, None).unwrap()[0];
+        assert_eq!(hit.id, 1000);
+        assert!((hit.score - 1.0).abs() < 1e-5);
+        assert_eq!(index.len(), 500);
+    }
+
+    // Save and load
+    let path = std::env::temp_dir().join(format!("fastembed-index-{}", std::process::id()));
+    for index in [&flat, &hnsw] {
+        index.save(&path).unwrap();
+        let loaded = VectorIndex::load(&path, "random").unwrap();
+        assert_eq!((loaded.len(), loaded.dim()), (index.len(), index.dim()));
+        assert_eq!(
+            loaded.search_batch(&queries, 10, Some(&odd)).unwrap(),
+            index.search_batch(&queries, 10, Some(&odd)).unwrap()
+        );
+        assert!(VectorIndex::load(&path, "other").is_err());
+    }
+
+    // Concurrent saves to the same path do not share a temporary file
+    std::thread::scope(|scope| {
+        for _ in 0..4 {
+            scope.spawn(|| hnsw.save(&path).unwrap());
+        }
+    });
+    assert_eq!(
+        VectorIndex::load(&path, "random").unwrap().len(),
+        hnsw.len()
+    );
+
+    // Compacting drops the deleted and replaced vectors from the HNSW graph
+    hnsw.save(&path).unwrap();
+    let size = fs::metadata(&path).unwrap().len();
+    hnsw.compact();
+    hnsw.save(&path).unwrap();
+    assert!(fs::metadata(&path).unwrap().len() < size);
+    assert_eq!(hnsw.len(), 500);
+    assert_eq!(hnsw.search(&queries[0], 1, None).unwrap()[0].id, 1000);
+    let hnsw_hits = hnsw.search_batch(&queries, 10, Some(&odd)).unwrap();
+    let flat_hits = flat.search_batch(&queries, 10, Some(&odd)).unwrap();
+    let found = flat_hits
+        .iter()
+        .zip(&hnsw_hits)
+        .map(|(flat, hnsw)| flat.iter().filter(|hit| hnsw.contains(hit)).count())
+        .sum::<usize>();
+    assert!(found >= 190, "recall of {found}/200");
+    fs::remove_file(&path).unwrap();
+
+    let model = TextEmbedding::try_new(InitOptions::new(EmbeddingModel::AllMiniLML6V2)).unwrap();
+    let mut index =
+        VectorIndex::new(IndexOptions::for_model(&EmbeddingModel::AllMiniLML6V2).unwrap()).unwrap();
+    let documents = vec![
+        "A cat sits on the mat",
+        "Quarterly revenue grew by ten percent",
+    ];
+    for (id, embedding) in model.embed(documents, None).unwrap().iter().enumerate() {
+        index.insert(id as u64, embedding, Payload::new()).unwrap();
+    }
+    let query = model.embed(vec!["kitten"], None).unwrap();
+    assert_eq!(index.search(&query[0], 1, None).unwrap()[0].id, 0);
+}
+
+#[test]
+fn test_embed_iter() {
+    let documents = vec![
+        "Hello, World!",
+        "This is an example passage.",
+        "fastembed-rs is licensed under Apache-2.0",
+        "Some other short text here blah blah blah",
+    ];
+
+    let model = TextEmbedding::try_new(InitOptions::new(EmbeddingModel::AllMiniLML6V2)).unwrap();
+    let expected = model.embed(documents.clone(), None).unwrap();
+    let embeddings = model
+        .embed_iter(
+            documents.iter().map(|document| document.to_string()),
+            Some(3),
+        )
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    assert_eq!(embeddings.len(), expected.len());
+    for ((index, embedding), (expected_index, expected)) in
+        embeddings.iter().zip(expected.iter().enumerate())
+    {
+        assert_eq!(*index, expected_index);
+        for (a, b) in embedding.iter().zip(expected) {
+            assert!((a - b).abs() < 1e-5);
+        }
+    }
+
+    let model = SparseTextEmbedding::try_new(SparseInitOptions::default()).unwrap();
+    let expected = model.embed(documents.clone(), None).unwrap();
+    let embeddings = model
+        .embed_iter(documents.clone(), Some(3))
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    assert_eq!(embeddings.len(), expected.len());
+    for ((index, embedding), (expected_index, expected)) in
+        embeddings.iter().zip(expected.iter().enumerate())
+    {
+        assert_eq!(*index, expected_index);
+        assert_eq!(embedding.indices, expected.indices);
+    }
+
+    let model = ImageEmbedding::try_new(ImageInitOptions::default()).unwrap();
+    let images = vec!["tests/assets/image_0.png", "tests/assets/image_1.png"];
+    let indices = model
+        .embed_iter(images.into_iter().cycle().take(5), Some(2))
+        .map(|result| result.map(|(index, _)| index))
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    assert_eq!(indices, (0..5).collect::<Vec<_>>());
+}
+
+#[test]
+fn test_task_prefixes() {
+    let model_info = TextEmbedding::get_model_info(&EmbeddingModel::MultilingualE5Small).unwrap();
+    assert_eq!(model_info.prefixes.get(EmbeddingTask::Query), "query: ");
+    assert_eq!(
+        model_info.prefixes.get(EmbeddingTask::Document),
+        "passage: "
+    );
+
+    let model = TextEmbedding::try_new(InitOptions::new(EmbeddingModel::MultilingualE5Small))
+        .expect("Create model successfully");
+
+    let texts = vec!["Hello, World!", "This is an example passage."];
+    let queries = model.embed_query(texts.clone(), None).unwrap();
+    let documents = model.embed_documents(texts.clone(), None).unwrap();
+    let clustering = model
+        .embed_for_task(EmbeddingTask::Clustering, texts.clone(), None)
+        .unwrap();
+
+    let prefixed = |prefix: &str| {
+        let texts = texts
+            .iter()
+            .map(|text| format!("{prefix}{text}"))
+            .collect::<Vec<_>>();
+        model.embed(texts, None).unwrap()
+    };
+    assert_eq!(queries, prefixed("query: "));
+    assert_eq!(documents, prefixed("passage: "));
+    assert_eq!(clustering, queries);
+
+    // Models without prefixes embed the texts as they are
+    let model = TextEmbedding::try_new(InitOptions::new(EmbeddingModel::AllMiniLML6V2))
+        .expect("Create model successfully");
+    assert_eq!(
+        model.embed_query(texts.clone(), None).unwrap(),
+        model.embed(texts, None).unwrap()
+    );
+
+    let prefixes = TaskPrefixes::new("search_query: ", "search_document: ")
+        .with_classification("classification: ");
+    assert_eq!(
+        prefixes.get(EmbeddingTask::Classification),
+        "classification: "
+    );
+    assert_eq!(prefixes.get(EmbeddingTask::Clustering), "");
+}
+
+#[test]
+fn test_embedding_cache() {
+    let documents = vec![
+        "Hello, World!",
+        "This is an example passage.",
+        "Hello, World!",
+    ];
+    let expected = TextEmbedding::try_new(InitOptions::new(EmbeddingModel::AllMiniLML6V2))
+        .unwrap()
+        .embed(documents.clone(), None)
+        .unwrap();
+
+    for cache in [
+        EmbeddingCacheOptions::in_memory(1),
+        EmbeddingCacheOptions::persistent(0),
+    ] {
+        let model = TextEmbedding::try_new(
+            InitOptions::new(EmbeddingModel::AllMiniLML6V2).with_embedding_cache(cache),
+        )
+        .unwrap();
+
+        // Once to populate the cache, once to read from it
+        for _ in 0..2 {
+            let embeddings = model.embed(documents.clone(), None).unwrap();
+            assert_embeddings_eq(&embeddings, &expected, 1e-5);
+        }
+    }
+
+    // Variants of a model sharing its code do not share cached embeddings
+    let variants = [
+        EmbeddingModel::AllMiniLML12V2,
+        EmbeddingModel::AllMiniLML12V2Q,
+    ]
+    .map(|model| {
+        TextEmbedding::try_new(
+            InitOptions::new(model).with_embedding_cache(EmbeddingCacheOptions::persistent(0)),
+        )
+        .unwrap()
+        .embed(vec![documents[0]], None)
+        .unwrap()
+    });
+    assert_ne!(variants[0], variants[1]);
+
+    let sparse_model = SparseTextEmbedding::try_new(
+        SparseInitOptions::default().with_embedding_cache(EmbeddingCacheOptions::persistent(16)),
+    )
+    .unwrap();
+    let first = sparse_model.embed(documents.clone(), None).unwrap();
+    let second = sparse_model.embed(documents.clone(), None).unwrap();
+    assert_eq!(first, second);
+    assert_eq!(first[0], first[2]);
+
+    let (onnx_file, tokenizer_files) = cached_model_files(&SparseModel::SPLADEPPV1.to_string());
+    let sparse_model = SparseTextEmbedding::try_new_from_user_defined(
+        UserDefinedSparseModel::new(onnx_file, tokenizer_files),
+        SparseInitOptionsUserDefined::new()
+            .with_embedding_cache(EmbeddingCacheOptions::in_memory(16)),
+    )
+    .unwrap();
+    assert_eq!(sparse_model.embed(documents.clone(), None).unwrap(), first);
+
+    // User-defined models are cached by their files
+    let cache_dir =
+        std::env::temp_dir().join(format!("fastembed_embedding_cache_{}", std::process::id()));
+    let (onnx_file, tokenizer_files) = user_defined_model_files(&EmbeddingModel::AllMiniLML6V2);
+    let user_defined_model = || {
+        TextEmbedding::try_new_from_user_defined(
+            UserDefinedEmbeddingModel::new(onnx_file.clone(), tokenizer_files.clone())
+                .with_pooling(Pooling::Mean),
+            InitOptionsUserDefined::new()
+                .with_cache_dir(cache_dir.clone())
+                .with_embedding_cache(EmbeddingCacheOptions::persistent(0)),
+        )
+        .unwrap()
+    };
+    let embeddings = user_defined_model().embed(documents.clone(), None).unwrap();
+    assert_embeddings_eq(&embeddings, &expected, 1e-5);
+
+    // Stored embeddings of another dimension are recomputed
+    let mut dirs = vec![cache_dir.join("embeddings")];
+    while let Some(dir) = dirs.pop() {
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                dirs.push(path);
+            } else {
+                fs::write(path, [0; 8]).unwrap();
+            }
+        }
+    }
+    let model = user_defined_model();
+    model.embed(vec!["Another text"], None).unwrap();
+    let embeddings = model.embed(documents, None).unwrap();
+    assert_embeddings_eq(&embeddings, &expected, 1e-5);
+
+    fs::remove_dir_all(&cache_dir).unwrap();
+}
+
+#[test]
+fn test_quantized_embeddings() {
+    let model = TextEmbedding::try_new(InitOptions::new(EmbeddingModel::AllMiniLML6V2)).unwrap();
+    let documents = vec![
+        "Hello, World!",
+        "This is an example passage.",
+        "fastembed-rs is licensed under Apache 2.0",
+    ];
+    let embeddings = model.embed(documents.clone(), None).unwrap();
+    let dot = |a: &[f32], b: &[f32]| a.iter().zip(b).map(|(x, y)| x * y).sum::<f32>();
+
+    let range = ScalarRange::from_sample(&embeddings).unwrap();
+    let int8 = model.embed_int8(documents.clone(), &range, None).unwrap();
+    let uint8 = model.embed_uint8(documents.clone(), &range, None).unwrap();
+    let f16 = model.embed_f16(documents.clone(), None).unwrap();
+    for (i, embedding) in embeddings.iter().enumerate() {
+        let expected = dot(&embeddings[0], embedding);
+        assert!((range.int8_dot(&embeddings[0], &int8[i]).unwrap() - expected).abs() < EPS);
+        assert!((range.uint8_dot(&embeddings[0], &uint8[i]).unwrap() - expected).abs() < EPS);
+        assert!((f16_dot(&embeddings[0], &f16[i]).unwrap() - expected).abs() < EPS);
+
+        for (x, y) in range.dequantize_int8(&int8[i]).iter().zip(embedding) {
+            assert!((x - y).abs() < EPS);
+        }
+        assert_eq!(
+            range.dequantize_int8(&int8[i]),
+            range.dequantize_uint8(&uint8[i])
+        );
+        assert_eq!(from_f16(&f16[i]).len(), embedding.len());
+    }
+
+    let binary = model.embed_binary(documents.clone(), None).unwrap();
+    assert_eq!(binary[0].dim, embeddings[0].len());
+    assert_eq!(binary[0].words.len(), embeddings[0].len().div_ceil(64));
+    assert_eq!(binary[0].hamming_distance(&binary[0]).unwrap(), 0);
+    assert!(binary[0].hamming_distance(&binary[1]).unwrap() > 0);
+    for (value, bit) in embeddings[0].iter().zip(binary[0].dequantize()) {
+        assert_eq!(*value > 0.0, bit > 0.0);
+    }
+
+    // Embeddings of other dimensions cannot be compared
+    let short = &embeddings[0][..10];
+    assert!(range.int8_dot(short, &int8[0]).is_err());
+    assert!(range.uint8_dot(&embeddings[0], &uint8[0][..10]).is_err());
+    assert!(f16_dot(short, &f16[0]).is_err());
+    assert!(binary[0]
+        .hamming_distance(&BinaryEmbedding::from_embedding(short))
+        .is_err());
+
+    let transformed = model
+        .transform(documents, None)
+        .unwrap()
+        .export_with_transformer(output::quantized_transformer_with_precedence(
+            output::OUTPUT_TYPE_PRECEDENCE,
+            Some(Pooling::Mean),
+            BinaryEmbedding::from_embedding,
+        ))
+        .unwrap();
+    assert_eq!(transformed, binary);
+
+    let wrong_range = ScalarRange::from_sample(&[vec![0.0, 1.0]]).unwrap();
+    assert!(model.embed_int8(vec!["Hello"], &wrong_range, None).is_err());
+}
+
+#[test]
+fn test_unnormalized_embeddings() {
+    let normalize = |v: &[f32]| {
+        let norm = v.iter().map(|x| x * x).sum::<f32>().sqrt();
+        v.iter().map(|x| x / norm).collect::<Vec<_>>()
+    };
+    let assert_close = |a: &[f32], b: &[f32]| {
+        assert_eq!(a.len(), b.len());
+        for (x, y) in a.iter().zip(b) {
+            assert!((x - y).abs() < 1e-5);
+        }
+    };
+
+    let documents = vec!["Hello, World!", "This is an example passage."];
+    let normalized_model =
+        TextEmbedding::try_new(InitOptions::new(EmbeddingModel::AllMiniLML6V2)).unwrap();
+    let raw_model = TextEmbedding::try_new(
+        InitOptions::new(EmbeddingModel::AllMiniLML6V2).with_normalize(false),
+    )
+    .unwrap();
+
+    let normalized = normalized_model.embed(documents.clone(), None).unwrap();
+    let raw = raw_model.embed(documents.clone(), None).unwrap();
+    let transformed = normalized_model
+        .transform(documents, None)
+        .unwrap()
+        .export_with_transformer(output::raw_transformer_with_precedence(
+            output::OUTPUT_TYPE_PRECEDENCE,
+            Some(Pooling::Mean),
+        ))
+        .unwrap();
+
+    for ((normalized, raw), transformed) in normalized.iter().zip(&raw).zip(&transformed) {
+        let norm = raw.iter().map(|x| x * x).sum::<f32>().sqrt();
+        assert!(
+            (norm - 1.0).abs() > 1e-3,
+            "Expected a raw, un-normalized embedding"
+        );
+        assert_close(raw, transformed);
+        assert_close(&normalize(raw), normalized);
+    }
+
+    let images = vec!["tests/assets/image_0.png", "tests/assets/image_1.png"];
+    let normalized =
+        ImageEmbedding::try_new(ImageInitOptions::new(ImageEmbeddingModel::ClipVitB32))
+            .unwrap()
+            .embed(images.clone(), None)
+            .unwrap();
+    let raw = ImageEmbedding::try_new(
+        ImageInitOptions::new(ImageEmbeddingModel::ClipVitB32).with_normalize(false),
+    )
+    .unwrap()
+    .embed(images, None)
+    .unwrap();
+    for (normalized, raw) in normalized.iter().zip(&raw) {
+        assert_close(&normalize(raw), normalized);
+    }
+}
+
 #[test]
 fn test_sparse_embeddings() {
     SparseTextEmbedding::list_supported_models()
@@ -199,15 +1320,21 @@ fn test_sparse_embeddings() {
         });
 }
 
-#[test]
-fn test_user_defined_embedding_model() {
+/// Download a supported model and read back its files, so that it can be loaded as a
+/// [`UserDefinedEmbeddingModel`].
+fn user_defined_model_files(model: &EmbeddingModel) -> (Vec<u8>, TokenizerFiles) {
     // Constitute the model in order to ensure it's downloaded and cached
-    let test_model_info = TextEmbedding::get_model_info(&EmbeddingModel::AllMiniLML6V2).unwrap();
+    let test_model_info = TextEmbedding::get_model_info(model).unwrap();
 
     TextEmbedding::try_new(InitOptions::new(test_model_info.model.clone())).unwrap();
 
+    cached_model_files(&test_model_info.model_code)
+}
+
+/// Read the files of a model downloaded to the default cache directory
+fn cached_model_files(model_code: &str) -> (Vec<u8>, TokenizerFiles) {
     // Get the directory of the model
-    let model_name = test_model_info.model_code.replace('/', "--");
+    let model_name = model_code.replace('/', "--");
     let model_dir = Path::new(DEFAULT_CACHE_DIR).join(format!("models--{}", model_name));
 
     // Find the "snapshots" sub-directory
@@ -257,6 +1384,73 @@ fn test_user_defined_embedding_model() {
         tokenizer_config_file: read_file_to_bytes(&model_files_dir.join("tokenizer_config.json"))
             .expect("Could not read tokenizer_config.json"),
     };
+    (onnx_file, tokenizer_files)
+}
+
+#[test]
+fn test_late_interaction_embeddings() {
+    LateInteractionTextEmbedding::list_supported_models()
+        .par_iter()
+        .for_each(|supported_model| {
+            let model = LateInteractionTextEmbedding::try_new(LateInteractionInitOptions::new(
+                supported_model.model.clone(),
+            ))
+            .unwrap();
+
+            let documents = vec![
+                "The giant panda is a bear species endemic to China.",
+                "fastembed-rs is licensed under Apache-2.0",
+            ];
+
+            let document_embeddings = model.embed(documents.clone(), None).unwrap();
+            let query_embeddings = model.query_embed(vec!["what is a panda?"], None).unwrap();
+
+            assert_eq!(document_embeddings.len(), documents.len());
+            for (document, embeddings) in documents.iter().zip(&document_embeddings) {
+                // [CLS], the document marker and [SEP] are kept, punctuation is not.
+                let tokens = model.tokenizer.encode(*document, true).unwrap().len();
+                let punctuation = document
+                    .chars()
+                    .filter(|c| c.is_ascii_punctuation())
+                    .count();
+                assert_eq!(embeddings.len(), tokens + 1 - punctuation);
+                for embedding in embeddings {
+                    assert_eq!(embedding.len(), supported_model.dim);
+                }
+            }
+
+            // Short queries are augmented with [MASK] tokens up to 32 tokens.
+            assert_eq!(query_embeddings[0].len(), 32);
+
+            // Long documents are truncated to leave room for the document marker.
+            let long_document = "The giant panda is a bear species endemic to China. ".repeat(100);
+            let long_embeddings = model.embed(vec![long_document], None).unwrap();
+            assert!(long_embeddings[0].len() < 512);
+
+            let scores = document_embeddings
+                .iter()
+                .map(|document| {
+                    LateInteractionTextEmbedding::max_sim(&query_embeddings[0], document)
+                })
+                .collect::<Vec<_>>();
+            assert!(
+                scores[0] > scores[1],
+                "Expected the panda document to score higher for {model}: {scores:?}",
+                model = supported_model.model_code
+            );
+
+            // Clear the model cache to avoid running out of space on GitHub Actions.
+            if std::env::var("CI").is_ok() {
+                clean_cache(supported_model.model_code.clone())
+            }
+        });
+}
+
+#[test]
+fn test_user_defined_embedding_model() {
+    let test_model_info = TextEmbedding::get_model_info(&EmbeddingModel::AllMiniLML6V2).unwrap();
+    let (onnx_file, tokenizer_files) = user_defined_model_files(&test_model_info.model);
+
     // Create a UserDefinedEmbeddingModel
     let user_defined_model =
         UserDefinedEmbeddingModel::new(onnx_file, tokenizer_files).with_pooling(Pooling::Mean);
@@ -285,6 +1479,143 @@ fn test_user_defined_embedding_model() {
     }
 }
 
+#[test]
+fn test_last_token_pooling() {
+    let (onnx_file, tokenizer_files) = user_defined_model_files(&EmbeddingModel::AllMiniLML6V2);
+    let load = |pooling: Pooling, padding_side: PaddingSide| {
+        TextEmbedding::try_new_from_user_defined(
+            UserDefinedEmbeddingModel::new(onnx_file.clone(), tokenizer_files.clone())
+                .with_pooling(pooling)
+                .with_padding_side(padding_side),
+            InitOptionsUserDefined::default(),
+        )
+        .unwrap()
+    };
+
+    let documents = vec![
+        "Hello, World!",
+        "This is a much longer example passage, which forces the first one to be padded.",
+    ];
+
+    let last_token = load(Pooling::LastToken, PaddingSide::Right);
+    let batched = last_token.embed(documents.clone(), None).unwrap();
+    let alone = last_token.embed(vec![documents[0]], None).unwrap();
+    assert_eq!(batched[0].len(), 384);
+    for (a, b) in batched[0].iter().zip(&alone[0]) {
+        assert!(
+            (a - b).abs() < 1e-4,
+            "Padding must not change the pooled token"
+        );
+    }
+
+    // The last real token of a BERT tokenizer is always `[SEP]`.
+    let sep_token_id = last_token.tokenizer.token_to_id("[SEP]").unwrap();
+    let eos_token = load(Pooling::EosToken(sep_token_id), PaddingSide::Right);
+    let eos_embeddings = eos_token.embed(documents.clone(), None).unwrap();
+    for (a, b) in eos_embeddings
+        .iter()
+        .flatten()
+        .zip(batched.iter().flatten())
+    {
+        assert!((a - b).abs() < 1e-6);
+    }
+
+    let left_padded = load(Pooling::LastToken, PaddingSide::Left);
+    let encodings = left_padded
+        .tokenizer
+        .encode_batch(documents.clone(), true)
+        .unwrap();
+    assert_eq!(encodings[0].get_attention_mask()[0], 0);
+    let embeddings = left_padded.embed(documents, None).unwrap();
+    assert!(embeddings.iter().flatten().all(|v| v.is_finite()));
+}
+
+#[test]
+fn test_max_weighted_mean_and_custom_pooling() {
+    /// Re-implementation of [`Pooling::Cls`] through the custom pooling trait.
+    struct FirstToken;
+
+    impl CustomPooling for FirstToken {
+        fn pool(
+            &self,
+            token_embeddings: &ArrayView<f32, Dim<IxDynImpl>>,
+            _attention_mask: &Array2<i64>,
+        ) -> anyhow::Result<Array2<f32>> {
+            Ok(token_embeddings.slice(s![.., 0, ..]).to_owned())
+        }
+    }
+
+    let (onnx_file, tokenizer_files) = user_defined_model_files(&EmbeddingModel::AllMiniLML6V2);
+    let embed = |pooling: Pooling, documents: Vec<&str>| {
+        TextEmbedding::try_new_from_user_defined(
+            UserDefinedEmbeddingModel::new(onnx_file.clone(), tokenizer_files.clone())
+                .with_pooling(pooling),
+            InitOptionsUserDefined::default(),
+        )
+        .unwrap()
+        .embed(documents, None)
+        .unwrap()
+    };
+
+    let documents = vec![
+        "Hello, World!",
+        "This is a much longer example passage, which forces the first one to be padded.",
+    ];
+
+    for pooling in [Pooling::Max, Pooling::WeightedMean] {
+        let batched = embed(pooling.clone(), documents.clone());
+        let alone = embed(pooling.clone(), vec![documents[0]]);
+        assert_eq!(batched[0].len(), 384);
+        for (a, b) in batched[0].iter().zip(&alone[0]) {
+            assert!(
+                (a - b).abs() < 1e-4,
+                "Padding must not change the {pooling:?} pooled embedding"
+            );
+        }
+    }
+
+    let custom = embed(Pooling::Custom(Arc::new(FirstToken)), documents.clone());
+    let cls = embed(Pooling::Cls, documents);
+    assert_eq!(custom, cls);
+}
+
+#[test]
+fn test_embed_tokens() {
+    let model = TextEmbedding::try_new(InitOptions::new(EmbeddingModel::AllMiniLML6V2)).unwrap();
+
+    let documents = vec![
+        "Hello, World!",
+        "This is a much longer example passage, which forces the first one to be padded.",
+    ];
+
+    let token_embeddings = model.embed_tokens(documents.clone(), None).unwrap();
+    let embeddings = model.embed(documents.clone(), None).unwrap();
+    assert_eq!(token_embeddings.len(), documents.len());
+
+    for ((tokens, embedding), document) in token_embeddings.iter().zip(&embeddings).zip(&documents)
+    {
+        let encoding = model.tokenizer.encode(*document, true).unwrap();
+        assert_eq!(tokens.token_ids, encoding.get_ids());
+        assert_eq!(tokens.embeddings.len(), tokens.token_ids.len());
+        assert_eq!(tokens.offsets.len(), tokens.token_ids.len());
+        let (start, end) = tokens.offsets[1];
+        assert_eq!(
+            &document[start..end],
+            document.split([' ', ',']).next().unwrap()
+        );
+
+        // Mean pooling over the unpadded tokens must reproduce the sentence embedding.
+        let mut mean = vec![0.0; tokens.embeddings[0].len()];
+        for token in &tokens.embeddings {
+            mean.iter_mut().zip(token).for_each(|(m, t)| *m += t);
+        }
+        let norm = mean.iter().map(|v| v * v).sum::<f32>().sqrt();
+        for (a, b) in mean.iter().zip(embedding) {
+            assert!((a / norm - b).abs() < 1e-4);
+        }
+    }
+}
+
 #[test]
 fn test_rerank() {
     let test_one_model = |supported_model: &RerankerModelInfo| {
@@ -497,28 +1828,6 @@ fn test_image_embedding_model() {
 #[test]
 #[ignore]
 fn test_nomic_embed_vision_v1_5() {
-    fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
-        let dot_product = a.iter().zip(b).map(|(x, y)| x * y).sum::<f32>();
-        let norm_a = a.iter().map(|x| x * x).sum::<f32>().sqrt();
-        let norm_b = b.iter().map(|x| x * x).sum::<f32>().sqrt();
-        dot_product / (norm_a * norm_b)
-    }
-
-    fn cosine_similarity_matrix(
-        embeddings_a: &[Vec<f32>],
-        embeddings_b: &[Vec<f32>],
-    ) -> Vec<Vec<f32>> {
-        embeddings_a
-            .iter()
-            .map(|a| {
-                embeddings_b
-                    .iter()
-                    .map(|b| cosine_similarity(a, b))
-                    .collect()
-            })
-            .collect()
-    }
-
     // Test the NomicEmbedVisionV15 model specifically because it outputs a 3D tensor with a different
     // output key ('last_hidden_state') compared to other models. This test ensures our tensor extraction
     // logic can handle both standard output keys and this model's specific naming convention.
@@ -541,9 +1850,14 @@ fn test_nomic_embed_vision_v1_5() {
     let text_embeddings = text_model.embed(texts.clone(), None).unwrap();
 
     // Generate similarity matrix
-    let similarity_matrix = cosine_similarity_matrix(&text_embeddings, &image_embeddings);
+    let similarity_matrix = similarity::matrix(
+        Metric::Cosine,
+        &Matrix::from_embeddings(&text_embeddings).unwrap(),
+        &Matrix::from_embeddings(&image_embeddings).unwrap(),
+    )
+    .unwrap();
     // Print the similarity matrix with text labels
-    for (i, row) in similarity_matrix.iter().enumerate() {
+    for (i, row) in similarity_matrix.iter_rows().enumerate() {
         println!("{}: {:?}", texts[i], row);
     }
 
@@ -590,11 +1904,109 @@ fn test_batch_size_does_not_change_output() {
         .expect("create successfully");
 
     assert_eq!(single_batch.len(), small_batch.len());
-    for (a, b) in single_batch.into_iter().zip(small_batch.into_iter()) {
+    for (a, b) in single_batch.into_iter().zip(small_batch) {
         assert!(a == b, "Expect each sentence embedding are equal.");
     }
 }
 
+#[test]
+fn test_sort_by_length_does_not_change_output() {
+    let sentences = vec![
+        "Books are no more threatened by Kindle than stairs by elevators.",
+        "You are who you are when nobody's watching.",
+        "I never travel without my diary. One should always have something sensational to read in the train.",
+        "I can resist anything except temptation.",
+        "It is absurd to divide people into good and bad. People are either charming or tedious.",
+    ];
+    let assert_close = |a: &[f32], b: &[f32]| {
+        assert_eq!(a.len(), b.len());
+        for (x, y) in a.iter().zip(b) {
+            assert!((x - y).abs() < 1e-4);
+        }
+    };
+
+    let in_order = TextEmbedding::try_new(InitOptions::new(EmbeddingModel::AllMiniLML6V2))
+        .unwrap()
+        .embed(sentences.clone(), Some(2))
+        .unwrap();
+    let by_length = TextEmbedding::try_new(
+        InitOptions::new(EmbeddingModel::AllMiniLML6V2).with_sort_by_length(true),
+    )
+    .unwrap()
+    .embed(sentences.clone(), Some(2))
+    .unwrap();
+    assert_eq!(in_order.len(), by_length.len());
+    for (a, b) in in_order.iter().zip(&by_length) {
+        assert_close(a, b);
+    }
+
+    let in_order = SparseTextEmbedding::try_new(SparseInitOptions::default())
+        .unwrap()
+        .embed(sentences.clone(), Some(2))
+        .unwrap();
+    let by_length =
+        SparseTextEmbedding::try_new(SparseInitOptions::default().with_sort_by_length(true))
+            .unwrap()
+            .embed(sentences.clone(), Some(2))
+            .unwrap();
+    assert_eq!(in_order.len(), by_length.len());
+    for (a, b) in in_order.iter().zip(&by_length) {
+        assert_eq!(a.indices, b.indices);
+        assert_close(&a.values, &b.values);
+    }
+
+    let in_order = TextRerank::try_new(RerankInitOptions::default())
+        .unwrap()
+        .rerank("what is temptation?", sentences.clone(), false, Some(2))
+        .unwrap();
+    let by_length = TextRerank::try_new(RerankInitOptions::default().with_sort_by_length(true))
+        .unwrap()
+        .rerank("what is temptation?", sentences, false, Some(2))
+        .unwrap();
+    assert_eq!(in_order.len(), by_length.len());
+    for (a, b) in in_order.iter().zip(&by_length) {
+        assert_eq!(a.index, b.index);
+        assert!((a.score - b.score).abs() < 1e-4);
+    }
+}
+
+#[test]
+fn test_max_batch_tokens_does_not_change_output() {
+    let sentences = vec![
+        "Hello, World!",
+        "I never travel without my diary. One should always have something sensational to read in the train.",
+        "I can resist anything except temptation.",
+        "It is absurd to divide people into good and bad. People are either charming or tedious.",
+    ];
+
+    let default = TextEmbedding::try_new(InitOptions::new(EmbeddingModel::AllMiniLML6V2))
+        .unwrap()
+        .embed(sentences.clone(), None)
+        .unwrap();
+    // Small enough to run every sentence in a batch of its own
+    let budgeted = TextEmbedding::try_new(
+        InitOptions::new(EmbeddingModel::AllMiniLML6V2).with_max_batch_tokens(16),
+    )
+    .unwrap()
+    .embed(sentences.clone(), None)
+    .unwrap();
+
+    assert_embeddings_eq(&default, &budgeted, 1e-4);
+
+    let in_order = TextRerank::try_new(RerankInitOptions::default())
+        .unwrap()
+        .rerank("what is temptation?", sentences.clone(), false, None)
+        .unwrap();
+    let budgeted = TextRerank::try_new(RerankInitOptions::default().with_max_batch_tokens(64))
+        .unwrap()
+        .rerank("what is temptation?", sentences, false, None)
+        .unwrap();
+    for (a, b) in in_order.iter().zip(&budgeted) {
+        assert_eq!(a.index, b.index);
+        assert!((a.score - b.score).abs() < 1e-4);
+    }
+}
+
 #[test]
 fn test_bgesmallen1point5_match_python_counterpart() {
     let model = TextEmbedding::try_new(
@@ -628,7 +2040,7 @@ fn test_bgesmallen1point5_match_python_counterpart() {
         .clone()
         .into_iter()
         .take(baseline.len())
-        .zip(baseline.into_iter())
+        .zip(baseline)
     {
         assert!((expected - actual).abs() < tolerance);
     }
@@ -667,7 +2079,7 @@ fn test_allminilml6v2_match_python_counterpart() {
         .clone()
         .into_iter()
         .take(baseline.len())
-        .zip(baseline.into_iter())
+        .zip(baseline)
     {
         assert!((expected - actual).abs() < tolerance);
     }
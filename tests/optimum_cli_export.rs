@@ -58,16 +58,19 @@ fn load_bytes_from_file(path: &PathBuf) -> anyhow::Result<Vec<u8>> {
 
 /// Load a model from a local directory.
 fn load_model(output: &PathBuf, pooling: Option<Pooling>) -> anyhow::Result<TextEmbedding> {
-    let model = UserDefinedEmbeddingModel {
-        onnx_file: load_bytes_from_file(&output.join("model.onnx"))?,
-        tokenizer_files: TokenizerFiles {
+    let model = UserDefinedEmbeddingModel::new(
+        load_bytes_from_file(&output.join("model.onnx"))?,
+        TokenizerFiles {
             tokenizer_file: load_bytes_from_file(&output.join("tokenizer.json"))?,
             config_file: load_bytes_from_file(&output.join("config.json"))?,
             special_tokens_map_file: load_bytes_from_file(&output.join("special_tokens_map.json"))?,
             tokenizer_config_file: load_bytes_from_file(&output.join("tokenizer_config.json"))?,
         },
-        pooling,
-        quantization: QuantizationMode::None,
+    )
+    .with_quantization(QuantizationMode::None);
+    let model = match pooling {
+        Some(pooling) => model.with_pooling(pooling),
+        None => model,
     };
 
     TextEmbedding::try_new_from_user_defined(model, Default::default())
@@ -154,6 +157,16 @@ create_test! {
     // when summed.
     expected: [ 0.5960538 ,  0.36542776, -0.16450086, -0.40904027]
 }
+create_test! {
+    repo_name: "all-MiniLM-L6-v2",
+    repo_owner: "sentence-transformers",
+    name: optimum_cli_export_all_minilm_l6_v2_none,
+    pooling: Some(Pooling::None), // The exported graph already pools and normalizes
+    expected_embedding_dim: 384,
+    // These are generated by Python; there could be accumulated variations
+    // when summed.
+    expected: [ 0.5960538 ,  0.36542776, -0.16450086, -0.40904027]
+}
 create_test! {
     repo_name: "all-mpnet-base-v2",
     repo_owner: "sentence-transformers",